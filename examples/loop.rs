@@ -4,7 +4,7 @@ use clap::{Arg, ArgAction, Command};
 use ilog::IntLog;
 use io_uring::{opcode, squeue, types};
 use libublk::helpers::IoBuf;
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
+use libublk::io::{UblkBasicParams, UblkDev, UblkIOCtx, UblkParamsBuilder, UblkQueue};
 use libublk::uring_async::ublk_wait_and_handle_ios;
 use libublk::{ctrl::UblkCtrl, sys, UblkError, UblkFlags, UblkIORes};
 use serde::Serialize;
@@ -94,21 +94,16 @@ fn lo_init_tgt(dev: &mut UblkDev, lo: &LoopTgt) -> Result<(), UblkError> {
     tgt.nr_fds = nr_fds + 1;
 
     let sz = { lo_file_size(&lo.back_file).unwrap() };
-    tgt.dev_size = sz.0;
     //todo: figure out correct block size
-    tgt.params = libublk::sys::ublk_params {
-        types: libublk::sys::UBLK_PARAM_TYPE_BASIC,
-        basic: libublk::sys::ublk_param_basic {
-            logical_bs_shift: sz.1,
-            physical_bs_shift: sz.2,
-            io_opt_shift: 12,
-            io_min_shift: 9,
-            max_sectors: dev.dev_info.max_io_buf_bytes >> 9,
-            dev_sectors: tgt.dev_size >> 9,
-            ..Default::default()
-        },
-        ..Default::default()
-    };
+    dev.set_params(
+        UblkParamsBuilder::default().dev_size(sz.0).basic(
+            UblkBasicParams::default()
+                .logical_bs_shift(sz.1)
+                .physical_bs_shift(sz.2)
+                .io_opt_shift(12)
+                .io_min_shift(9),
+        ),
+    )?;
     let val = serde_json::json!({"loop": LoJson { back_file_path: lo.back_file_path.clone(), direct_io: 1 } });
     dev.set_target_json(val);
 
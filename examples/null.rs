@@ -1,9 +1,10 @@
 use bitflags::bitflags;
 use clap::{Arg, ArgAction, Command};
+use libublk::ctrl::{UblkCtrl, UblkShutdownMode};
 use libublk::helpers::IoBuf;
 use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
 use libublk::uring_async::ublk_wait_and_handle_ios;
-use libublk::{ctrl::UblkCtrl, UblkFlags, UblkIORes};
+use libublk::{UblkFlags, UblkIORes};
 use std::rc::Rc;
 
 bitflags! {
@@ -104,6 +105,7 @@ fn __null_add(
         .nr_queues(nr_queues.try_into().unwrap())
         .io_buf_bytes(buf_size)
         .ctrl_flags(ctrl_flags)
+        .shutdown_mode(UblkShutdownMode::Stop)
         .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
         .build()
         .unwrap();
@@ -1,10 +1,11 @@
-use libublk::ctrl::UblkCtrl;
+use libublk::ctrl::{UblkCtrl, UblkRecoveryMode};
 ///! # Example of ramdisk
 ///
 /// Serves for covering recovery test[`test_ublk_ramdisk_recovery`],
 ///
 /// Build ramdisk target in single-thread conext, and the same technique
-/// will be extended to create multiple devices in single thread
+/// is used by `libublk::manager::UblkDeviceManager` for serving multiple
+/// devices in single thread
 ///
 use libublk::helpers::IoBuf;
 use libublk::io::{UblkDev, UblkQueue};
@@ -99,7 +100,7 @@ fn rd_add_dev(dev_id: i32, buf_addr: *mut u8, size: u64, for_add: bool) {
             .nr_queues(1_u16)
             .depth(128_u16)
             .dev_flags(dev_flags)
-            .ctrl_flags(libublk::sys::UBLK_F_USER_RECOVERY as u64)
+            .recovery_mode(UblkRecoveryMode::Requeue)
             .build()
             .unwrap(),
     );
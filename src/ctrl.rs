@@ -1,23 +1,32 @@
+use super::driver::UblkDriver;
 use super::io::{UblkDev, UblkTgt};
 use super::uring_async::UblkUringOpFuture;
-use super::{sys, UblkError, UblkFlags};
+use super::{sys, UblkError, UblkEventListener, UblkFeatures, UblkFlags, UblkOpError};
 use bitmaps::Bitmap;
 use derive_setters::*;
-use io_uring::{opcode, squeue, types, IoUring};
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use log::{error, trace};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::os::unix::io::AsRawFd;
 use std::sync::{Arc, RwLock};
-use std::{
-    fs,
-    io::{Read, Write},
-    path::Path,
-};
+use std::{fs, io::Write, path::Path};
 
-const CTRL_PATH: &str = "/dev/ublk-control";
+const MAX_BUF_SZ: u32 = 1_u32 << sys::UBLK_IO_BUF_BITS;
 
-const MAX_BUF_SZ: u32 = 32_u32 << 20;
+/// environment variable for overriding the default run directory
+pub const UBLK_RUN_DIR_ENV: &str = "LIBUBLK_RUN_DIR";
+
+/// preferred default run directory if it is writable
+const UBLK_RUN_DIR_DEFAULT: &str = "/run/ublk";
+
+/// default timeout of draining queues after the device is stopped in
+/// `UblkCtrl::run_target()`
+const UBLK_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const UBLK_CDEV_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// process-wide run directory, set by `UblkCtrl::set_run_dir()`
+static RUN_DIR: RwLock<Option<String>> = RwLock::new(None);
 
 // per-thread control uring
 //
@@ -25,18 +34,76 @@ std::thread_local! {
     pub(crate) static CTRL_URING: RefCell<IoUring::<squeue::Entry128>> =
         RefCell::new(IoUring::<squeue::Entry128>::builder()
             .build(16).unwrap());
+
+    /// buffers of cancelled sync control commands, which are freed
+    /// after the CQEs are reaped
+    static CTRL_CANCELLED_BUFS: RefCell<std::collections::HashMap<u64, Vec<u8>>> =
+        RefCell::new(std::collections::HashMap::new());
+}
+
+/// Handle CQE which isn't for the waited sync control command: wake up
+/// async control task, or free buffer of the cancelled sync command
+pub(crate) fn ublk_ctrl_handle_cqe(cqe: &cqueue::Entry) {
+    let data = cqe.user_data();
+
+    if data & CTRL_SYNC_TOKEN != 0 {
+        CTRL_CANCELLED_BUFS.with(|bufs| bufs.borrow_mut().remove(&data));
+    } else if data & CTRL_CANCEL_TOKEN == 0 {
+        crate::uring_async::ublk_wake_task(data, cqe);
+    }
+}
+
+/// Sleep for `dur` via one timeout command queued in the per-thread
+/// control io_uring
+async fn ublk_ctrl_sleep_async(dur: std::time::Duration) {
+    // timespec has to be live until the timeout command is submitted
+    let ts = types::Timespec::from(dur);
+    let f = UblkUringOpFuture::new(0);
+    let sqe = opcode::Timeout::new(&ts).build().user_data(f.user_data);
+
+    CTRL_URING.with(|refcell| unsafe {
+        refcell
+            .borrow_mut()
+            .submission()
+            .push(&squeue::Entry128::from(sqe))
+            .unwrap();
+    });
+    f.await;
+}
+
+/// Flush queued commands of the per-thread control uring, and wake up
+/// async control tasks whose commands are completed
+///
+/// Buffers of cancelled sync control commands are freed when their CQEs
+/// are reaped. Returns how many CQEs are reaped.
+pub(crate) fn ublk_ctrl_flush_and_wake() -> Result<usize, UblkError> {
+    CTRL_URING.with(|refcell| {
+        let mut r = refcell.borrow_mut();
+
+        r.submit()?;
+        let cqes: Vec<cqueue::Entry> = r.completion().collect();
+        for cqe in &cqes {
+            ublk_ctrl_handle_cqe(cqe);
+        }
+        Ok(cqes.len())
+    })
 }
 
 /// Ublk per-queue CPU affinity
 ///
 /// Responsible for setting ublk queue pthread's affinity.
 ///
-#[derive(Debug, Default, Copy, Clone)]
+/// It can be built from cpu list, linux cpuset string(such as "0-3,8"),
+/// `libc::cpu_set_t` or NUMA node, and is serialized as cpu list.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct UblkQueueAffinity {
     affinity: Bitmap<1024>,
 }
 
 impl UblkQueueAffinity {
+    /// max number of cpus covered, same with `libc::CPU_SETSIZE`
+    pub const MAX_CPUS: usize = 1024;
+
     pub fn new() -> UblkQueueAffinity {
         UblkQueueAffinity {
             affinity: Bitmap::new(),
@@ -53,119 +120,1284 @@ impl UblkQueueAffinity {
     pub fn to_bits_vec(&self) -> Vec<usize> {
         self.affinity.into_iter().collect()
     }
+
+    /// Build affinity from cpu list, and fail if any cpu is beyond
+    /// `MAX_CPUS`
+    pub fn from_cpus(cpus: &[u32]) -> Result<UblkQueueAffinity, UblkError> {
+        let mut affinity = UblkQueueAffinity::new();
+
+        for cpu in cpus {
+            affinity.insert(*cpu)?;
+        }
+        Ok(affinity)
+    }
+
+    /// Build affinity from `libc::cpu_set_t`
+    pub fn from_cpu_set(set: &libc::cpu_set_t) -> UblkQueueAffinity {
+        let mut affinity = UblkQueueAffinity::new();
+
+        for cpu in 0..Self::MAX_CPUS {
+            if unsafe { libc::CPU_ISSET(cpu, set) } {
+                affinity.affinity.set(cpu, true);
+            }
+        }
+        affinity
+    }
+
+    /// Convert to `libc::cpu_set_t`
+    pub fn to_cpu_set(&self) -> libc::cpu_set_t {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+        for cpu in &self.affinity {
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        set
+    }
+
+    /// cpus which the current thread is allowed to run on
+    pub fn from_current_thread() -> Result<UblkQueueAffinity, UblkError> {
+        Self::from_thread(0)
+    }
+
+    /// cpus which thread `tid` is allowed to run on
+    pub fn from_thread(tid: i32) -> Result<UblkQueueAffinity, UblkError> {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut set) }
+            != 0
+        {
+            return Err(UblkError::IOError(std::io::Error::last_os_error()));
+        }
+        Ok(Self::from_cpu_set(&set))
+    }
+
+    /// cpus in NUMA node `node`, retrieved from sysfs
+    pub fn from_numa_node(node: u32) -> Result<UblkQueueAffinity, UblkError> {
+        let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// NUMA node of `cpu`, retrieved from sysfs
+    pub fn cpu_numa_node(cpu: u32) -> Option<u32> {
+        std::fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+            .ok()?
+            .flatten()
+            .find_map(|e| e.file_name().to_str()?.strip_prefix("node")?.parse().ok())
+    }
+
+    /// NUMA nodes covered by this affinity, in ascending order
+    pub fn numa_nodes(&self) -> Vec<u32> {
+        let mut nodes: Vec<u32> = self
+            .affinity
+            .into_iter()
+            .filter_map(|c| Self::cpu_numa_node(c as u32))
+            .collect();
+
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+
+    /// Add `cpu` into this affinity
+    pub fn insert(&mut self, cpu: u32) -> Result<(), UblkError> {
+        if cpu as usize >= Self::MAX_CPUS {
+            return Err(UblkError::InvalidVal);
+        }
+        self.affinity.set(cpu as usize, true);
+        Ok(())
+    }
+
+    /// Remove `cpu` from this affinity
+    pub fn remove(&mut self, cpu: u32) {
+        if (cpu as usize) < Self::MAX_CPUS {
+            self.affinity.set(cpu as usize, false);
+        }
+    }
+
+    pub fn contains(&self, cpu: u32) -> bool {
+        (cpu as usize) < Self::MAX_CPUS && self.affinity.get(cpu as usize)
+    }
+
+    /// Number of cpus in this affinity
+    pub fn len(&self) -> usize {
+        self.affinity.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.affinity.is_empty()
+    }
+
+    /// cpus in this affinity, in ascending order
+    pub fn cpus(&self) -> Vec<u32> {
+        self.affinity.into_iter().map(|c| c as u32).collect()
+    }
+
+    pub fn first_cpu(&self) -> Option<u32> {
+        self.affinity.first_index().map(|c| c as u32)
+    }
+
+    pub fn union(&self, other: &UblkQueueAffinity) -> UblkQueueAffinity {
+        UblkQueueAffinity {
+            affinity: self.affinity | other.affinity,
+        }
+    }
+
+    pub fn intersection(&self, other: &UblkQueueAffinity) -> UblkQueueAffinity {
+        UblkQueueAffinity {
+            affinity: self.affinity & other.affinity,
+        }
+    }
+
+    /// cpus in `self` but not in `other`
+    pub fn difference(&self, other: &UblkQueueAffinity) -> UblkQueueAffinity {
+        UblkQueueAffinity {
+            affinity: self.affinity & !other.affinity,
+        }
+    }
+
+    /// Bind pthread `thread` to this affinity via `pthread_setaffinity_np`
+    pub fn set_thread_affinity(&self, thread: libc::pthread_t) -> Result<(), UblkError> {
+        let set = self.to_cpu_set();
+        let res = unsafe {
+            libc::pthread_setaffinity_np(thread, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+
+        if res != 0 {
+            return Err(UblkError::OtherError(-res));
+        }
+        Ok(())
+    }
+
+    /// Parse cpu list in linux format, such as "0-3,8"
+    ///
+    /// Range is checked before being expanded, so huge range can't
+    /// consume lots of memory.
+    fn parse_cpu_list(s: &str) -> Option<Vec<u32>> {
+        let mut cpus = Vec::new();
+
+        for item in s.trim().split(',').filter(|i| !i.is_empty()) {
+            match item.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (start.parse::<u32>().ok()?, end.parse::<u32>().ok()?);
+                    if start > end || end as usize >= Self::MAX_CPUS {
+                        return None;
+                    }
+                    cpus.extend(start..=end);
+                }
+                None => cpus.push(item.parse::<u32>().ok()?),
+            }
+        }
+        Some(cpus)
+    }
+}
+
+impl std::str::FromStr for UblkQueueAffinity {
+    type Err = UblkError;
+
+    /// Parse linux cpuset string, such as "0-3,8"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cpus = Self::parse_cpu_list(s).ok_or(UblkError::InvalidVal)?;
+
+        Self::from_cpus(&cpus)
+    }
+}
+
+impl std::fmt::Display for UblkQueueAffinity {
+    /// Format as linux cpuset string, such as "0-3,8"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let cpus = self.cpus();
+        let mut ranges = Vec::new();
+        let mut i = 0;
+
+        while i < cpus.len() {
+            let mut j = i;
+            while j + 1 < cpus.len() && cpus[j + 1] == cpus[j] + 1 {
+                j += 1;
+            }
+            ranges.push(if i == j {
+                cpus[i].to_string()
+            } else {
+                format!("{}-{}", cpus[i], cpus[j])
+            });
+            i = j + 1;
+        }
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+impl Serialize for UblkQueueAffinity {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.cpus().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UblkQueueAffinity {
+    /// Accept both cpu list and cpuset string
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Cpus(Vec<u32>),
+            CpuSet(String),
+        }
+
+        let res = match Repr::deserialize(deserializer)? {
+            Repr::Cpus(cpus) => Self::from_cpus(&cpus),
+            Repr::CpuSet(s) => s.parse(),
+        };
+        res.map_err(|_| serde::de::Error::custom("invalid cpu affinity"))
+    }
+}
+
+/// How to set cpu affinity of queue pthread in `UblkCtrl::run_target()`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UblkAffinityPolicy {
+    /// cpus retrieved from ublk driver via GET_QUEUE_AFFINITY
+    #[default]
+    DriverDefault,
+
+    /// one single cpu for each queue, selected in round-robin from cpus
+    /// which the daemon is allowed to run on
+    RoundRobin,
+
+    /// all cpus in the NUMA node of the driver's queue affinity
+    NumaLocal,
+
+    /// explicit cpu list for each queue, indexed by queue id
+    Explicit(Vec<Vec<u32>>),
+
+    /// queue pthread isn't pinned
+    None,
+}
+
+/// How ublk driver handles IOs after the daemon exits abnormally, see
+/// `UBLK_F_USER_RECOVERY*`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UblkRecoveryMode {
+    /// device is removed after the daemon exits, and can't be recovered
+    #[default]
+    Disabled,
+
+    /// inflight IOs are requeued, and handled by the recovered daemon
+    Requeue,
+
+    /// inflight IOs are re-issued to the recovered daemon, for targets
+    /// which can't tolerate lost IOs, such as network backend
+    Reissue,
+
+    /// IOs are failed when the device is quiesced for recovery
+    FailIo,
+}
+
+impl UblkRecoveryMode {
+    /// Driver flags passed via `sys::ublksrv_ctrl_dev_info.flags`
+    pub fn features(&self) -> UblkFeatures {
+        match self {
+            UblkRecoveryMode::Disabled => UblkFeatures::empty(),
+            UblkRecoveryMode::Requeue => UblkFeatures::UBLK_F_USER_RECOVERY,
+            UblkRecoveryMode::Reissue => {
+                UblkFeatures::UBLK_F_USER_RECOVERY | UblkFeatures::UBLK_F_USER_RECOVERY_REISSUE
+            }
+            UblkRecoveryMode::FailIo => {
+                UblkFeatures::UBLK_F_USER_RECOVERY | UblkFeatures::UBLK_F_USER_RECOVERY_FAIL_IO
+            }
+        }
+    }
+
+    /// Retrieve recovery mode from device's flags
+    pub fn from_flags(flags: u64) -> UblkRecoveryMode {
+        let f = UblkFeatures::from_bits_retain(flags);
+
+        if !f.contains(UblkFeatures::UBLK_F_USER_RECOVERY) {
+            UblkRecoveryMode::Disabled
+        } else if f.contains(UblkFeatures::UBLK_F_USER_RECOVERY_REISSUE) {
+            UblkRecoveryMode::Reissue
+        } else if f.contains(UblkFeatures::UBLK_F_USER_RECOVERY_FAIL_IO) {
+            UblkRecoveryMode::FailIo
+        } else {
+            UblkRecoveryMode::Requeue
+        }
+    }
+}
+
+impl std::fmt::Display for UblkRecoveryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            UblkRecoveryMode::Disabled => "disabled",
+            UblkRecoveryMode::Requeue => "requeue",
+            UblkRecoveryMode::Reissue => "reissue",
+            UblkRecoveryMode::FailIo => "fail_io",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What `UblkCtrl::run_target()` does when SIGTERM or SIGINT is received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UblkShutdownMode {
+    /// no signal handling, and the default signal action is applied
+    #[default]
+    Disabled,
+
+    /// stop the device, then wait for queues to drain in-flight IOs,
+    /// and finally remove the exported json file
+    Stop,
+
+    /// make queues stop issuing io commands and drain in-flight target IOs
+    /// in shutdown timeout, then exit and close the char device without
+    /// stopping the device, so that the device is quiesced by ublk driver
+    /// and can be recovered later, and the device has to be created with
+    /// recovery support. `run_target()` returns after queues exit, and the
+    /// application decides whether to exit, see `UblkQueueOutcome::Quiesced`
+    Quiesce,
+}
+
+/// Return value of queue handler passed to `UblkCtrl::run_target()`,
+/// so that the handler can return either `()` or `Result`
+pub trait UblkQueueResult {
+    fn into_queue_result(self) -> Result<(), UblkError>;
+}
+
+impl UblkQueueResult for () {
+    fn into_queue_result(self) -> Result<(), UblkError> {
+        Ok(())
+    }
+}
+
+impl UblkQueueResult for Result<(), UblkError> {
+    fn into_queue_result(self) -> Result<(), UblkError> {
+        self
+    }
+}
+
+/// How one queue pthread exits in `UblkCtrl::run_target_report()`
+#[derive(Debug)]
+pub enum UblkQueueOutcome {
+    /// queue handler returns successfully
+    Exited,
+
+    /// queue handler returns after queues are quiesced, see
+    /// `UblkShutdownMode::Quiesce`
+    Quiesced,
+
+    /// queue handler returns error
+    Failed(UblkError),
+
+    /// queue handler panics, with the panic message
+    Panicked(String),
+
+    /// queue pthread doesn't exit in shutdown timeout after the device
+    /// is stopped or quiesced, and it is detached
+    Detached,
+}
+
+impl UblkQueueOutcome {
+    fn is_ok(&self) -> bool {
+        matches!(self, UblkQueueOutcome::Exited | UblkQueueOutcome::Quiesced)
+    }
+}
+
+/// Result of `UblkCtrl::run_target_report()`
+#[derive(Debug)]
+pub struct UblkRunReport {
+    pub dev_id: u32,
+
+    /// outcome of each queue, indexed by queue id
+    pub queues: Vec<UblkQueueOutcome>,
+
+    /// failure of stopping device after all queues exit, and -ENOENT is
+    /// ignored since the device may be deleted from another context
+    pub stop_error: Option<UblkError>,
+}
+
+impl UblkRunReport {
+    /// Return true if all queues exit successfully
+    pub fn is_clean(&self) -> bool {
+        self.queues.iter().all(|q| q.is_ok())
+    }
+
+    /// Return true if queues are quiesced and the device isn't stopped,
+    /// so the device can be recovered after it becomes QUIESCED
+    pub fn is_quiesced(&self) -> bool {
+        self.queues
+            .iter()
+            .any(|q| matches!(q, UblkQueueOutcome::Quiesced))
+    }
+
+    /// Return 0, or error if any queue pthread is detached
+    fn into_result(self) -> Result<i32, UblkError> {
+        match self
+            .failed_queues()
+            .find(|(_, q)| matches!(q, UblkQueueOutcome::Detached))
+        {
+            Some((qid, _)) => Err(UblkOpError::new("join_queue", -libc::ETIMEDOUT)
+                .dev(self.dev_id)
+                .queue(qid)
+                .into()),
+            None => Ok(0),
+        }
+    }
+
+    /// Iterator over queues which don't exit successfully
+    pub fn failed_queues(&self) -> impl Iterator<Item = (u16, &UblkQueueOutcome)> {
+        self.queues
+            .iter()
+            .enumerate()
+            .filter(|(_, q)| !q.is_ok())
+            .map(|(qid, q)| (qid as u16, q))
+    }
+}
+
+/// Block SIGTERM & SIGINT in current thread, so that threads spawned
+/// from current thread inherit the mask, and the two signals are only
+/// consumed via `sigtimedwait()`. The old mask is restored in drop().
+struct UblkSignalMask {
+    set: libc::sigset_t,
+    old: libc::sigset_t,
+}
+
+impl UblkSignalMask {
+    fn new() -> Result<UblkSignalMask, UblkError> {
+        let mut mask = UblkSignalMask {
+            set: unsafe { std::mem::zeroed() },
+            old: unsafe { std::mem::zeroed() },
+        };
+
+        let res = unsafe {
+            libc::sigemptyset(&mut mask.set);
+            libc::sigaddset(&mut mask.set, libc::SIGTERM);
+            libc::sigaddset(&mut mask.set, libc::SIGINT);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &mask.set, &mut mask.old)
+        };
+        if res != 0 {
+            return Err(UblkError::OtherError(-res));
+        }
+        Ok(mask)
+    }
+
+    /// Wait for one of the blocked signals in `timeout`, return the
+    /// received signal or None
+    fn wait(&self, timeout: std::time::Duration) -> Option<i32> {
+        let ts = libc::timespec {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        let sig = unsafe { libc::sigtimedwait(&self.set, std::ptr::null_mut(), &ts) };
+
+        if sig > 0 {
+            Some(sig)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for UblkSignalMask {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.old, std::ptr::null_mut()) };
+    }
+}
+
+/// the max supported length of char device path, which
+/// is one implementation limit, and can be increased
+/// without breaking anything.
+const CTRL_UBLKC_PATH_MAX: usize = 32;
+const CTRL_CMD_HAS_DATA: u32 = 1;
+const CTRL_CMD_HAS_BUF: u32 = 2;
+/// this command need to read data back from device, such as ADD_DEV
+/// which returns the allocated device id
+const CTRL_CMD_BUF_READ: u32 = 8;
+/// this command needn't to attach char device path for audit in
+/// case of unprivileged ublk, such as get_features(), add_dev().
+const CTRL_CMD_NO_NEED_DEV_PATH: u32 = 16;
+
+/// user_data tag of sync control command, so that its CQE can be
+/// told from the ones of async control command(`UblkUringOpFuture`)
+const CTRL_SYNC_TOKEN: u64 = 1_u64 << 62;
+
+/// user_data tag of command for cancelling timed-out control command
+const CTRL_CANCEL_TOKEN: u64 = 1_u64 << 61;
+
+/// Name of control command `cmd_op`, which may be ioctl encoded or not
+pub(crate) fn ctrl_cmd_name(cmd_op: u32) -> &'static str {
+    match cmd_op & 0xff {
+        sys::UBLK_CMD_GET_QUEUE_AFFINITY => "GET_QUEUE_AFFINITY",
+        sys::UBLK_CMD_GET_DEV_INFO => "GET_DEV_INFO",
+        sys::UBLK_CMD_ADD_DEV => "ADD_DEV",
+        sys::UBLK_CMD_DEL_DEV => "DEL_DEV",
+        sys::UBLK_CMD_START_DEV => "START_DEV",
+        sys::UBLK_CMD_STOP_DEV => "STOP_DEV",
+        sys::UBLK_CMD_SET_PARAMS => "SET_PARAMS",
+        sys::UBLK_CMD_GET_PARAMS => "GET_PARAMS",
+        sys::UBLK_CMD_START_USER_RECOVERY => "START_USER_RECOVERY",
+        sys::UBLK_CMD_END_USER_RECOVERY => "END_USER_RECOVERY",
+        sys::UBLK_CMD_GET_DEV_INFO2 => "GET_DEV_INFO2",
+        op if op == sys::UBLK_U_CMD_GET_FEATURES & 0xff => "GET_FEATURES",
+        op if op == sys::UBLK_U_CMD_DEL_DEV_ASYNC & 0xff => "DEL_DEV_ASYNC",
+        _ => "UNKNOWN_CMD",
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct UblkCtrlCmdData {
+    cmd_op: u32,
+    flags: u32,
+    data: u64,
+    dev_path_len: u16,
+    _pad: u16,
+    _reserved: u32,
+
+    addr: u64,
+    len: u32,
+}
+
+impl UblkCtrlCmdData {
+    /// Copy payload of this command into one owned buffer, and char
+    /// device path is attached at the buffer head for unprivileged device
+    ///
+    /// The owned buffer has to be live until the command's CQE is reaped,
+    /// so the caller's buffer is never touched by the command after it is
+    /// timed out. Address of the caller's buffer is returned together.
+    fn prep_cmd_buf(&mut self, dev: &UblkCtrlCmdCtx) -> (u64, Option<Vec<u8>>) {
+        // handle GET_DEV_INFO2 always with dev_path attached
+        let cmd_op = self.cmd_op & 0xff;
+        let path_len = if cmd_op == sys::UBLK_CMD_GET_DEV_INFO2
+            || (dev.unprivileged && (self.flags & CTRL_CMD_NO_NEED_DEV_PATH) == 0)
+        {
+            CTRL_UBLKC_PATH_MAX
+        } else {
+            0
+        };
+        let len = if self.flags & CTRL_CMD_HAS_BUF != 0 {
+            self.len as usize
+        } else {
+            0
+        };
+
+        if path_len == 0 && self.flags & CTRL_CMD_HAS_BUF == 0 {
+            return (0, None);
+        }
+
+        let mut buf = vec![0_u8; path_len + len];
+        if path_len != 0 {
+            let path_str = &dev.cdev_path;
+            assert!(path_str.len() <= CTRL_UBLKC_PATH_MAX);
+
+            buf[..path_str.len()].copy_from_slice(path_str.as_bytes());
+            self.flags |= CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA;
+            self.len += path_len as u32;
+            self.dev_path_len = path_len as u16;
+        }
+        if len != 0 {
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    self.addr as *const u8,
+                    buf.as_mut_ptr().add(path_len),
+                    len,
+                );
+            }
+        }
+
+        let addr = self.addr;
+        self.addr = buf.as_mut_ptr() as u64;
+        (addr, Some(buf))
+    }
+
+    /// Copy data read by the completed command back to the caller's
+    /// buffer `buf`, and the owned buffer has to be still live
+    fn unprep_cmd_buf(&self, buf: u64) {
+        if buf == 0 || self.flags & CTRL_CMD_BUF_READ == 0 {
+            return;
+        }
+
+        let path_len = self.dev_path_len as usize;
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (self.addr as *const u8).add(path_len),
+                buf as *mut u8,
+                self.len as usize - path_len,
+            );
+        }
+    }
+}
+
+/// What is needed for issuing control command, snapshotted from
+/// `UblkCtrlInner`, so that async command can be awaited without
+/// holding the device lock
+struct UblkCtrlCmdCtx {
+    fd: i32,
+    dev_id: u32,
+    unprivileged: bool,
+    cdev_path: String,
+    cmd_timeout: Option<std::time::Duration>,
+    driver: Arc<dyn UblkDriver>,
+}
+
+impl UblkCtrlCmdCtx {
+    fn prep_cmd(&self, data: &UblkCtrlCmdData, token: u64) -> Option<squeue::Entry128> {
+        let cmd = sys::ublksrv_ctrl_cmd {
+            addr: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
+                data.addr
+            } else {
+                0
+            },
+            len: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
+                data.len as u16
+            } else {
+                0
+            },
+            data: if (data.flags & CTRL_CMD_HAS_DATA) != 0 {
+                [data.data]
+            } else {
+                [0]
+            },
+            dev_id: self.dev_id,
+            queue_id: u16::MAX,
+            dev_path_len: data.dev_path_len,
+            ..Default::default()
+        };
+        let ring_fd = CTRL_URING.with(|refcell| refcell.borrow().as_raw_fd());
+
+        self.driver
+            .ctrl_cmd(ring_fd, self.fd, data.cmd_op, &cmd, token)
+    }
+
+    fn ublk_submit_cmd_async(
+        &self,
+        data: &UblkCtrlCmdData,
+        buf: Option<Vec<u8>>,
+    ) -> UblkUringOpFuture {
+        let f = UblkUringOpFuture::new(0);
+        if let Some(b) = buf {
+            f.keep_buf(b);
+        }
+        if let Some(sqe) = self.prep_cmd(data, f.user_data) {
+            CTRL_URING.with(|refcell| unsafe {
+                refcell.borrow_mut().submission().push(&sqe).unwrap();
+            })
+        }
+        f
+    }
+
+    /// Wait one submitted async control command with timeout
+    ///
+    /// None is returned if the command isn't completed in `timeout`, and
+    /// it has been cancelled.
+    async fn ublk_wait_cmd_async_timeout(
+        cmd_f: &mut UblkUringOpFuture,
+        timeout: std::time::Duration,
+    ) -> Option<i32> {
+        use futures::future::Either;
+
+        // timespec has to be live until the timeout command is submitted
+        let ts = types::Timespec::from(timeout);
+        let cmd_data = cmd_f.user_data;
+        let timeout_f = UblkUringOpFuture::new(0);
+        let timeout_data = timeout_f.user_data;
+        let sqe = opcode::Timeout::new(&ts).build().user_data(timeout_data);
+
+        CTRL_URING.with(|refcell| unsafe {
+            refcell
+                .borrow_mut()
+                .submission()
+                .push(&squeue::Entry128::from(sqe))
+                .unwrap();
+        });
+
+        match futures::future::select(cmd_f, timeout_f).await {
+            Either::Left((res, _timeout_f)) => {
+                // remove the timer, and its slot is released after the
+                // CQE is reaped
+                let sqe = opcode::TimeoutRemove::new(timeout_data)
+                    .build()
+                    .user_data(CTRL_CANCEL_TOKEN);
+                CTRL_URING.with(|refcell| unsafe {
+                    refcell
+                        .borrow_mut()
+                        .submission()
+                        .push(&squeue::Entry128::from(sqe))
+                        .unwrap();
+                });
+                Some(res)
+            }
+            Either::Right(_) => {
+                CTRL_URING
+                    .with(|refcell| UblkCtrlInner::cancel_cmd(&mut refcell.borrow_mut(), cmd_data));
+                None
+            }
+        }
+    }
+
+    fn ublk_err_to_result(&self, cmd_op: u32, res: i32) -> Result<i32, UblkError> {
+        if res >= 0 {
+            return Ok(res);
+        }
+
+        let err = UblkOpError::new(ctrl_cmd_name(cmd_op), res);
+        match self.dev_id {
+            u32::MAX => Err(err.into()),
+            id => Err(err.dev(id).into()),
+        }
+    }
+
+    async fn ublk_ctrl_cmd_async(&self, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
+        let mut new_data = *data;
+        let mut res: i32 = 0;
+
+        for _ in 0..2 {
+            let (old_buf, new) = new_data.prep_cmd_buf(self);
+
+            // the command buffer is owned by the command future, so it is
+            // live until the CQE is reaped even though the command is
+            // cancelled
+            let mut cmd_f = self.ublk_submit_cmd_async(&new_data, new);
+            res = match self.cmd_timeout {
+                None => (&mut cmd_f).await,
+                Some(t) => match Self::ublk_wait_cmd_async_timeout(&mut cmd_f, t).await {
+                    Some(r) => r,
+                    None => {
+                        return Err(UblkError::CtrlTimeout {
+                            cmd: data.cmd_op,
+                            dev_id: self.dev_id,
+                        });
+                    }
+                },
+            };
+            new_data.unprep_cmd_buf(old_buf);
+            drop(cmd_f);
+
+            trace!("ublk_ctrl_cmd_async: cmd {:x} res {}", data.cmd_op, res);
+            if !UblkCtrlInner::ublk_ctrl_need_retry(&mut new_data, data, res) {
+                break;
+            }
+        }
+
+        self.ublk_err_to_result(data.cmd_op, res)
+    }
+
+    async fn read_dev_info_async(
+        &self,
+        info: &mut sys::ublksrv_ctrl_dev_info,
+    ) -> Result<i32, UblkError> {
+        let mut data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_GET_DEV_INFO2,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_READ,
+            addr: info as *mut sys::ublksrv_ctrl_dev_info as u64,
+            len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
+            ..Default::default()
+        };
+
+        let res = self.ublk_ctrl_cmd_async(&data).await;
+        if res.is_err() {
+            data.cmd_op = sys::UBLK_U_CMD_GET_DEV_INFO;
+            self.ublk_ctrl_cmd_async(&data).await
+        } else {
+            res
+        }
+    }
+
+    async fn start_async(&self, pid: i32) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_START_DEV,
+            flags: CTRL_CMD_HAS_DATA,
+            data: pid as u64,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn stop_async(&self) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_STOP_DEV,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn get_params_async(&self, params: &mut sys::ublk_params) -> Result<i32, UblkError> {
+        params.len = core::mem::size_of::<sys::ublk_params>() as u32;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_GET_PARAMS,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_READ,
+            addr: params as *const sys::ublk_params as u64,
+            len: params.len,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn set_params_async(&self, params: &sys::ublk_params) -> Result<i32, UblkError> {
+        let mut p = *params;
+
+        p.len = core::mem::size_of::<sys::ublk_params>() as u32;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_SET_PARAMS,
+            flags: CTRL_CMD_HAS_BUF,
+            addr: std::ptr::addr_of!(p) as u64,
+            len: p.len,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn get_queue_affinity_async(
+        &self,
+        q: u32,
+        bm: &mut UblkQueueAffinity,
+    ) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_GET_QUEUE_AFFINITY,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA | CTRL_CMD_BUF_READ,
+            addr: bm.addr() as u64,
+            data: q as u64,
+            len: bm.buf_len() as u32,
+            ..Default::default()
+        };
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn __start_user_recover_async(&self) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_START_USER_RECOVERY,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+
+    async fn end_user_recover_async(&self, pid: i32) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_END_USER_RECOVERY,
+            flags: CTRL_CMD_HAS_DATA,
+            data: pid as u64,
+            ..Default::default()
+        };
+
+        self.ublk_ctrl_cmd_async(&data).await
+    }
+}
+
+/// Ublk device state, reported in `sys::ublksrv_ctrl_dev_info.state`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum UblkDevState {
+    /// device isn't started yet, or it has been stopped
+    Dead,
+
+    /// device is started, and `/dev/ublkbN` is exposed
+    Live,
+
+    /// ublk server is gone, and the device is waiting for recovery
+    Quiesced,
+
+    Unknown,
+}
+
+impl From<u16> for UblkDevState {
+    fn from(state: u16) -> Self {
+        match state as u32 {
+            sys::UBLK_S_DEV_DEAD => UblkDevState::Dead,
+            sys::UBLK_S_DEV_LIVE => UblkDevState::Live,
+            sys::UBLK_S_DEV_QUIESCED => UblkDevState::Quiesced,
+            _ => UblkDevState::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for UblkDevState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let desc = match self {
+            UblkDevState::Dead => "DEAD",
+            UblkDevState::Live => "LIVE",
+            UblkDevState::Quiesced => "QUIESCED",
+            UblkDevState::Unknown => "UNKNOWN",
+        };
+        write!(f, "{}", desc)
+    }
+}
+
+/// Summary of one ublk device, retrieved from ublk driver
+///
+/// The device's exported json data is included if it is available.
+#[derive(Debug, Clone, Serialize)]
+pub struct UblkDevSummary {
+    pub dev_id: u32,
+    pub state: UblkDevState,
+    pub owner_uid: u32,
+    pub owner_gid: u32,
+
+    /// pid of the ublk server
+    pub pid: i32,
+    pub nr_queues: u16,
+    pub queue_depth: u16,
+    pub max_io_buf_bytes: u32,
+
+    /// `sys::ublksrv_ctrl_dev_info.flags`
+    pub flags: u64,
+
+    /// loaded from exported json file in `UblkCtrl::run_dir()`
+    pub export: Option<UblkDevExport>,
+}
+
+impl UblkDevSummary {
+    fn new(info: &sys::ublksrv_ctrl_dev_info, export: Option<UblkDevExport>) -> Self {
+        UblkDevSummary {
+            dev_id: info.dev_id,
+            state: UblkDevState::from(info.state),
+            owner_uid: info.owner_uid,
+            owner_gid: info.owner_gid,
+            pid: info.ublksrv_pid,
+            nr_queues: info.nr_hw_queues,
+            queue_depth: info.queue_depth,
+            max_io_buf_bytes: info.max_io_buf_bytes,
+            flags: info.flags,
+            export,
+        }
+    }
+
+    /// Driver features enabled for this device
+    pub fn features(&self) -> UblkFeatures {
+        UblkFeatures::from_bits_retain(self.flags)
+    }
+
+    /// Target type recorded in exported json file
+    pub fn target_type(&self) -> Option<&str> {
+        self.export.as_ref().map(|e| e.target.tgt_type.as_str())
+    }
+}
+
+/// Output format of `UblkCtrl::dump_format()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UblkDumpFormat {
+    /// human readable text
+    Text,
+
+    /// json object of `UblkDevReport`
+    Json,
+}
+
+/// Detailed report of one ublk device, returned from `UblkCtrl::describe()`
+///
+/// The 1st part is retrieved from ublk driver, and the 2nd part is from
+/// device's exported json file.
+#[derive(Debug, Clone, Serialize)]
+pub struct UblkDevReport {
+    pub dev_info: sys::ublksrv_ctrl_dev_info,
+    pub params: sys::ublk_params,
+    pub state: UblkDevState,
+
+    /// char & block device numbers
+    pub devt: sys::ublk_param_devt,
+    pub owner_uid: u32,
+    pub owner_gid: u32,
+
+    /// per-queue tid and affinity
+    pub queues: Vec<UblkQueueExport>,
+    pub target: Option<UblkTgt>,
+    pub target_data: Option<serde_json::Value>,
+}
+
+impl UblkDevReport {
+    /// Render this report as human readable text
+    pub fn to_text(&self) -> String {
+        use std::fmt::Write;
+
+        let info = &self.dev_info;
+        let p = &self.params;
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "dev id {}: nr_hw_queues {} queue_depth {} block size {} dev_capacity {}",
+            info.dev_id,
+            info.nr_hw_queues,
+            info.queue_depth,
+            1 << p.basic.logical_bs_shift,
+            p.basic.dev_sectors
+        );
+        let _ = writeln!(
+            out,
+            "\tmax rq size {} daemon pid {} flags 0x{:x} state {}",
+            info.max_io_buf_bytes, info.ublksrv_pid, info.flags, self.state
+        );
+        let _ = writeln!(
+            out,
+            "\tublkc: {}:{} ublkb: {}:{} owner: {}:{}",
+            self.devt.char_major,
+            self.devt.char_minor,
+            self.devt.disk_major,
+            self.devt.disk_minor,
+            self.owner_uid,
+            self.owner_gid
+        );
+        for q in &self.queues {
+            let _ = writeln!(
+                out,
+                "\tqueue {} tid: {} affinity({})",
+                q.qid,
+                q.tid,
+                q.affinity
+                    .cpus()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            );
+        }
+        if let Some(tgt) = &self.target {
+            let _ = writeln!(
+                out,
+                "\ttarget {{\"dev_size\":{},\"name\":\"{}\",\"type\":0}}",
+                tgt.dev_size, tgt.tgt_type
+            );
+            let _ = writeln!(
+                out,
+                "\ttarget_data {}",
+                self.target_data
+                    .as_ref()
+                    .unwrap_or(&serde_json::Value::Null)
+            );
+        }
+        out
+    }
+
+    /// Render this report as json string
+    pub fn to_json(&self) -> Result<String, UblkError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Render this report in the specified format
+    pub fn render(&self, fmt: UblkDumpFormat) -> Result<String, UblkError> {
+        match fmt {
+            UblkDumpFormat::Text => Ok(self.to_text()),
+            UblkDumpFormat::Json => self.to_json(),
+        }
+    }
 }
 
-#[repr(C)]
-union CtrlCmd {
-    ctrl_cmd: sys::ublksrv_ctrl_cmd,
-    buf: [u8; 80],
+/// Polling interval of waiting for device state, which starts from
+/// the min value and is doubled until reaching the max value
+const STATE_POLL_MIN_MS: u64 = 1;
+const STATE_POLL_MAX_MS: u64 = 100;
+
+/// Version of the exported json file layout
+///
+/// Version 0 is the layout without `version` field, which is migrated
+/// to the current version in lenient parse mode.
+pub const UBLK_DEV_EXPORT_VERSION: u32 = 3;
+
+/// How to parse device's exported json file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UblkJsonParseMode {
+    /// `version` has to be the current version, and every field is
+    /// required, unknown field is rejected too
+    Strict,
+
+    /// older layout is migrated to the current version, missing field
+    /// is filled with default value except for `dev_info` and `target`,
+    /// and unknown field is ignored
+    Lenient,
 }
 
-/// the max supported length of char device path, which
-/// is one implementation limit, and can be increased
-/// without breaking anything.
-const CTRL_UBLKC_PATH_MAX: usize = 32;
-const CTRL_CMD_HAS_DATA: u32 = 1;
-const CTRL_CMD_HAS_BUF: u32 = 2;
-/// this command need to read data back from device
-const CTRL_CMD_BUF_READ: u32 = 8;
-/// this command needn't to attach char device path for audit in
-/// case of unprivileged ublk, such as get_features(), add_dev().
-const CTRL_CMD_NO_NEED_DEV_PATH: u32 = 16;
+/// Per-queue entry of device's exported json file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UblkQueueExport {
+    pub qid: u16,
 
-#[derive(Debug, Default, Copy, Clone)]
-struct UblkCtrlCmdData {
-    cmd_op: u32,
-    flags: u32,
-    data: u64,
-    dev_path_len: u16,
-    _pad: u16,
-    _reserved: u32,
+    /// tid of the queue pthread
+    #[serde(default)]
+    pub tid: i32,
 
-    addr: u64,
-    len: u32,
+    /// cpus which the queue pthread is bound to, empty if it isn't pinned
+    #[serde(default)]
+    pub affinity: UblkQueueAffinity,
 }
 
-impl UblkCtrlCmdData {
-    fn prep_un_privileged_dev_path(&mut self, dev: &UblkCtrlInner) -> (u64, Option<Vec<u8>>) {
-        // handle GET_DEV_INFO2 always with dev_path attached
-        let cmd_op = self.cmd_op & 0xff;
+/// Layout of device's exported json file, `{run_dir}/{dev_id:04}.json`
+///
+/// The file is written when the device is started or recovered, and
+/// is removed after the device is stopped or deleted.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UblkDevExport {
+    /// layout version, `UBLK_DEV_EXPORT_VERSION`
+    pub version: u32,
+    pub dev_info: sys::ublksrv_ctrl_dev_info,
+    pub target: UblkTgt,
+
+    /// `UblkFlags` of this device
+    #[serde(default)]
+    pub target_flags: u32,
+
+    /// target specific data, see `UblkDev::set_target_json()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_data: Option<serde_json::Value>,
+
+    /// queue entries indexed by queue id
+    #[serde(default)]
+    pub queues: std::collections::BTreeMap<u16, UblkQueueExport>,
+
+    /// affinity policy applied on queue pthreads, added in version 2
+    #[serde(default)]
+    pub affinity_policy: UblkAffinityPolicy,
+
+    /// recovery mode of this device, added in version 3
+    #[serde(default)]
+    pub recovery_mode: UblkRecoveryMode,
+}
 
-        if cmd_op != sys::UBLK_CMD_GET_DEV_INFO2
-            && (!dev.is_unprivileged() || (self.flags & CTRL_CMD_NO_NEED_DEV_PATH) != 0)
+impl UblkDevExport {
+    const FIELDS: [&'static str; 8] = [
+        "version",
+        "dev_info",
+        "target",
+        "target_flags",
+        "target_data",
+        "queues",
+        "affinity_policy",
+        "recovery_mode",
+    ];
+    const QUEUE_FIELDS: [&'static str; 3] = ["qid", "tid", "affinity"];
+
+    fn json_error(msg: String) -> UblkError {
+        UblkError::JsonError(<serde_json::Error as serde::de::Error>::custom(msg))
+    }
+
+    /// Check unknown and missing fields, which are only rejected in strict
+    /// mode, and `target_data` is the only field which can be missing
+    fn check_fields(val: &serde_json::Value) -> Result<(), UblkError> {
+        let obj = val
+            .as_object()
+            .ok_or_else(|| Self::json_error("device json isn't object".to_string()))?;
+
+        if let Some(key) = obj.keys().find(|k| !Self::FIELDS.contains(&k.as_str())) {
+            return Err(Self::json_error(format!("unknown field `{}`", key)));
+        }
+        if let Some(key) = Self::FIELDS
+            .iter()
+            .find(|k| **k != "target_data" && !obj.contains_key(**k))
         {
-            return (0, None);
+            return Err(Self::json_error(format!("missing field `{}`", key)));
         }
-
-        let (buf, new_buf) = {
-            let size = {
-                if self.flags & CTRL_CMD_HAS_BUF != 0 {
-                    self.len as usize + CTRL_UBLKC_PATH_MAX
-                } else {
-                    CTRL_UBLKC_PATH_MAX
+        if let Some(queues) = obj.get("queues").and_then(|q| q.as_object()) {
+            for q in queues.values().filter_map(|q| q.as_object()) {
+                if let Some(key) = q.keys().find(|k| !Self::QUEUE_FIELDS.contains(&k.as_str())) {
+                    return Err(Self::json_error(format!("unknown queue field `{}`", key)));
                 }
-            };
-            let mut v = vec![0_u8; size];
+                if let Some(key) = Self::QUEUE_FIELDS.iter().find(|k| !q.contains_key(**k)) {
+                    return Err(Self::json_error(format!("missing queue field `{}`", key)));
+                }
+            }
+        }
+        Ok(())
+    }
 
-            (v.as_mut_ptr(), v)
+    /// Migrate older layout to the current version
+    fn migrate(val: &mut serde_json::Value) -> Result<(), UblkError> {
+        let version = match val.get("version") {
+            None => 0,
+            Some(v) => v
+                .as_u64()
+                .ok_or_else(|| Self::json_error(format!("invalid version {}", v)))?,
         };
 
-        let path_str = dev.get_cdev_path().to_string();
-        assert!(path_str.len() <= CTRL_UBLKC_PATH_MAX);
-
-        unsafe {
-            libc::memset(buf as *mut libc::c_void, 0, CTRL_UBLKC_PATH_MAX);
-            libc::memcpy(
-                buf as *mut libc::c_void,
-                path_str.as_ptr() as *const libc::c_void,
-                path_str.len(),
-            );
+        if version > UBLK_DEV_EXPORT_VERSION as u64 {
+            log::warn!("device json version {} is newer than supported", version);
+            return Ok(());
+        }
 
-            if self.flags & CTRL_CMD_HAS_BUF != 0 {
-                libc::memcpy(
-                    (buf as u64 + CTRL_UBLKC_PATH_MAX as u64) as *mut libc::c_void,
-                    self.addr as *const libc::c_void,
-                    self.len as usize,
-                );
+        // version 0: no `version`, and `target_flags` may be missing
+        if version == 0 {
+            if let Some(obj) = val.as_object_mut() {
+                obj.entry("target_flags").or_insert(serde_json::json!(0));
+                if let Some(queues) = obj.get_mut("queues").and_then(|q| q.as_object_mut()) {
+                    for q in queues.values_mut().filter_map(|q| q.as_object_mut()) {
+                        q.entry("affinity").or_insert(serde_json::json!([]));
+                    }
+                }
             }
         }
 
-        self.flags |= CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA;
-        self.len += CTRL_UBLKC_PATH_MAX as u32;
-        self.dev_path_len = CTRL_UBLKC_PATH_MAX as u16;
-        let addr = self.addr;
-        self.addr = buf as u64;
-        (addr, Some(new_buf))
-    }
-
-    fn unprep_un_privileged_dev_path(&mut self, dev: &UblkCtrlInner, buf: u64) {
-        let cmd_op = self.cmd_op & 0xff;
+        // version 1: no `affinity_policy`, and driver default is applied
+        if version <= 1 {
+            if let Some(obj) = val.as_object_mut() {
+                obj.entry("affinity_policy")
+                    .or_insert(serde_json::json!(UblkAffinityPolicy::DriverDefault));
+            }
+        }
 
-        if cmd_op != sys::UBLK_CMD_GET_DEV_INFO2
-            && (!dev.is_unprivileged() || (self.flags & CTRL_CMD_NO_NEED_DEV_PATH) != 0)
-        {
-            return;
+        // version 2: no `recovery_mode`, which is figured out from flags
+        if version <= 2 {
+            let flags = val["dev_info"]["flags"].as_u64().unwrap_or(0);
+            if let Some(obj) = val.as_object_mut() {
+                obj.entry("recovery_mode")
+                    .or_insert(serde_json::json!(UblkRecoveryMode::from_flags(flags)));
+            }
         }
+        val["version"] = serde_json::json!(UBLK_DEV_EXPORT_VERSION);
+        Ok(())
+    }
 
-        let addr = self.addr + CTRL_UBLKC_PATH_MAX as u64;
-        let len = self.len - CTRL_UBLKC_PATH_MAX as u32;
-        if self.flags & CTRL_CMD_BUF_READ != 0 {
-            unsafe {
-                libc::memcpy(
-                    buf as *mut libc::c_void,
-                    addr as *const libc::c_void,
-                    len as usize,
-                );
+    /// Parse device export from json value
+    pub fn from_value(
+        mut val: serde_json::Value,
+        mode: UblkJsonParseMode,
+    ) -> Result<UblkDevExport, UblkError> {
+        match mode {
+            UblkJsonParseMode::Strict => {
+                match val.get("version").and_then(|v| v.as_u64()) {
+                    Some(v) if v == UBLK_DEV_EXPORT_VERSION as u64 => {}
+                    Some(v) => return Err(Self::json_error(format!("unsupported version {}", v))),
+                    None => return Err(Self::json_error("missing field `version`".to_string())),
+                }
+                Self::check_fields(&val)?;
             }
+            UblkJsonParseMode::Lenient => Self::migrate(&mut val)?,
         }
+
+        let export: UblkDevExport = serde_json::from_value(val)?;
+        Ok(export)
+    }
+
+    /// Parse device export from json string
+    pub fn from_json_str(s: &str, mode: UblkJsonParseMode) -> Result<UblkDevExport, UblkError> {
+        Self::from_value(serde_json::from_str(s)?, mode)
+    }
+
+    /// Load device export from json file, such as `UblkCtrl::run_path()`
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        mode: UblkJsonParseMode,
+    ) -> Result<UblkDevExport, UblkError> {
+        Self::from_json_str(&fs::read_to_string(path)?, mode)
+    }
+
+    /// Serialize as json string in the current layout
+    pub fn to_json_string(&self) -> Result<String, UblkError> {
+        Ok(serde_json::to_string(self)?)
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct QueueAffinityJson {
-    affinity: Vec<u32>,
-    qid: u32,
-    tid: u32,
+/// Why `UblkCtrlBuilder::build()` rejects queue number, depth or io
+/// buffer size of the device to be added
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UblkLimitError {
+    #[error("nr_queues {0} isn't in range of [1, {max}]", max = sys::UBLK_MAX_NR_QUEUES)]
+    NrQueues(u16),
+
+    #[error("queue depth {0} isn't in range of [1, {max}]", max = sys::UBLK_MAX_QUEUE_DEPTH)]
+    QueueDepth(u16),
+
+    #[error("io_buf_bytes {0} isn't in range of [page size, {max}]", max = MAX_BUF_SZ)]
+    IoBufBytes(u32),
+
+    #[error("io_buf_bytes {0} isn't aligned with page size")]
+    IoBufUnaligned(u32),
 }
 
 /// UblkSession: build one new ublk control device or recover the old one.
@@ -196,6 +1428,11 @@ pub struct UblkCtrlBuilder<'a> {
     /// block layer's queue limit of max hw sectors
     io_buf_bytes: u32,
 
+    /// auto mode for adding device: `nr_queues` is set as online CPU
+    /// count, and `io_buf_bytes` is aligned and clamped to the page-aligned
+    /// max buffer size instead of being rejected
+    auto_limits: bool,
+
     /// passed to ublk driver via `sys::ublksrv_ctrl_dev_info.flags`,
     /// usually for adding or recovering device
     ctrl_flags: u64,
@@ -206,6 +1443,57 @@ pub struct UblkCtrlBuilder<'a> {
 
     /// libublk feature flags: UBLK_DEV_F_*
     dev_flags: UblkFlags,
+
+    /// driver features which have to be supported, otherwise building
+    /// `UblkCtrl` fails with `UblkError::MissingFeatures`
+    required_features: UblkFeatures,
+
+    /// driver features which are enabled only if the running ublk driver
+    /// supports them, such as `UBLK_F_CMD_IOCTL_ENCODE | UBLK_F_USER_COPY`
+    preferred_features: UblkFeatures,
+
+    /// cpu affinity policy of queue pthread
+    affinity_policy: UblkAffinityPolicy,
+
+    /// recovery mode, which is converted to `UBLK_F_USER_RECOVERY*` for
+    /// adding device, and has to match with the device's recovery mode
+    /// for recovering device if it isn't `UblkRecoveryMode::Disabled`
+    recovery_mode: UblkRecoveryMode,
+
+    /// what `UblkCtrl::run_target()` does when SIGTERM or SIGINT is
+    /// received, `UblkShutdownMode::Quiesce` requires recovery support
+    shutdown_mode: UblkShutdownMode,
+
+    /// how long `UblkCtrl::run_target()` waits for queues to drain
+    /// in-flight IOs after the device is stopped by signal
+    shutdown_timeout: std::time::Duration,
+
+    /// timeout of each control command, the timed-out command is
+    /// cancelled and `UblkError::CtrlTimeout` is returned; wait forever
+    /// if it isn't set. It can be overridden per call via
+    /// `UblkCtrl::cmd_opts()`
+    #[setters(strip_option)]
+    cmd_timeout: Option<std::time::Duration>,
+
+    /// how long `UblkDev::new()` waits for /dev/ublkcN to be created and
+    /// made accessible by udev, such as running `ublk_chown.sh` for
+    /// unprivileged device
+    cdev_timeout: std::time::Duration,
+
+    /// if it is set, `UblkCtrl::run_target()` waits for /dev/ublkbN to be
+    /// ready via `UblkCtrl::wait_bdev_ready()` before calling `device_fn`,
+    /// and fails if the block device isn't ready in this timeout
+    #[setters(strip_option)]
+    bdev_ready_timeout: Option<std::time::Duration>,
+
+    /// directory for storing this device's exported json file, and
+    /// `UblkCtrl::run_dir()` is used if it isn't set
+    ///
+    /// Other processes have to look up this device via
+    /// `UblkCtrl::new_simple_with_run_dir()` or `UblkCtrl::set_run_dir()`
+    /// for finding the json file.
+    #[setters(strip_option)]
+    run_dir: Option<&'a str>,
 }
 
 impl Default for UblkCtrlBuilder<'_> {
@@ -216,9 +1504,20 @@ impl Default for UblkCtrlBuilder<'_> {
             nr_queues: 1,
             depth: 64,
             io_buf_bytes: 524288,
+            auto_limits: false,
             ctrl_flags: 0,
             ctrl_target_flags: 0,
             dev_flags: UblkFlags::empty(),
+            required_features: UblkFeatures::empty(),
+            preferred_features: UblkFeatures::empty(),
+            affinity_policy: UblkAffinityPolicy::DriverDefault,
+            recovery_mode: UblkRecoveryMode::Disabled,
+            shutdown_mode: UblkShutdownMode::Disabled,
+            shutdown_timeout: UBLK_SHUTDOWN_TIMEOUT,
+            cmd_timeout: None,
+            cdev_timeout: UBLK_CDEV_TIMEOUT,
+            bdev_ready_timeout: None,
+            run_dir: None,
         }
     }
 }
@@ -227,16 +1526,162 @@ impl UblkCtrlBuilder<'_> {
     /// create one pair of ublk devices, the 1st one is control device(`UblkCtrl`),
     /// and the 2nd one is data device(`UblkDev`)
     pub fn build(self) -> Result<UblkCtrl, UblkError> {
-        UblkCtrl::new(
+        let for_add = self.dev_flags.intersects(UblkFlags::UBLK_DEV_F_ADD_DEV);
+        let for_recover = self.dev_flags.intersects(UblkFlags::UBLK_DEV_F_RECOVER_DEV);
+
+        // raw recovery flags can't conflict with recovery mode
+        let raw_mode = UblkRecoveryMode::from_flags(self.ctrl_flags);
+        if self.recovery_mode != UblkRecoveryMode::Disabled
+            && raw_mode != UblkRecoveryMode::Disabled
+            && raw_mode != self.recovery_mode
+        {
+            return Err(UblkError::InvalidVal);
+        }
+
+        // validate limits before sending ADD_DEV, and the driver returns
+        // the actual ones for other commands
+        let (nr_queues, depth, io_buf_bytes) = if for_add {
+            self.dev_limits()?
+        } else {
+            (self.nr_queues, self.depth, self.io_buf_bytes)
+        };
+
+        let ctrl_flags = if for_add {
+            self.ctrl_flags | self.negotiate_features()?.bits()
+        } else {
+            self.ctrl_flags
+        };
+
+        if let UblkAffinityPolicy::Explicit(cpus) = &self.affinity_policy {
+            if for_add && cpus.len() < nr_queues as usize {
+                return Err(UblkError::InvalidVal);
+            }
+            let valid = |c: &Vec<u32>| UblkQueueAffinity::from_cpus(c).is_ok_and(|a| !a.is_empty());
+            if !cpus.iter().all(valid) {
+                return Err(UblkError::InvalidVal);
+            }
+        }
+
+        // quiesce requires recovery support, which is checked in
+        // check_recovery() for recovering device
+        if for_add
+            && self.shutdown_mode == UblkShutdownMode::Quiesce
+            && UblkRecoveryMode::from_flags(ctrl_flags) == UblkRecoveryMode::Disabled
+        {
+            return Err(UblkError::InvalidVal);
+        }
+
+        let ctrl = UblkCtrl::new_with_config(
             Some(self.name.to_string()),
             self.id,
-            self.nr_queues.into(),
-            self.depth.into(),
-            self.io_buf_bytes,
-            self.ctrl_flags,
+            nr_queues.into(),
+            depth.into(),
+            io_buf_bytes,
+            ctrl_flags,
             self.ctrl_target_flags,
             self.dev_flags,
-        )
+            UblkCtrlConfig {
+                affinity_policy: self.affinity_policy,
+                shutdown_mode: self.shutdown_mode,
+                shutdown_timeout: self.shutdown_timeout,
+                cmd_timeout: self.cmd_timeout,
+                cdev_timeout: self.cdev_timeout,
+                bdev_ready_timeout: self.bdev_ready_timeout,
+                run_dir: self.run_dir.map(|d| d.to_string()),
+            },
+        )?;
+
+        if for_recover {
+            let mode = ctrl.get_inner().check_recovery()?;
+            if self.recovery_mode != UblkRecoveryMode::Disabled && self.recovery_mode != mode {
+                return Err(UblkError::RecoveryNotSupported {
+                    dev_id: ctrl.dev_info().dev_id,
+                    mode: self.recovery_mode,
+                });
+            }
+        }
+        Ok(ctrl)
+    }
+
+    /// Return queue number, depth and io buffer size for adding device,
+    /// which are resolved in auto mode and validated against the limits
+    /// of ublk driver
+    fn dev_limits(&self) -> Result<(u16, u16, u32), UblkLimitError> {
+        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let max_buf = MAX_BUF_SZ & !(page_sz - 1);
+
+        let (nr_queues, io_buf_bytes) = if self.auto_limits {
+            let nr_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as u32;
+            (
+                nr_cpus.min(sys::UBLK_MAX_NR_QUEUES) as u16,
+                (self.io_buf_bytes & !(page_sz - 1)).clamp(page_sz, max_buf),
+            )
+        } else {
+            (self.nr_queues, self.io_buf_bytes)
+        };
+
+        if nr_queues == 0 || u32::from(nr_queues) > sys::UBLK_MAX_NR_QUEUES {
+            return Err(UblkLimitError::NrQueues(nr_queues));
+        }
+        if self.depth == 0 || u32::from(self.depth) > sys::UBLK_MAX_QUEUE_DEPTH {
+            return Err(UblkLimitError::QueueDepth(self.depth));
+        }
+        if io_buf_bytes < page_sz || io_buf_bytes > max_buf {
+            return Err(UblkLimitError::IoBufBytes(io_buf_bytes));
+        }
+        if io_buf_bytes & (page_sz - 1) != 0 {
+            return Err(UblkLimitError::IoBufUnaligned(io_buf_bytes));
+        }
+        Ok((nr_queues, self.depth, io_buf_bytes))
+    }
+
+    /// Figure out features to be enabled from the required & preferred
+    /// features and the ones supported by the running ublk driver
+    fn negotiate_features(&self) -> Result<UblkFeatures, UblkError> {
+        if self.required_features.is_empty()
+            && self.preferred_features.is_empty()
+            && self.recovery_mode == UblkRecoveryMode::Disabled
+        {
+            return Ok(UblkFeatures::empty());
+        }
+
+        // GET_FEATURES is supported since v6.5, and the older driver
+        // only supports the legacy features
+        let supported = UblkCtrl::get_features_flags().unwrap_or(UblkCtrl::UBLK_DRV_F_LEGACY);
+        let required = self.required_features | self.recovery_mode.features();
+        let missing = required - supported;
+        if !missing.is_empty() {
+            return Err(UblkError::MissingFeatures(missing));
+        }
+
+        Ok(required | (self.preferred_features & supported))
+    }
+}
+
+/// Optional settings of `UblkCtrl`, which are only configured via
+/// `UblkCtrlBuilder`
+#[derive(Debug, Clone)]
+struct UblkCtrlConfig {
+    affinity_policy: UblkAffinityPolicy,
+    shutdown_mode: UblkShutdownMode,
+    shutdown_timeout: std::time::Duration,
+    cmd_timeout: Option<std::time::Duration>,
+    cdev_timeout: std::time::Duration,
+    bdev_ready_timeout: Option<std::time::Duration>,
+    run_dir: Option<String>,
+}
+
+impl Default for UblkCtrlConfig {
+    fn default() -> Self {
+        UblkCtrlConfig {
+            affinity_policy: UblkAffinityPolicy::DriverDefault,
+            shutdown_mode: UblkShutdownMode::Disabled,
+            shutdown_timeout: UBLK_SHUTDOWN_TIMEOUT,
+            cmd_timeout: None,
+            cdev_timeout: UBLK_CDEV_TIMEOUT,
+            bdev_ready_timeout: None,
+            run_dir: None,
+        }
     }
 }
 
@@ -258,12 +1703,25 @@ struct UblkCtrlInner {
     name: Option<String>,
     file: fs::File,
     dev_info: sys::ublksrv_ctrl_dev_info,
-    json: serde_json::Value,
-    features: Option<u64>,
+    json: Option<UblkDevExport>,
+    features: Option<UblkFeatures>,
 
     /// global flags, shared with UblkDev and UblkQueue
     dev_flags: UblkFlags,
     cmd_token: i32,
+    cmd_timeout: Option<std::time::Duration>,
+    cdev_timeout: std::time::Duration,
+    bdev_ready_timeout: Option<std::time::Duration>,
+    run_dir: String,
+    affinity_policy: UblkAffinityPolicy,
+    shutdown_mode: UblkShutdownMode,
+    shutdown_timeout: std::time::Duration,
+    listener: Option<Arc<dyn UblkEventListener>>,
+    driver: Arc<dyn UblkDriver>,
+
+    /// advisory lock of the exported json file, held in the whole
+    /// daemon lifetime after the json file is flushed
+    lock_file: Option<fs::File>,
     queue_tids: Vec<i32>,
     nr_queues_configured: u16,
 }
@@ -293,6 +1751,7 @@ impl UblkCtrlInner {
         flags: u64,
         tgt_flags: u64,
         dev_flags: UblkFlags,
+        cfg: UblkCtrlConfig,
     ) -> Result<UblkCtrlInner, UblkError> {
         let info = sys::ublksrv_ctrl_dev_info {
             nr_hw_queues: nr_queues as u16,
@@ -304,17 +1763,28 @@ impl UblkCtrlInner {
             ublksrv_flags: tgt_flags,
             ..Default::default()
         };
-        let fd = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(CTRL_PATH)?;
+        let driver = crate::driver::current_driver();
+        let fd = driver.open_ctrl()?;
 
         let mut dev = UblkCtrlInner {
             name,
             file: fd,
             dev_info: info,
-            json: serde_json::json!({}),
+            json: None,
             cmd_token: 0,
+            cmd_timeout: cfg.cmd_timeout,
+            cdev_timeout: cfg.cdev_timeout,
+            bdev_ready_timeout: cfg.bdev_ready_timeout,
+            run_dir: cfg.run_dir.unwrap_or_else(|| {
+                let lookup = id >= 0 && !dev_flags.intersects(UblkFlags::UBLK_DEV_F_ADD_DEV);
+                UblkCtrl::resolve_run_dir(lookup.then_some(id as u32))
+            }),
+            affinity_policy: cfg.affinity_policy,
+            shutdown_mode: cfg.shutdown_mode,
+            shutdown_timeout: cfg.shutdown_timeout,
+            listener: None,
+            driver,
+            lock_file: None,
             queue_tids: {
                 let mut tids = Vec::<i32>::with_capacity(nr_queues as usize);
                 unsafe {
@@ -328,7 +1798,7 @@ impl UblkCtrlInner {
         };
 
         let features = match dev.__get_features() {
-            Ok(f) => Some(f),
+            Ok(f) => Some(UblkFeatures::from_bits_retain(f)),
             _ => None,
         };
         dev.features = features;
@@ -337,8 +1807,15 @@ impl UblkCtrlInner {
         if dev.for_add_dev() {
             dev.add()?;
         } else if id >= 0 {
+            // json of the recovered device is locked before recovery, so
+            // it can't be recovered by two daemons
+            if dev.for_recover_dev() && Path::new(&dev.run_path()).exists() {
+                dev.lock_json()?;
+            }
+            // device may not be created by libublk, so json file can
+            // be missing
             let res = dev.reload_json();
-            if res.is_err() {
+            if res.is_err() && Path::new(&dev.run_path()).exists() {
                 eprintln!("device reload json failed");
             }
             dev.read_dev_info()?;
@@ -369,160 +1846,300 @@ impl UblkCtrlInner {
         self.dev_flags.intersects(UblkFlags::UBLK_DEV_F_RECOVER_DEV)
     }
 
-    fn dev_state_desc(&self) -> String {
-        match self.dev_info.state as u32 {
-            sys::UBLK_S_DEV_DEAD => "DEAD".to_string(),
-            sys::UBLK_S_DEV_LIVE => "LIVE".to_string(),
-            sys::UBLK_S_DEV_QUIESCED => "QUIESCED".to_string(),
-            _ => "UNKNOWN".to_string(),
+    fn state(&self) -> UblkDevState {
+        UblkDevState::from(self.dev_info.state)
+    }
+
+    /// Context for issuing control commands of this device with
+    /// command timeout `timeout`
+    fn cmd_ctx(&self, timeout: Option<std::time::Duration>) -> UblkCtrlCmdCtx {
+        UblkCtrlCmdCtx {
+            fd: self.file.as_raw_fd(),
+            dev_id: self.dev_info.dev_id,
+            unprivileged: self.is_unprivileged(),
+            cdev_path: self.get_cdev_path(),
+            cmd_timeout: timeout,
+            driver: self.driver.clone(),
         }
     }
 
-    fn store_queue_tid(&mut self, qid: u16, tid: i32) {
-        self.queue_tids[qid as usize] = tid;
+    /// Run `f` with control command timeout overridden as `timeout`, and
+    /// the override isn't visible to others since `self` is borrowed
+    /// exclusively
+    fn with_cmd_timeout<R>(
+        &mut self,
+        timeout: Option<std::time::Duration>,
+        f: impl FnOnce(&mut Self) -> R,
+    ) -> R {
+        let old = std::mem::replace(&mut self.cmd_timeout, timeout);
+        let res = f(self);
+
+        self.cmd_timeout = old;
+        res
     }
 
-    fn dump_from_json(&self) {
-        if !Path::new(&self.run_path()).exists() {
-            return;
+    /// Notify event listener if it is installed
+    fn notify<F: FnOnce(&dyn UblkEventListener, u32)>(&self, f: F) {
+        if let Some(l) = &self.listener {
+            f(l.as_ref(), self.dev_info.dev_id);
+        }
+    }
+
+    /// Check if this device can be recovered: it has to be created with
+    /// recovery support, and the recovery mode recorded in json has to
+    /// be same with the one in device flags
+    fn check_recovery(&self) -> Result<UblkRecoveryMode, UblkError> {
+        let mode = UblkRecoveryMode::from_flags(self.dev_info.flags);
+        let recorded = self.json.as_ref().map_or(mode, |e| e.recovery_mode);
+
+        if mode == UblkRecoveryMode::Disabled || recorded != mode {
+            return Err(UblkError::RecoveryNotSupported {
+                dev_id: self.dev_info.dev_id,
+                mode: recorded,
+            });
         }
-        let mut file = fs::File::open(self.run_path()).expect("Failed to open file");
-        let mut json_str = String::new();
+        Ok(mode)
+    }
 
-        file.read_to_string(&mut json_str)
-            .expect("Failed to read file");
+    fn store_queue_tid(&mut self, qid: u16, tid: i32) {
+        self.queue_tids[qid as usize] = tid;
+    }
 
-        let json_value: serde_json::Value =
-            serde_json::from_str(&json_str).expect("Failed to parse JSON");
-        let queues = &json_value["queues"];
+    /// cpus which queue pthread `qid` is bound to actually, which may
+    /// be different with the affinity policy, such as setting affinity
+    /// fails, and it is empty if the queue pthread isn't pinned
+    fn applied_queue_affinity(&self, qid: u16) -> UblkQueueAffinity {
+        if self.affinity_policy == UblkAffinityPolicy::None {
+            return UblkQueueAffinity::default();
+        }
+        self.queue_tids
+            .get(qid as usize)
+            .and_then(|tid| UblkQueueAffinity::from_thread(*tid).ok())
+            .unwrap_or_default()
+    }
 
-        for i in 0..self.dev_info.nr_hw_queues {
-            let queue = &queues[i.to_string()];
-            let this_queue: Result<QueueAffinityJson, _> = serde_json::from_value(queue.clone());
+    /// Build report from device info, parameters and exported json file
+    fn describe(&mut self) -> Result<UblkDevReport, UblkError> {
+        let mut p = sys::ublk_params {
+            ..Default::default()
+        };
 
-            if let Ok(p) = this_queue {
-                println!(
-                    "\tqueue {} tid: {} affinity({})",
-                    p.qid,
-                    p.tid,
-                    p.affinity
-                        .iter()
-                        .map(ToString::to_string)
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                );
+        self.read_dev_info()?;
+        self.get_params(&mut p)?;
+
+        // prefer the exported json file, which may be updated by the daemon
+        let export = if Path::new(&self.run_path()).exists() {
+            match UblkDevExport::from_file(self.run_path(), UblkJsonParseMode::Lenient) {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    error!("parse {} failed: {}", self.run_path(), e);
+                    None
+                }
             }
-        }
-        let tgt_val = &json_value["target"];
-        let tgt: Result<UblkTgt, _> = serde_json::from_value(tgt_val.clone());
-        if let Ok(p) = tgt {
-            println!(
-                "\ttarget {{\"dev_size\":{},\"name\":\"{}\",\"type\":0}}",
-                p.dev_size, p.tgt_type
-            );
-        }
-        println!("\ttarget_data {}", &json_value["target_data"]);
+        } else {
+            self.json.clone()
+        };
+        let info = &self.dev_info;
+
+        Ok(UblkDevReport {
+            dev_info: *info,
+            params: p,
+            state: self.state(),
+            devt: p.devt,
+            owner_uid: info.owner_uid,
+            owner_gid: info.owner_gid,
+            queues: export
+                .as_ref()
+                .map(|e| e.queues.values().cloned().collect())
+                .unwrap_or_default(),
+            target: export.as_ref().map(|e| e.target.clone()),
+            target_data: export.and_then(|e| e.target_data),
+        })
     }
 
     /// Returned path of this device's exported json file
     ///
     fn run_path(&self) -> String {
-        format!("{}/{:04}.json", UblkCtrl::run_dir(), self.dev_info.dev_id)
+        format!("{}/{:04}.json", self.run_dir, self.dev_info.dev_id)
     }
 
-    fn ublk_ctrl_prep_cmd(
-        &mut self,
-        fd: i32,
-        dev_id: u32,
-        data: &UblkCtrlCmdData,
-        token: u64,
-    ) -> squeue::Entry128 {
-        let cmd = sys::ublksrv_ctrl_cmd {
-            addr: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
-                data.addr
-            } else {
-                0
-            },
-            len: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
-                data.len as u16
-            } else {
-                0
-            },
-            data: if (data.flags & CTRL_CMD_HAS_DATA) != 0 {
-                [data.data]
-            } else {
-                [0]
-            },
-            dev_id,
-            queue_id: u16::MAX,
-            dev_path_len: data.dev_path_len,
-            ..Default::default()
-        };
-        let c_cmd = CtrlCmd { ctrl_cmd: cmd };
+    /// Path of the advisory lock file for this device's exported json file
+    fn lock_path(&self) -> String {
+        format!("{}/{:04}.lock", self.run_dir, self.dev_info.dev_id)
+    }
 
-        opcode::UringCmd80::new(types::Fd(fd), data.cmd_op)
-            .cmd(unsafe { c_cmd.buf })
-            .build()
-            .user_data(token)
+    /// Lock this device's exported json file, and the lock is held until
+    /// this device is stopped or deleted, or the daemon is gone
+    ///
+    /// The lock file is removed by its owner with the lock held, so the
+    /// lock is retried if the locked file has been removed, otherwise two
+    /// daemons could hold lock of different files.
+    ///
+    /// -EBUSY is returned if the lock is held by another daemon.
+    fn lock_json(&mut self) -> Result<i32, UblkError> {
+        use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+        if self.lock_file.is_some() {
+            return Ok(0);
+        }
+
+        loop {
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .mode(0o644)
+                .open(self.lock_path())?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+                let err = std::io::Error::last_os_error();
+
+                if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                    error!("json of dev {} is locked by others", self.dev_info.dev_id);
+                    return Err(UblkOpError::new("lock_json", -libc::EBUSY)
+                        .dev(self.dev_info.dev_id)
+                        .into());
+                }
+                return Err(UblkError::IOError(err));
+            }
+
+            let locked = file.metadata()?;
+            if matches!(fs::metadata(self.lock_path()),
+                    Ok(m) if m.dev() == locked.dev() && m.ino() == locked.ino())
+            {
+                self.lock_file = Some(file);
+                return Ok(0);
+            }
+        }
     }
 
-    fn ublk_submit_cmd_async(&mut self, data: &UblkCtrlCmdData) -> UblkUringOpFuture {
-        let fd = self.file.as_raw_fd();
-        let dev_id = self.dev_info.dev_id;
-        let f = UblkUringOpFuture::new(0);
-        let sqe = self.ublk_ctrl_prep_cmd(fd, dev_id, data, f.user_data);
+    /// Remove the exported json file, and remove the lock file with its
+    /// lock held, then release the lock
+    fn remove_json(&mut self) -> Result<i32, UblkError> {
+        let run_path = self.run_path();
 
-        unsafe {
-            CTRL_URING.with(|refcell| {
-                refcell.borrow_mut().submission().push(&sqe).unwrap();
-            })
+        if Path::new(&run_path).exists() {
+            fs::remove_file(run_path)?;
         }
-        f
+        if self.lock_file.is_some() {
+            let _ = fs::remove_file(self.lock_path());
+        }
+        self.lock_file = None;
+        Ok(0)
+    }
+
+    /// Check if the json file is locked by one live daemon
+    ///
+    /// None is returned if the lock file doesn't exist, such as the json
+    /// file is exported by older libublk.
+    fn json_locked_by_others(&self) -> Option<bool> {
+        let file = fs::File::open(self.lock_path()).ok()?;
+        let res = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) };
+
+        Some(res < 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK))
+    }
+
+    /// Cancel one in-flight control command via IORING_OP_ASYNC_CANCEL
+    ///
+    /// The cancelled command's CQE is dropped when it is reaped.
+    fn cancel_cmd(r: &mut IoUring<squeue::Entry128>, token: u64) {
+        let sqe = opcode::AsyncCancel::new(token)
+            .build()
+            .user_data(CTRL_CANCEL_TOKEN);
+
+        unsafe { r.submission().push(&squeue::Entry128::from(sqe)).unwrap() };
+        let _ = r.submit();
     }
 
     fn ublk_submit_cmd(
         &mut self,
+        ctx: &UblkCtrlCmdCtx,
         data: &UblkCtrlCmdData,
         to_wait: usize,
     ) -> Result<u64, UblkError> {
-        let fd = self.file.as_raw_fd();
-        let dev_id = self.dev_info.dev_id;
-
         // token is generated uniquely because '&mut self' is
         // passed in
         let token = {
             self.cmd_token += 1;
             self.cmd_token
-        } as u64;
-        let sqe = self.ublk_ctrl_prep_cmd(fd, dev_id, data, token);
+        } as u64
+            | CTRL_SYNC_TOKEN;
+        let sqe = ctx.prep_cmd(data, token);
 
         CTRL_URING.with(|refcell| {
             let mut r = refcell.borrow_mut();
 
-            unsafe { r.submission().push(&sqe).unwrap() };
+            if let Some(sqe) = sqe {
+                unsafe { r.submission().push(&sqe).unwrap() };
+            }
             let _ = r.submit_and_wait(to_wait);
         });
         Ok(token)
     }
 
-    /// check one control command and see if it is completed
+    /// Reap all completed CQEs, and return the result of `token`
+    ///
+    /// CQEs of async control commands are forwarded to the waiting
+    /// task, and buffers of cancelled sync commands are freed.
+    fn reap_cmd(r: &mut IoUring<squeue::Entry128>, token: u64) -> Option<i32> {
+        let cqes: Vec<cqueue::Entry> = r.completion().collect();
+        let mut res = None;
+
+        for cqe in cqes {
+            let data = cqe.user_data();
+
+            if data == token {
+                res = Some(cqe.result());
+            } else {
+                ublk_ctrl_handle_cqe(&cqe);
+            }
+        }
+        res
+    }
+
+    /// wait until one control command is completed
     ///
-    fn poll_cmd(&mut self, token: u64) -> i32 {
+    /// The command is cancelled and `UblkError::CtrlTimeout` is returned
+    /// if it isn't completed in `timeout`.
+    fn wait_cmd(
+        &self,
+        token: u64,
+        cmd_op: u32,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<i32, UblkError> {
+        let deadline = timeout.map(|t| std::time::Instant::now() + t);
+
         CTRL_URING.with(|refcell| {
             let mut r = refcell.borrow_mut();
 
-            let res = match r.completion().next() {
-                Some(cqe) => {
-                    if cqe.user_data() != token {
-                        -libc::EAGAIN
-                    } else {
-                        cqe.result()
+            loop {
+                if let Some(res) = Self::reap_cmd(&mut r, token) {
+                    return Ok(res);
+                }
+                let res = match deadline {
+                    None => r.submit_and_wait(1),
+                    Some(d) => {
+                        let left = d.saturating_duration_since(std::time::Instant::now());
+                        if left.is_zero() {
+                            break;
+                        }
+                        let ts = types::Timespec::from(left);
+                        let args = types::SubmitArgs::new().timespec(&ts);
+                        r.submitter().submit_with_args(1, &args)
+                    }
+                };
+                if let Err(e) = res {
+                    if !matches!(e.raw_os_error(), Some(libc::ETIME) | Some(libc::EINTR)) {
+                        return Err(UblkError::IOError(e));
                     }
                 }
-                None => -libc::EAGAIN,
-            };
+            }
 
-            res
+            Self::cancel_cmd(&mut r, token);
+            Err(UblkError::CtrlTimeout {
+                cmd: cmd_op,
+                dev_id: self.dev_info.dev_id,
+            })
         })
     }
 
@@ -533,56 +2150,41 @@ impl UblkCtrlInner {
     ) -> bool {
         let legacy_op = data.cmd_op & 0xff;
 
-        // Needn't to retry:
-        //
-        // 1) command is completed successfully
-        //
-        // 2) this is new command which has been issued via ioctl encoding
-        // already
-        if res >= 0 || res == -libc::EBUSY || (legacy_op > sys::UBLK_CMD_GET_DEV_INFO2) {
-            false
-        } else {
-            *new_data = *data;
-            new_data.cmd_op = legacy_op;
-            true
-        }
-    }
-
-    fn ublk_err_to_result(res: i32) -> Result<i32, UblkError> {
-        if res >= 0 || res == -libc::EBUSY {
-            Ok(res)
-        } else {
-            Err(UblkError::UringIOError(res))
-        }
-    }
-
-    async fn ublk_ctrl_cmd_async(&mut self, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
-        let mut new_data = *data;
-        let mut res: i32 = 0;
-
-        for _ in 0..2 {
-            let (old_buf, _new) = new_data.prep_un_privileged_dev_path(self);
-            res = self.ublk_submit_cmd_async(&new_data).await;
-            new_data.unprep_un_privileged_dev_path(self, old_buf);
-
-            trace!("ublk_ctrl_cmd_async: cmd {:x} res {}", data.cmd_op, res);
-            if !Self::ublk_ctrl_need_retry(&mut new_data, data, res) {
-                break;
-            }
-        }
-
-        Self::ublk_err_to_result(res)
+        // Needn't to retry:
+        //
+        // 1) command is completed successfully
+        //
+        // 2) this is new command which has been issued via ioctl encoding
+        // already
+        if res >= 0 || res == -libc::EBUSY || (legacy_op > sys::UBLK_CMD_GET_DEV_INFO2) {
+            false
+        } else {
+            *new_data = *data;
+            new_data.cmd_op = legacy_op;
+            true
+        }
     }
 
     fn ublk_ctrl_cmd(&mut self, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
+        let ctx = self.cmd_ctx(self.cmd_timeout);
         let mut new_data = *data;
         let mut res: i32 = 0;
 
         for _ in 0..2 {
-            let (old_buf, _new) = new_data.prep_un_privileged_dev_path(self);
-            let token = self.ublk_submit_cmd(&new_data, 1)?;
-            res = self.poll_cmd(token);
-            new_data.unprep_un_privileged_dev_path(self, old_buf);
+            let (old_buf, new) = new_data.prep_cmd_buf(&ctx);
+            let token = self.ublk_submit_cmd(&ctx, &new_data, 0)?;
+            res = match self.wait_cmd(token, data.cmd_op, ctx.cmd_timeout) {
+                Ok(r) => r,
+                Err(e) => {
+                    // the cancelled command may still touch the buffer
+                    if let Some(buf) = new {
+                        CTRL_CANCELLED_BUFS.with(|bufs| bufs.borrow_mut().insert(token, buf));
+                    }
+                    return Err(e);
+                }
+            };
+            new_data.unprep_cmd_buf(old_buf);
+            drop(new);
 
             trace!("ublk_ctrl_cmd: cmd {:x} res {}", data.cmd_op, res);
             if !Self::ublk_ctrl_need_retry(&mut new_data, data, res) {
@@ -590,13 +2192,13 @@ impl UblkCtrlInner {
             }
         }
 
-        Self::ublk_err_to_result(res)
+        ctx.ublk_err_to_result(data.cmd_op, res)
     }
 
     fn add(&mut self) -> Result<i32, UblkError> {
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_U_CMD_ADD_DEV,
-            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_NO_NEED_DEV_PATH,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_READ | CTRL_CMD_NO_NEED_DEV_PATH,
             addr: std::ptr::addr_of!(self.dev_info) as u64,
             len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
             ..Default::default()
@@ -608,7 +2210,13 @@ impl UblkCtrlInner {
     /// Remove this device
     ///
     fn del(&mut self) -> Result<i32, UblkError> {
-        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+        let data = self.del_cmd_data();
+
+        self.ublk_ctrl_cmd(&data)
+    }
+
+    fn del_cmd_data(&self) -> UblkCtrlCmdData {
+        UblkCtrlCmdData {
             cmd_op: if self
                 .dev_flags
                 .intersects(UblkFlags::UBLK_DEV_F_DEL_DEV_ASYNC)
@@ -618,17 +2226,15 @@ impl UblkCtrlInner {
                 sys::UBLK_U_CMD_DEL_DEV
             },
             ..Default::default()
-        };
-
-        self.ublk_ctrl_cmd(&data)
+        }
     }
 
     fn __get_features(&mut self) -> Result<u64, UblkError> {
-        let features = 0_u64;
+        let mut features = 0_u64;
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_U_CMD_GET_FEATURES,
             flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_READ | CTRL_CMD_NO_NEED_DEV_PATH,
-            addr: std::ptr::addr_of!(features) as u64,
+            addr: std::ptr::addr_of_mut!(features) as u64,
             len: core::mem::size_of::<u64>() as u32,
             ..Default::default()
         };
@@ -682,20 +2288,9 @@ impl UblkCtrlInner {
             ..Default::default()
         };
 
-        self.ublk_ctrl_cmd(&data)
-    }
-
-    /// Start this device by sending command to ublk driver
-    ///
-    async fn start_async(&mut self, pid: i32) -> Result<i32, UblkError> {
-        let data: UblkCtrlCmdData = UblkCtrlCmdData {
-            cmd_op: sys::UBLK_U_CMD_START_DEV,
-            flags: CTRL_CMD_HAS_DATA,
-            data: pid as u64,
-            ..Default::default()
-        };
-
-        self.ublk_ctrl_cmd_async(&data).await
+        let res = self.ublk_ctrl_cmd(&data)?;
+        self.notify(|l, id| l.on_started(id));
+        Ok(res)
     }
 
     /// Stop this device by sending command to ublk driver
@@ -706,7 +2301,9 @@ impl UblkCtrlInner {
             ..Default::default()
         };
 
-        self.ublk_ctrl_cmd(&data)
+        let res = self.ublk_ctrl_cmd(&data)?;
+        self.notify(|l, id| l.on_stopped(id));
+        Ok(res)
     }
 
     /// Retrieve this device's parameter from ublk driver by
@@ -742,7 +2339,9 @@ impl UblkCtrlInner {
             ..Default::default()
         };
 
-        self.ublk_ctrl_cmd(&data)
+        let res = self.ublk_ctrl_cmd(&data)?;
+        self.notify(|l, id| l.on_params_set(id));
+        Ok(res)
     }
 
     fn get_queue_affinity(&mut self, q: u32, bm: &mut UblkQueueAffinity) -> Result<i32, UblkError> {
@@ -757,22 +2356,46 @@ impl UblkCtrlInner {
         self.ublk_ctrl_cmd(&data)
     }
 
-    fn __start_user_recover(&mut self) -> Result<i32, UblkError> {
-        let data: UblkCtrlCmdData = UblkCtrlCmdData {
-            cmd_op: sys::UBLK_U_CMD_START_USER_RECOVERY,
-            ..Default::default()
-        };
+    /// Figure out cpu affinity of queue `qid` according to affinity policy,
+    /// and None means that the queue pthread isn't pinned
+    fn queue_affinity(&mut self, qid: u16) -> Result<Option<UblkQueueAffinity>, UblkError> {
+        let mut driver = UblkQueueAffinity::new();
+
+        match &self.affinity_policy {
+            UblkAffinityPolicy::None => return Ok(None),
+            UblkAffinityPolicy::Explicit(cpus) => {
+                return match cpus.get(qid as usize) {
+                    Some(c) if !c.is_empty() => Ok(Some(UblkQueueAffinity::from_cpus(c)?)),
+                    _ => Err(UblkError::InvalidVal),
+                };
+            }
+            UblkAffinityPolicy::RoundRobin => {
+                let cpus = UblkQueueAffinity::from_current_thread()?.cpus();
+                if !cpus.is_empty() {
+                    let cpu = cpus[qid as usize % cpus.len()];
+                    return Ok(Some(UblkQueueAffinity::from_cpus(&[cpu])?));
+                }
+            }
+            _ => {}
+        }
 
-        self.ublk_ctrl_cmd(&data)
+        self.get_queue_affinity(qid as u32, &mut driver)?;
+        if self.affinity_policy == UblkAffinityPolicy::NumaLocal {
+            let node_cpus = driver
+                .first_cpu()
+                .and_then(UblkQueueAffinity::cpu_numa_node)
+                .and_then(|node| UblkQueueAffinity::from_numa_node(node).ok());
+
+            if let Some(cpus) = node_cpus {
+                return Ok(Some(cpus));
+            }
+        }
+        Ok(Some(driver))
     }
 
-    /// End user recover for this device, do similar thing done in start_dev()
-    ///
-    fn end_user_recover(&mut self, pid: i32) -> Result<i32, UblkError> {
+    fn __start_user_recover(&mut self) -> Result<i32, UblkError> {
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
-            cmd_op: sys::UBLK_U_CMD_END_USER_RECOVERY,
-            flags: CTRL_CMD_HAS_DATA,
-            data: pid as u64,
+            cmd_op: sys::UBLK_U_CMD_START_USER_RECOVERY,
             ..Default::default()
         };
 
@@ -781,7 +2404,7 @@ impl UblkCtrlInner {
 
     /// End user recover for this device, do similar thing done in start_dev()
     ///
-    async fn end_user_recover_async(&mut self, pid: i32) -> Result<i32, UblkError> {
+    fn end_user_recover(&mut self, pid: i32) -> Result<i32, UblkError> {
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_U_CMD_END_USER_RECOVERY,
             flags: CTRL_CMD_HAS_DATA,
@@ -789,7 +2412,9 @@ impl UblkCtrlInner {
             ..Default::default()
         };
 
-        self.ublk_ctrl_cmd_async(&data).await
+        let res = self.ublk_ctrl_cmd(&data)?;
+        self.notify(|l, id| l.on_started(id));
+        Ok(res)
     }
 
     fn prep_start_dev(&mut self, dev: &UblkDev) -> Result<i32, UblkError> {
@@ -802,6 +2427,7 @@ impl UblkCtrlInner {
             self.set_params(&dev.tgt.params)?;
             self.flush_json()?;
         } else if self.for_recover_dev() {
+            self.check_recovery()?;
             self.flush_json()?;
         } else {
             return Err(crate::UblkError::OtherError(-libc::EINVAL));
@@ -824,9 +2450,10 @@ impl UblkCtrlInner {
 
     /// Flush this device's json info as file
     fn flush_json(&mut self) -> Result<i32, UblkError> {
-        if self.json == serde_json::json!({}) {
-            return Ok(0);
-        }
+        let json_str = match &self.json {
+            Some(export) => export.to_json_string()?,
+            None => return Ok(0),
+        };
 
         // flushing json should only be done in case of adding new device
         // or recovering old device
@@ -847,14 +2474,27 @@ impl UblkCtrlInner {
                 Self::set_path_permission(parent_dir, 0o777)?;
             }
         }
-        let mut run_file = fs::File::create(json_path)?;
+        self.lock_json()?;
 
-        // Each exported json file is only visible for the device owner.
-        // In future, it can be relaxed, such as allowing group to access,
-        // according to ublk use policy
-        Self::set_path_permission(json_path, 0o700)?;
+        // Write to one temp file and rename it, so the exported json file
+        // is always complete even though the daemon crashes in writing
+        let tmp_path = format!("{}.tmp", run_path);
+        {
+            let mut tmp_file = fs::File::create(&tmp_path)?;
+
+            // Each exported json file is only visible for the device owner.
+            // In future, it can be relaxed, such as allowing group to access,
+            // according to ublk use policy
+            Self::set_path_permission(Path::new(&tmp_path), 0o700)?;
 
-        run_file.write_all(self.json.to_string().as_bytes())?;
+            tmp_file.write_all(json_str.as_bytes())?;
+            tmp_file.sync_all()?;
+        }
+        fs::rename(&tmp_path, json_path)?;
+        if let Some(parent_dir) = json_path.parent() {
+            fs::File::open(parent_dir)?.sync_all()?;
+        }
+        self.notify(|l, id| l.on_json_flushed(id, &run_path));
         Ok(0)
     }
 
@@ -868,62 +2508,59 @@ impl UblkCtrlInner {
     /// pthread tid
     ///
     fn build_json(&mut self, dev: &UblkDev) -> Result<i32, UblkError> {
-        // keep everything not changed except for queue tid
+        // keep everything not changed except for queue tid & affinity
         if dev.dev_info.state == sys::UBLK_S_DEV_QUIESCED as u16 {
-            if let Some(queues) = self.json.get_mut("queues") {
-                for qid in 0..dev.dev_info.nr_hw_queues {
-                    let t = format!("{}", qid);
-                    if let Some(q) = queues.get_mut(t) {
-                        if let Some(tid) = q.get_mut("tid") {
-                            *tid = serde_json::json!(self.queue_tids[qid as usize]);
-                        }
+            let affinities: Vec<_> = (0..self.queue_tids.len() as u16)
+                .map(|qid| self.applied_queue_affinity(qid))
+                .collect();
+            if let Some(export) = self.json.as_mut() {
+                export.dev_info.ublksrv_pid = unsafe { libc::getpid() };
+                for (qid, q) in export.queues.iter_mut() {
+                    if let Some(tid) = self.queue_tids.get(*qid as usize) {
+                        q.tid = *tid;
+                        q.affinity = affinities[*qid as usize];
                     }
                 }
             }
             return Ok(0);
         }
 
-        let tgt_data = dev.get_target_json();
-        let mut map: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
-
+        let mut queues = std::collections::BTreeMap::new();
         for qid in 0..dev.dev_info.nr_hw_queues {
-            let mut affinity = self::UblkQueueAffinity::new();
-            self.get_queue_affinity(qid as u32, &mut affinity)?;
-
-            map.insert(
-                format!("{}", qid),
-                serde_json::json!({
-                    "qid": qid,
-                    "tid": self.queue_tids[qid as usize],
-                    "affinity": affinity.to_bits_vec(),
-                }),
+            let affinity = self.applied_queue_affinity(qid);
+
+            queues.insert(
+                qid,
+                UblkQueueExport {
+                    qid,
+                    tid: self.queue_tids[qid as usize],
+                    affinity,
+                },
             );
         }
 
-        let mut json = serde_json::json!({
-                    "dev_info": dev.dev_info,
-                    "target": dev.tgt,
-                    "target_flags": dev.flags.bits(),
+        self.json = Some(UblkDevExport {
+            version: UBLK_DEV_EXPORT_VERSION,
+            dev_info: dev.dev_info,
+            target: dev.tgt.clone(),
+            target_flags: dev.flags.bits(),
+            target_data: dev.get_target_json().cloned(),
+            queues,
+            affinity_policy: self.affinity_policy.clone(),
+            recovery_mode: UblkRecoveryMode::from_flags(self.dev_info.flags),
         });
-
-        if let Some(val) = tgt_data {
-            json["target_data"] = val.clone()
-        }
-
-        json["queues"] = serde_json::Value::Object(map);
-
-        self.json = json;
         Ok(0)
     }
 
     /// Reload json info for this device
     ///
+    /// The file may be written by older libublk, so parse it in lenient
+    /// mode.
     fn reload_json(&mut self) -> Result<i32, UblkError> {
-        let mut file = fs::File::open(self.run_path())?;
-        let mut json_str = String::new();
-
-        file.read_to_string(&mut json_str)?;
-        self.json = serde_json::from_str(&json_str).map_err(UblkError::JsonError)?;
+        self.json = Some(UblkDevExport::from_file(
+            self.run_path(),
+            UblkJsonParseMode::Lenient,
+        )?);
 
         Ok(0)
     }
@@ -935,15 +2572,11 @@ impl UblkCtrl {
     const CDEV_PATH: &'static str = "/dev/ublkc";
     const BDEV_PATH: &'static str = "/dev/ublkb";
 
-    const UBLK_DRV_F_ALL: u64 = (sys::UBLK_F_SUPPORT_ZERO_COPY
-        | sys::UBLK_F_URING_CMD_COMP_IN_TASK
-        | sys::UBLK_F_NEED_GET_DATA
-        | sys::UBLK_F_USER_RECOVERY
-        | sys::UBLK_F_USER_RECOVERY_REISSUE
-        | sys::UBLK_F_UNPRIVILEGED_DEV
-        | sys::UBLK_F_CMD_IOCTL_ENCODE
-        | sys::UBLK_F_USER_COPY
-        | sys::UBLK_F_ZONED) as u64;
+    /// features supported by ublk driver before GET_FEATURES is added
+    const UBLK_DRV_F_LEGACY: UblkFeatures = UblkFeatures::UBLK_F_URING_CMD_COMP_IN_TASK
+        .union(UblkFeatures::UBLK_F_NEED_GET_DATA)
+        .union(UblkFeatures::UBLK_F_USER_RECOVERY)
+        .union(UblkFeatures::UBLK_F_USER_RECOVERY_REISSUE);
 
     fn get_inner(&self) -> std::sync::RwLockReadGuard<UblkCtrlInner> {
         self.inner.read().unwrap()
@@ -966,6 +2599,10 @@ impl UblkCtrl {
         self.get_inner().dev_flags
     }
 
+    pub(crate) fn get_driver(&self) -> Arc<dyn UblkDriver> {
+        self.get_inner().driver.clone()
+    }
+
     /// New one ublk control device
     ///
     /// # Arguments:
@@ -993,13 +2630,33 @@ impl UblkCtrl {
         tgt_flags: u64,
         dev_flags: UblkFlags,
     ) -> Result<UblkCtrl, UblkError> {
-        if (flags & !Self::UBLK_DRV_F_ALL) != 0 {
-            return Err(UblkError::InvalidVal);
-        }
+        Self::new_with_config(
+            name,
+            id,
+            nr_queues,
+            depth,
+            io_buf_bytes,
+            flags,
+            tgt_flags,
+            dev_flags,
+            UblkCtrlConfig::default(),
+        )
+    }
 
-        if !Path::new(CTRL_PATH).exists() {
-            eprintln!("Please run `modprobe ublk_drv` first");
-            return Err(UblkError::OtherError(-libc::ENOENT));
+    #[allow(clippy::too_many_arguments)]
+    fn new_with_config(
+        name: Option<String>,
+        id: i32,
+        nr_queues: u32,
+        depth: u32,
+        io_buf_bytes: u32,
+        flags: u64,
+        tgt_flags: u64,
+        dev_flags: UblkFlags,
+        cfg: UblkCtrlConfig,
+    ) -> Result<UblkCtrl, UblkError> {
+        if (flags & !UblkFeatures::all().bits()) != 0 {
+            return Err(UblkError::InvalidVal);
         }
 
         if dev_flags.intersects(UblkFlags::UBLK_DEV_F_INTERNAL_0) {
@@ -1032,6 +2689,7 @@ impl UblkCtrl {
             flags,
             tgt_flags,
             dev_flags,
+            cfg,
         )?);
 
         Ok(UblkCtrl { inner })
@@ -1044,6 +2702,27 @@ impl UblkCtrl {
         Self::new(None, id, 0, 0, 0, 0, 0, UblkFlags::empty())
     }
 
+    /// Same with `new_simple()`, but the device's exported json file is
+    /// looked up in `run_dir`, which is for device created with
+    /// `UblkCtrlBuilder::run_dir()`
+    pub fn new_simple_with_run_dir(id: i32, run_dir: &str) -> Result<UblkCtrl, UblkError> {
+        assert!(id >= 0);
+        Self::new_with_config(
+            None,
+            id,
+            0,
+            0,
+            0,
+            0,
+            0,
+            UblkFlags::empty(),
+            UblkCtrlConfig {
+                run_dir: Some(run_dir.to_string()),
+                ..Default::default()
+            },
+        )
+    }
+
     /// Return current device info
     pub fn dev_info(&self) -> sys::ublksrv_ctrl_dev_info {
         self.get_inner().dev_info
@@ -1054,9 +2733,69 @@ impl UblkCtrl {
     /// Target code may need to query driver features runtime, so
     /// cache it inside device
     pub fn get_driver_features(&self) -> Option<u64> {
+        self.get_driver_features_flags().map(|f| f.bits())
+    }
+
+    /// Same with `get_driver_features()`, but return typed features
+    pub fn get_driver_features_flags(&self) -> Option<UblkFeatures> {
         self.get_inner().features
     }
 
+    /// Install event listener for observing this device's lifecycle,
+    /// and it is inherited by `UblkDev` created after this call
+    pub fn set_event_listener(&self, listener: Arc<dyn UblkEventListener>) {
+        self.get_inner_mut().listener = Some(listener);
+    }
+
+    /// Return the installed event listener
+    pub fn get_event_listener(&self) -> Option<Arc<dyn UblkEventListener>> {
+        self.get_inner().listener.clone()
+    }
+
+    /// Return recovery mode of this device
+    pub fn get_recovery_mode(&self) -> UblkRecoveryMode {
+        UblkRecoveryMode::from_flags(self.get_inner().dev_info.flags)
+    }
+
+    /// Return cpu affinity policy of queue pthread
+    pub fn get_affinity_policy(&self) -> UblkAffinityPolicy {
+        self.get_inner().affinity_policy.clone()
+    }
+
+    /// Return timeout of control command
+    pub fn cmd_timeout(&self) -> Option<std::time::Duration> {
+        self.get_inner().cmd_timeout
+    }
+
+    /// Issue control commands of this device with command timeout
+    /// `timeout`, which is applied on these commands only, for example:
+    ///
+    /// ```no_run
+    /// use libublk::ctrl::UblkCtrl;
+    /// use std::time::Duration;
+    ///
+    /// fn del(ctrl: &UblkCtrl) {
+    ///     let res = ctrl.cmd_opts(Some(Duration::from_secs(5))).del_dev();
+    ///     eprintln!("delete dev {:?}", res);
+    /// }
+    /// ```
+    pub fn cmd_opts(&self, timeout: Option<std::time::Duration>) -> UblkCtrlCmdOpts<'_> {
+        UblkCtrlCmdOpts {
+            ctrl: self,
+            timeout,
+        }
+    }
+
+    /// Return how long `UblkDev::new()` waits for char device to be ready
+    pub fn cdev_timeout(&self) -> std::time::Duration {
+        self.get_inner().cdev_timeout
+    }
+
+    /// Set timeout of waiting for char device, 3 seconds by default
+    pub fn set_cdev_timeout(&self, timeout: std::time::Duration) {
+        self.get_inner_mut().cdev_timeout = timeout;
+    }
+
     /// Return ublk char device path
     pub fn get_cdev_path(&self) -> String {
         self.get_inner().get_cdev_path()
@@ -1074,13 +2813,11 @@ impl UblkCtrl {
     /// * `qid`: queue id
     ///
     pub fn get_queue_tid(&self, qid: u32) -> Result<i32, UblkError> {
-        let ctrl = self.get_inner_mut();
-        let queues = &ctrl.json["queues"];
-        let queue = &queues[qid.to_string()];
-        let this_queue: Result<QueueAffinityJson, _> = serde_json::from_value(queue.clone());
+        let ctrl = self.get_inner();
+        let queue = ctrl.json.as_ref().and_then(|e| e.queues.get(&(qid as u16)));
 
-        if let Ok(p) = this_queue {
-            Ok(p.tid as i32)
+        if let Some(p) = queue {
+            Ok(p.tid)
         } else {
             Err(UblkError::OtherError(-libc::EEXIST))
         }
@@ -1089,26 +2826,18 @@ impl UblkCtrl {
     /// Get target flags from exported json file for this device
     ///
     pub fn get_target_flags_from_json(&self) -> Result<u32, UblkError> {
-        let ctrl = self.get_inner_mut();
-        let __tgt_flags = &ctrl.json["target_flags"];
-        let tgt_flags: Result<u32, _> = serde_json::from_value(__tgt_flags.clone());
-
-        if let Ok(flags) = tgt_flags {
-            Ok(flags)
-        } else {
-            Err(UblkError::OtherError(-libc::EINVAL))
+        match &self.get_inner().json {
+            Some(export) => Ok(export.target_flags),
+            None => Err(UblkError::OtherError(-libc::EINVAL)),
         }
     }
 
     /// Get target from exported json file for this device
     ///
     pub fn get_target_from_json(&self) -> Result<super::io::UblkTgt, UblkError> {
-        let tgt_val = &self.get_inner().json["target"];
-        let tgt: Result<super::io::UblkTgt, _> = serde_json::from_value(tgt_val.clone());
-        if let Ok(p) = tgt {
-            Ok(p)
-        } else {
-            Err(UblkError::OtherError(-libc::EINVAL))
+        match &self.get_inner().json {
+            Some(export) => Ok(export.target.clone()),
+            None => Err(UblkError::OtherError(-libc::EINVAL)),
         }
     }
 
@@ -1117,14 +2846,49 @@ impl UblkCtrl {
     /// Should only be called after device is started, otherwise target data
     /// won't be serialized out, and this API returns None
     pub fn get_target_data_from_json(&self) -> Option<serde_json::Value> {
-        let val = &self.get_inner().json["target_data"];
-        if !val.is_null() {
-            Some(val.clone())
-        } else {
-            None
+        self.get_inner()
+            .json
+            .as_ref()
+            .and_then(|e| e.target_data.clone())
+    }
+
+    /// Return pid of the daemon recorded in exported json file
+    pub fn get_owner_pid(&self) -> Option<i32> {
+        self.get_inner()
+            .json
+            .as_ref()
+            .map(|e| e.dev_info.ublksrv_pid)
+    }
+
+    /// Check if the daemon recorded in exported json file is still alive
+    ///
+    /// The json file's advisory lock is held by the daemon in its whole
+    /// lifetime, so the lock is checked first; if the lock file doesn't
+    /// exist, check the recorded pid. Stale json file can be detected
+    /// by this way, and it is reclaimed when another daemon exports
+    /// json for the device id.
+    pub fn is_owner_alive(&self) -> bool {
+        let ctrl = self.get_inner();
+
+        if let Some(locked) = ctrl.json_locked_by_others() {
+            return locked || ctrl.lock_file.is_some();
+        }
+        match ctrl.json.as_ref().map(|e| e.dev_info.ublksrv_pid) {
+            Some(pid) if pid > 0 => {
+                let res = unsafe { libc::kill(pid, 0) };
+
+                res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+            }
+            _ => false,
         }
     }
 
+    /// Return this device's export, which is built when the device is
+    /// started, or loaded from the exported json file
+    pub fn get_export(&self) -> Option<UblkDevExport> {
+        self.get_inner().json.clone()
+    }
+
     /// Get target type from exported json file for this device
     ///
     pub fn get_target_type_from_json(&self) -> Result<String, UblkError> {
@@ -1148,6 +2912,7 @@ impl UblkCtrl {
         let mut ctrl = self.get_inner_mut();
 
         ctrl.store_queue_tid(qid, tid);
+        ctrl.notify(|l, id| l.on_queue_configured(id, qid, tid));
 
         ctrl.nr_queues_configured += 1;
 
@@ -1158,57 +2923,126 @@ impl UblkCtrl {
         Ok(0)
     }
 
+    /// Describe this device
+    ///
+    /// The 1st part is retrieved from ublk driver, such as device info and
+    /// parameters, and the 2nd part is from device's exported json file,
+    /// such as queue tid & affinity and target data.
+    pub fn describe(&self) -> Result<UblkDevReport, UblkError> {
+        self.get_inner_mut().describe()
+    }
+
+    /// Dump this device info in the specified format
+    pub fn dump_format(&self, fmt: UblkDumpFormat) {
+        let res = self.describe().and_then(|r| r.render(fmt));
+
+        match res {
+            Ok(out) => println!("\n{}", out.trim_end()),
+            Err(e) => error!("Dump dev {} failed: {}\n", self.dev_info().dev_id, e),
+        }
+    }
+
     /// Dump this device info
     ///
     /// The 1st part is from UblkCtrl.dev_info, and the 2nd part is
     /// retrieved from device's exported json file
     pub fn dump(&self) {
-        let mut ctrl = self.get_inner_mut();
-        let mut p = sys::ublk_params {
-            ..Default::default()
-        };
+        self.dump_format(UblkDumpFormat::Text)
+    }
 
-        if ctrl.read_dev_info().is_err() {
-            error!("Dump dev {} failed\n", ctrl.dev_info.dev_id);
-            return;
+    /// Return the default directory for storing exported json files
+    ///
+    /// The first one of the following is used:
+    ///
+    /// 1) directory set by `UblkCtrl::set_run_dir()`
+    ///
+    /// 2) `LIBUBLK_RUN_DIR` environment variable
+    ///
+    /// 3) `/run/ublk` if it is writable, or it can be created
+    ///
+    /// 4) `$TMPDIR/ublk`
+    ///
+    /// Device created with `UblkCtrlBuilder::run_dir()` uses its own
+    /// directory, see `UblkCtrl::get_run_dir()`.
+    pub fn run_dir() -> String {
+        Self::resolve_run_dir(None)
+    }
+
+    /// Directories which may hold exported json files, in lookup order
+    ///
+    /// The directory from `UblkCtrl::set_run_dir()` or `LIBUBLK_RUN_DIR`
+    /// is the only one if it is set, otherwise both `/run/ublk` and the
+    /// legacy `$TMPDIR/ublk` are covered.
+    fn run_dirs() -> Vec<String> {
+        if let Some(dir) = RUN_DIR.read().unwrap().as_ref() {
+            return vec![dir.clone()];
         }
 
-        if ctrl.get_params(&mut p).is_err() {
-            error!("Dump dev {} failed\n", ctrl.dev_info.dev_id);
-            return;
+        if let Ok(dir) = std::env::var(UBLK_RUN_DIR_ENV) {
+            if !dir.is_empty() {
+                return vec![dir];
+            }
         }
 
-        let info = &ctrl.dev_info;
-        println!(
-            "\ndev id {}: nr_hw_queues {} queue_depth {} block size {} dev_capacity {}",
-            info.dev_id,
-            info.nr_hw_queues,
-            info.queue_depth,
-            1 << p.basic.logical_bs_shift,
-            p.basic.dev_sectors
-        );
-        println!(
-            "\tmax rq size {} daemon pid {} flags 0x{:x} state {}",
-            info.max_io_buf_bytes,
-            info.ublksrv_pid,
-            info.flags,
-            ctrl.dev_state_desc()
-        );
-        println!(
-            "\tublkc: {}:{} ublkb: {}:{} owner: {}:{}",
-            p.devt.char_major,
-            p.devt.char_minor,
-            p.devt.disk_major,
-            p.devt.disk_minor,
-            info.owner_uid,
-            info.owner_gid
-        );
+        vec![
+            UBLK_RUN_DIR_DEFAULT.to_string(),
+            format!("{}/ublk", std::env::temp_dir().display()),
+        ]
+    }
+
+    /// Resolve directory of exported json file
+    ///
+    /// If `id` is passed, the directory holding json file of device `id`
+    /// is preferred, so that json file in `/run/ublk` can be read without
+    /// write permission, and json file in the legacy `$TMPDIR/ublk` can
+    /// still be found. Otherwise the first usable one is returned.
+    fn resolve_run_dir(id: Option<u32>) -> String {
+        let dirs = Self::run_dirs();
+
+        if let Some(id) = id {
+            let found = dirs
+                .iter()
+                .find(|d| Path::new(&format!("{}/{:04}.json", d, id)).exists());
+            if let Some(dir) = found {
+                return dir.clone();
+            }
+        }
 
-        ctrl.dump_from_json();
+        match dirs.iter().find(|d| Self::dir_usable(Path::new(d))) {
+            Some(dir) => dir.clone(),
+            None => dirs[dirs.len() - 1].clone(),
+        }
     }
 
-    pub fn run_dir() -> String {
-        format!("{}/ublk", std::env::temp_dir().display())
+    /// Directory is usable if it is writable, or it can be created
+    fn dir_usable(dir: &Path) -> bool {
+        if dir.is_dir() {
+            Self::dir_writable(dir)
+        } else {
+            dir.parent().is_some_and(Self::dir_writable)
+        }
+    }
+
+    fn dir_writable(dir: &Path) -> bool {
+        use std::os::unix::ffi::OsStrExt;
+
+        match std::ffi::CString::new(dir.as_os_str().as_bytes()) {
+            Ok(p) => unsafe { libc::access(p.as_ptr(), libc::W_OK) == 0 },
+            _ => false,
+        }
+    }
+
+    /// Set process-wide default directory for storing exported json files
+    ///
+    /// It takes precedence over `LIBUBLK_RUN_DIR`, and None restores the
+    /// default policy.
+    pub fn set_run_dir(dir: Option<&str>) {
+        *RUN_DIR.write().unwrap() = dir.map(|d| d.to_string());
+    }
+
+    /// Return directory for storing this device's exported json file
+    pub fn get_run_dir(&self) -> String {
+        self.get_inner().run_dir.clone()
     }
 
     /// Returned path of this device's exported json file
@@ -1217,20 +3051,150 @@ impl UblkCtrl {
         self.get_inner().run_path()
     }
 
-    /// Retrieving supported UBLK FEATURES from ublk driver
+    /// Retrieving supported UBLK FEATURES from ublk driver
+    ///
+    /// Supported since linux kernel v6.5
+    pub fn get_features() -> Option<u64> {
+        Self::get_features_flags().map(|f| f.bits())
+    }
+
+    /// Same with `get_features()`, but return typed features
+    pub fn get_features_flags() -> Option<UblkFeatures> {
+        match Self::new(None, -1, 0, 0, 0, 0, 0, UblkFlags::empty()) {
+            Ok(ctrl) => ctrl.get_driver_features_flags(),
+            _ => None,
+        }
+    }
+
+    /// Retrieving device info from ublk driver
+    ///
+    pub fn read_dev_info(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout()).read_dev_info()
+    }
+
+    /// Retrieving device info from ublk driver in async/.await
+    ///
+    pub async fn read_dev_info_async(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout())
+            .read_dev_info_async()
+            .await
+    }
+
+    /// Return device state stored in current device info
+    ///
+    /// Call `read_dev_info()` first for retrieving the latest state
+    /// from ublk driver.
+    pub fn state(&self) -> UblkDevState {
+        self.get_inner().state()
+    }
+
+    /// Wait until device becomes the specified state
+    ///
+    /// # Arguments:
+    ///
+    /// * `state`: the expected device state
+    /// * `timeout`: max time for waiting
+    ///
+    /// Device info is retrieved from ublk driver in exponential backoff
+    /// interval, and `UblkError::Op` of "wait_for_state" with -ETIMEDOUT
+    /// is returned if the device doesn't become `state` in `timeout`.
+    pub fn wait_for_state(
+        &self,
+        state: UblkDevState,
+        timeout: std::time::Duration,
+    ) -> Result<i32, UblkError> {
+        let start = std::time::Instant::now();
+        let mut ms = STATE_POLL_MIN_MS;
+
+        loop {
+            self.read_dev_info()?;
+            if self.state() == state {
+                return Ok(0);
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(UblkOpError::new("wait_for_state", -libc::ETIMEDOUT)
+                    .dev(self.dev_info().dev_id)
+                    .into());
+            }
+            let interval = std::time::Duration::from_millis(ms).min(timeout - elapsed);
+            std::thread::sleep(interval);
+            ms = (ms * 2).min(STATE_POLL_MAX_MS);
+        }
+    }
+
+    /// Wait until device becomes the specified state in async/.await
+    ///
+    /// Same with `wait_for_state()`, but both GET_DEV_INFO command and
+    /// the polling interval are handled via the per-thread control
+    /// io_uring, so the current thread won't be blocked.
+    pub async fn wait_for_state_async(
+        &self,
+        state: UblkDevState,
+        timeout: std::time::Duration,
+    ) -> Result<i32, UblkError> {
+        let start = std::time::Instant::now();
+        let mut ms = STATE_POLL_MIN_MS;
+
+        loop {
+            self.read_dev_info_async().await?;
+            if self.state() == state {
+                return Ok(0);
+            }
+
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(UblkOpError::new("wait_for_state", -libc::ETIMEDOUT)
+                    .dev(self.dev_info().dev_id)
+                    .into());
+            }
+            let interval = std::time::Duration::from_millis(ms).min(timeout - elapsed);
+            ublk_ctrl_sleep_async(interval).await;
+            ms = (ms * 2).min(STATE_POLL_MAX_MS);
+        }
+    }
+
+    /// Wait until char device /dev/ublkcN is ready for use
     ///
-    /// Supported since linux kernel v6.5
-    pub fn get_features() -> Option<u64> {
-        match Self::new(None, -1, 0, 0, 0, 0, 0, UblkFlags::empty()) {
-            Ok(ctrl) => ctrl.get_driver_features(),
-            _ => None,
-        }
+    /// The char device is ready if its node is created, matches the device
+    /// number in sysfs, and is readable & writable after udev handling.
+    /// The node is never opened, so it is safe to call while the daemon
+    /// is opening or holding the char device.
+    ///
+    /// -ENOENT is returned if the device is gone, -ETIMEDOUT if the node
+    /// isn't created in `timeout`, and -EACCES or -EPERM if it still isn't
+    /// accessible.
+    pub fn wait_cdev_ready(&self, timeout: std::time::Duration) -> Result<i32, UblkError> {
+        let id = self.dev_info().dev_id;
+        self.get_driver()
+            .wait_cdev(id, &self.get_cdev_path(), timeout)?;
+        Ok(0)
     }
 
-    /// Retrieving device info from ublk driver
+    /// Wait until block device /dev/ublkbN is ready for use
     ///
-    pub fn read_dev_info(&self) -> Result<i32, UblkError> {
-        self.get_inner_mut().read_dev_info()
+    /// The block device is ready if its node is created, matches the
+    /// device number from GET_PARAMS, and can be opened for read & write,
+    /// so that mkfs or mount won't fail with -ENOENT or -EACCES because of
+    /// udev handling in progress. The device has to be started.
+    ///
+    /// -ENOENT is returned if the device isn't started, -ETIMEDOUT if the
+    /// node isn't created in `timeout`, and -EACCES or -EPERM if it still
+    /// can't be opened.
+    pub fn wait_bdev_ready(&self, timeout: std::time::Duration) -> Result<i32, UblkError> {
+        let mut p: sys::ublk_params = Default::default();
+        self.get_params(&mut p)?;
+
+        let devt = if (p.types & sys::UBLK_PARAM_TYPE_DEVT) != 0 {
+            Some((p.devt.disk_major, p.devt.disk_minor))
+        } else {
+            None
+        };
+        let id = self.dev_info().dev_id;
+        self.get_driver()
+            .open_bdev(id, &self.get_bdev_path(), devt, timeout)?;
+        Ok(0)
     }
 
     /// Retrieve this device's parameter from ublk driver by
@@ -1238,7 +3202,15 @@ impl UblkCtrl {
     ///
     /// Can't pass params by reference(&mut), why?
     pub fn get_params(&self, params: &mut sys::ublk_params) -> Result<i32, UblkError> {
-        self.get_inner_mut().get_params(params)
+        self.cmd_opts(self.cmd_timeout()).get_params(params)
+    }
+
+    /// Retrieve this device's parameter from ublk driver in async/.await
+    ///
+    pub async fn get_params_async(&self, params: &mut sys::ublk_params) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout())
+            .get_params_async(params)
+            .await
     }
 
     /// Send this device's parameter to ublk driver
@@ -1246,34 +3218,54 @@ impl UblkCtrl {
     /// Note: device parameter has to send to driver before starting
     /// this device
     pub fn set_params(&self, params: &sys::ublk_params) -> Result<i32, UblkError> {
-        self.get_inner_mut().set_params(params)
+        self.cmd_opts(self.cmd_timeout()).set_params(params)
+    }
+
+    /// Send this device's parameter to ublk driver in async/.await
+    ///
+    pub async fn set_params_async(&self, params: &sys::ublk_params) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout())
+            .set_params_async(params)
+            .await
     }
 
     /// Retrieving the specified queue's affinity from ublk driver
     ///
     pub fn get_queue_affinity(&self, q: u32, bm: &mut UblkQueueAffinity) -> Result<i32, UblkError> {
-        self.get_inner_mut().get_queue_affinity(q, bm)
+        self.cmd_opts(self.cmd_timeout()).get_queue_affinity(q, bm)
+    }
+
+    /// Retrieving the specified queue's affinity from ublk driver in
+    /// async/.await
+    ///
+    pub async fn get_queue_affinity_async(
+        &self,
+        q: u32,
+        bm: &mut UblkQueueAffinity,
+    ) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout())
+            .get_queue_affinity_async(q, bm)
+            .await
     }
 
     /// Start user recover for this device
     ///
+    /// Driver returns -EBUSY if the device isn't quiesced yet, so retry
+    /// for up to 30 seconds, and the last error is returned if it is
+    /// still busy.
     pub fn start_user_recover(&self) -> Result<i32, UblkError> {
-        let mut count = 0u32;
-        let unit = 100_u32;
+        self.cmd_opts(self.cmd_timeout()).start_user_recover()
+    }
 
-        loop {
-            let res = self.get_inner_mut().__start_user_recover();
-            if let Ok(r) = res {
-                if r == -libc::EBUSY {
-                    std::thread::sleep(std::time::Duration::from_millis(unit as u64));
-                    count += unit;
-                    if count < 30000 {
-                        continue;
-                    }
-                }
-            }
-            return res;
-        }
+    /// Start user recover for this device in async/.await
+    ///
+    /// Driver returns -EBUSY if the device isn't quiesced yet, so retry
+    /// for up to 30 seconds, and the interval sleep is done via the
+    /// per-thread control io_uring too.
+    pub async fn start_user_recover_async(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout())
+            .start_user_recover_async()
+            .await
     }
 
     /// Start ublk device
@@ -1286,16 +3278,7 @@ impl UblkCtrl {
     /// send START command
     ///
     pub fn start_dev(&self, dev: &UblkDev) -> Result<i32, UblkError> {
-        let mut ctrl = self.get_inner_mut();
-        ctrl.prep_start_dev(dev)?;
-
-        if ctrl.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
-            ctrl.start(unsafe { libc::getpid() as i32 })
-        } else if ctrl.for_recover_dev() {
-            ctrl.end_user_recover(unsafe { libc::getpid() as i32 })
-        } else {
-            Err(crate::UblkError::OtherError(-libc::EINVAL))
-        }
+        self.cmd_opts(self.cmd_timeout()).start_dev(dev)
     }
 
     /// Start ublk device in async/.await
@@ -1308,17 +3291,7 @@ impl UblkCtrl {
     /// send START command
     ///
     pub async fn start_dev_async(&self, dev: &UblkDev) -> Result<i32, UblkError> {
-        let mut ctrl = self.get_inner_mut();
-        ctrl.prep_start_dev(dev)?;
-
-        if ctrl.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
-            ctrl.start_async(unsafe { libc::getpid() as i32 }).await
-        } else if ctrl.for_recover_dev() {
-            ctrl.end_user_recover_async(unsafe { libc::getpid() as i32 })
-                .await
-        } else {
-            Err(crate::UblkError::OtherError(-libc::EINVAL))
-        }
+        self.cmd_opts(self.cmd_timeout()).start_dev_async(dev).await
     }
 
     /// Stop ublk device
@@ -1326,13 +3299,15 @@ impl UblkCtrl {
     /// Remove json export, and send stop command to control device
     ///
     pub fn stop_dev(&self) -> Result<i32, UblkError> {
-        let mut ctrl = self.get_inner_mut();
-        let rp = ctrl.run_path();
+        self.cmd_opts(self.cmd_timeout()).stop_dev()
+    }
 
-        if ctrl.for_add_dev() && Path::new(&rp).exists() {
-            fs::remove_file(rp)?;
-        }
-        ctrl.stop()
+    /// Stop ublk device in async/.await
+    ///
+    /// Remove json export, and send stop command to control device
+    ///
+    pub async fn stop_dev_async(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout()).stop_dev_async().await
     }
 
     /// Kill this device
@@ -1344,7 +3319,13 @@ impl UblkCtrl {
     /// can still be in-use after kill_dev() returns.
     ///
     pub fn kill_dev(&self) -> Result<i32, UblkError> {
-        self.get_inner_mut().stop()
+        self.cmd_opts(self.cmd_timeout()).kill_dev()
+    }
+
+    /// Kill this device in async/.await
+    ///
+    pub async fn kill_dev_async(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout()).kill_dev_async().await
     }
 
     /// Remove this device and its exported json file
@@ -1356,22 +3337,27 @@ impl UblkCtrl {
     /// to kill device via .kill_dev().
     ///
     pub fn del_dev(&self) -> Result<i32, UblkError> {
-        let mut ctrl = self.get_inner_mut();
+        self.cmd_opts(self.cmd_timeout()).del_dev()
+    }
 
-        ctrl.del()?;
-        if Path::new(&ctrl.run_path()).exists() {
-            fs::remove_file(ctrl.run_path())?;
-        }
-        Ok(0)
+    /// Remove this device and its exported json file in async/.await
+    ///
+    /// `UBLK_U_CMD_DEL_DEV` won't be completed until the device is
+    /// released, so don't wait in the context which is serving this
+    /// device's IO, or set `UBLK_DEV_F_DEL_DEV_ASYNC`.
+    pub async fn del_dev_async(&self) -> Result<i32, UblkError> {
+        self.cmd_opts(self.cmd_timeout()).del_dev_async().await
     }
 
-    fn create_queue_handlers<Q>(
+    fn create_queue_handlers<Q, R>(
         &self,
         dev: &Arc<UblkDev>,
         q_fn: Q,
-    ) -> Vec<std::thread::JoinHandle<()>>
+        outcome_tx: std::sync::mpsc::Sender<(u16, UblkQueueOutcome)>,
+    ) -> Result<Vec<std::thread::JoinHandle<()>>, UblkError>
     where
-        Q: FnOnce(u16, &UblkDev) + Send + Sync + Clone + 'static,
+        Q: FnOnce(u16, &UblkDev) -> R + Send + Sync + Clone + 'static,
+        R: UblkQueueResult,
     {
         use std::sync::mpsc;
 
@@ -1379,24 +3365,25 @@ impl UblkCtrl {
         let nr_queues = dev.dev_info.nr_hw_queues;
 
         let (tx, rx) = mpsc::channel();
+        let affinities = (0..nr_queues)
+            .map(|q| self.get_inner_mut().queue_affinity(q))
+            .collect::<Result<Vec<_>, _>>()?;
 
         for q in 0..nr_queues {
             let _dev = Arc::clone(dev);
             let _tx = tx.clone();
+            let _outcome_tx = outcome_tx.clone();
 
-            let mut affinity = UblkQueueAffinity::new();
-            self.get_queue_affinity(q as u32, &mut affinity).unwrap();
+            let affinity = affinities[q as usize];
             let mut _q_fn = q_fn.clone();
 
             q_threads.push(std::thread::spawn(move || {
                 //setup pthread affinity first, so that any allocation may
                 //be affine to cpu/memory
-                unsafe {
-                    libc::pthread_setaffinity_np(
-                        libc::pthread_self(),
-                        affinity.buf_len(),
-                        affinity.addr() as *const libc::cpu_set_t,
-                    );
+                if let Some(affinity) = affinity {
+                    if let Err(e) = affinity.set_thread_affinity(unsafe { libc::pthread_self() }) {
+                        error!("queue {} set affinity {} failed: {:?}", q, affinity, e);
+                    }
                 }
                 _tx.send((q, unsafe { libc::gettid() })).unwrap();
 
@@ -1405,7 +3392,23 @@ impl UblkCtrl {
                     libc::prctl(PR_SET_IO_FLUSHER, 0, 0, 0, 0);
                 };
 
-                _q_fn(q, &_dev);
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    _q_fn(q, &_dev).into_queue_result()
+                }));
+                let outcome = match res {
+                    Ok(Ok(_)) | Ok(Err(UblkError::QueueIsDown)) if _dev.is_quiescing() => {
+                        UblkQueueOutcome::Quiesced
+                    }
+                    Ok(Ok(_)) => UblkQueueOutcome::Exited,
+                    Ok(Err(e)) => UblkQueueOutcome::Failed(e),
+                    Err(p) => UblkQueueOutcome::Panicked(
+                        p.downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| p.downcast_ref::<String>().cloned())
+                            .unwrap_or_default(),
+                    ),
+                };
+                let _ = _outcome_tx.send((q, outcome));
             }));
         }
 
@@ -1417,90 +3420,711 @@ impl UblkCtrl {
                     dev.dev_info.dev_id, qid
                 );
             }
-        }
+        }
+
+        Ok(q_threads)
+    }
+
+    /// Run ublk daemon and kick off the ublk device, and `/dev/ublkbN` will be
+    /// created and exposed to userspace.
+    ///
+    /// # Arguments:
+    ///
+    /// * `tgt_fn`: target initialization handler
+    /// * `q_fn`: queue handler for setting up the queue and its handler,
+    ///     all IO logical is implemented in queue handler
+    /// * `device_fn`: called after device is started, run in current
+    ///     context
+    ///
+    /// This one is the preferred interface for creating ublk daemon, and
+    /// is friendly for user, such as, user can customize queue setup and
+    /// io handler, such as setup async/await for handling io command.
+    ///
+    /// If `UblkCtrlBuilder::bdev_ready_timeout()` is set, `device_fn` is
+    /// called after /dev/ublkbN is ready, see `UblkCtrl::wait_bdev_ready()`.
+    ///
+    /// Queue handler may return `()` or `Result<(), UblkError>`, and the
+    /// device is stopped automatically once any queue handler fails or
+    /// panics, so that other queues won't hang forever.
+    ///
+    /// If shutdown mode is set via `UblkCtrlBuilder::shutdown_mode()`,
+    /// SIGTERM and SIGINT are blocked in current thread and all queue
+    /// pthreads, and handled in one dedicated thread, see
+    /// `UblkShutdownMode`. Threads created before calling this function
+    /// have to block the two signals too.
+    ///
+    /// Error is returned if any queue pthread can't exit in shutdown
+    /// timeout, and `run_target_report()` can be used for retrieving
+    /// outcome of each queue.
+    pub fn run_target<T, Q, R, W>(&self, tgt_fn: T, q_fn: Q, device_fn: W) -> Result<i32, UblkError>
+    where
+        T: FnOnce(&mut UblkDev) -> Result<(), UblkError>,
+        Q: FnOnce(u16, &UblkDev) -> R + Send + Sync + Clone + 'static,
+        R: UblkQueueResult,
+        W: FnOnce(&UblkCtrl) + Send + Sync + 'static,
+    {
+        self.run_target_report(tgt_fn, q_fn, device_fn)?
+            .into_result()
+    }
+
+    /// Same with `run_target()`, but return outcome of each queue in
+    /// `UblkRunReport`
+    ///
+    /// If queues are quiesced, the device isn't stopped and won't be
+    /// deleted when this `UblkCtrl` is dropped, and
+    /// `UblkRunReport::is_quiesced()` returns true.
+    ///
+    /// Queue pthread which can't exit in shutdown timeout is reported as
+    /// `UblkQueueOutcome::Detached`, and the device won't be deleted when
+    /// this `UblkCtrl` is dropped, since DEL_DEV can't complete until the
+    /// pthread releases the char device.
+    pub fn run_target_report<T, Q, R, W>(
+        &self,
+        tgt_fn: T,
+        q_fn: Q,
+        device_fn: W,
+    ) -> Result<UblkRunReport, UblkError>
+    where
+        T: FnOnce(&mut UblkDev) -> Result<(), UblkError>,
+        Q: FnOnce(u16, &UblkDev) -> R + Send + Sync + Clone + 'static,
+        R: UblkQueueResult,
+        W: FnOnce(&UblkCtrl) + Send + Sync + 'static,
+    {
+        // signals have to be blocked before spawning queue pthreads
+        let mask = match self.get_inner().shutdown_mode {
+            UblkShutdownMode::Disabled => None,
+            _ => Some(UblkSignalMask::new()?),
+        };
+        let dev = &Arc::new(UblkDev::new(self.get_name(), tgt_fn, self)?);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handles = self.create_queue_handlers(dev, q_fn, tx)?;
+
+        self.start_dev(dev)?;
+
+        let bdev_timeout = self.get_inner().bdev_ready_timeout;
+        if let Some(Err(e)) = bdev_timeout.map(|t| self.wait_bdev_ready(t)) {
+            // queues exit after the device is stopped
+            let _ = self.stop_dev();
+            for qh in handles {
+                let _ = qh.join();
+            }
+            return Err(e);
+        }
+
+        let outcomes = self.supervise_queues(dev, mask.as_ref(), rx, device_fn);
+        let mut queues = Vec::new();
+        for (qh, outcome) in handles.into_iter().zip(outcomes) {
+            match outcome {
+                Some(o) => {
+                    qh.join().unwrap_or_else(|_| {
+                        error!("dev-{} join queue thread failed", dev.dev_info.dev_id)
+                    });
+                    queues.push(o);
+                }
+                None => queues.push(UblkQueueOutcome::Detached),
+            }
+        }
+        let detached = queues
+            .iter()
+            .any(|q| matches!(q, UblkQueueOutcome::Detached));
+
+        // the device is quiesced by ublk driver after the char device is
+        // closed, so keep it and its json file for recovery
+        if dev.is_quiescing() {
+            self.get_inner_mut()
+                .dev_flags
+                .remove(UblkFlags::UBLK_DEV_F_ADD_DEV);
+            return Ok(UblkRunReport {
+                dev_id: dev.dev_info.dev_id,
+                queues,
+                stop_error: None,
+            });
+        }
+
+        //device may be deleted from another context, so it is normal
+        //to see -ENODEV or -ENOENT failure here
+        let stop_error = match self.stop_dev() {
+            Err(e) if e.is_not_found() => None,
+            Err(e) => Some(e),
+            Ok(_) => None,
+        };
+
+        // detached queue pthreads still hold the char device, so DEL_DEV
+        // from drop() would hang
+        if detached {
+            self.get_inner_mut()
+                .dev_flags
+                .remove(UblkFlags::UBLK_DEV_F_ADD_DEV);
+        }
+
+        Ok(UblkRunReport {
+            dev_id: dev.dev_info.dev_id,
+            queues,
+            stop_error,
+        })
+    }
+
+    /// Run `device_fn` and wait until all queue pthreads exit, meantime
+    /// queue failure and SIGTERM/SIGINT are handled in one dedicated
+    /// thread: the device is stopped when any queue fails, or handled
+    /// according to shutdown mode when signal is received.
+    ///
+    /// Return outcome of each queue, and None is filled for queues which
+    /// can't exit in shutdown timeout after the device is stopped or
+    /// quiesced.
+    fn supervise_queues<W>(
+        &self,
+        dev: &UblkDev,
+        mask: Option<&UblkSignalMask>,
+        rx: std::sync::mpsc::Receiver<(u16, UblkQueueOutcome)>,
+        device_fn: W,
+    ) -> Vec<Option<UblkQueueOutcome>>
+    where
+        W: FnOnce(&UblkCtrl),
+    {
+        use std::time::{Duration, Instant};
+        const POLL: Duration = Duration::from_millis(100);
+
+        let (id, nr_queues, mode, timeout) = {
+            let ctrl = self.get_inner();
+            (
+                ctrl.dev_info.dev_id,
+                ctrl.dev_info.nr_hw_queues as usize,
+                ctrl.shutdown_mode,
+                ctrl.shutdown_timeout,
+            )
+        };
+
+        std::thread::scope(|s| {
+            let supervisor = s.spawn(move || {
+                let mut outcomes: Vec<Option<UblkQueueOutcome>> =
+                    (0..nr_queues).map(|_| None).collect();
+                let mut left = nr_queues;
+                let mut deadline = None;
+
+                while left > 0 {
+                    let mut stop = false;
+
+                    if let Some(sig) = mask.and_then(|m| m.wait(POLL)) {
+                        log::info!("dev {}: signal {} received, {:?}", id, sig, mode);
+                        if mode == UblkShutdownMode::Quiesce {
+                            // nothing to do if the device is being stopped
+                            if deadline.is_none() {
+                                if let Err(e) = dev.quiesce_queues(timeout) {
+                                    error!("dev {}: quiesce queues failed {:?}", id, e);
+                                }
+                                // queues drain target IOs in `timeout`, so give
+                                // them one more poll period for reporting
+                                deadline = Some(Instant::now() + timeout + POLL);
+                            }
+                        } else {
+                            stop = true;
+                        }
+                    }
+
+                    let res = match mask {
+                        Some(_) => rx.try_recv().ok(),
+                        None => rx.recv_timeout(POLL).ok(),
+                    };
+                    if let Some((qid, outcome)) = res {
+                        if !outcome.is_ok() {
+                            error!("dev {}: queue {} failed: {:?}", id, qid, outcome);
+                            stop = !dev.is_quiescing();
+                        }
+                        outcomes[qid as usize] = Some(outcome);
+                        left -= 1;
+                    }
+
+                    // queues drain in-flight IOs after UBLK_IO_RES_ABORT is
+                    // observed, and wait for them in bounded time
+                    if stop && deadline.is_none() {
+                        if let Err(e) = self.kill_dev() {
+                            error!("dev {}: stop device failed {:?}", id, e);
+                        }
+                        deadline = Some(Instant::now() + timeout);
+                    }
+                    if left > 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+                        error!("dev {}: queues aren't drained in {:?}", id, timeout);
+                        break;
+                    }
+                }
+                outcomes
+            });
+
+            device_fn(self);
+            supervisor.join().unwrap()
+        })
+    }
+
+    /// Recover one QUIESCED device and run ublk daemon for it
+    ///
+    /// # Arguments:
+    ///
+    /// * `id`: device id
+    /// * `tgt_fn`: target initialization handler, the previous target and
+    ///   target data retrieved from exported json file are passed in,
+    ///   so the target can be re-built in the same way
+    /// * `q_fn`: queue handler, same with `run_target()`
+    /// * `device_fn`: called after device is recovered, same with
+    ///   `run_target()`
+    ///
+    /// The device has to be in QUIESCED state, and the previous daemon
+    /// has to be gone, otherwise `UblkError::InvalidDevState` or
+    /// `UblkError::OwnerAlive` is returned. Then the device is created
+    /// with `UBLK_DEV_F_RECOVER_DEV` by using info of the previous device,
+    /// and its json file is locked before START_USER_RECOVERY is sent, and
+    /// finally END_USER_RECOVERY is sent from `run_target()`.
+    pub fn recover_target<T, Q, R, W>(
+        id: i32,
+        tgt_fn: T,
+        q_fn: Q,
+        device_fn: W,
+    ) -> Result<i32, UblkError>
+    where
+        T: FnOnce(&mut UblkDev, &UblkTgt, Option<&serde_json::Value>) -> Result<(), UblkError>,
+        Q: FnOnce(u16, &UblkDev) -> R + Send + Sync + Clone + 'static,
+        R: UblkQueueResult,
+        W: FnOnce(&UblkCtrl) + Send + Sync + 'static,
+    {
+        Self::recover_target_report(id, tgt_fn, q_fn, device_fn)?.into_result()
+    }
+
+    /// Same with `recover_target()`, but return outcome of each queue in
+    /// `UblkRunReport`, see `run_target_report()`
+    pub fn recover_target_report<T, Q, R, W>(
+        id: i32,
+        tgt_fn: T,
+        q_fn: Q,
+        device_fn: W,
+    ) -> Result<UblkRunReport, UblkError>
+    where
+        T: FnOnce(&mut UblkDev, &UblkTgt, Option<&serde_json::Value>) -> Result<(), UblkError>,
+        Q: FnOnce(u16, &UblkDev) -> R + Send + Sync + Clone + 'static,
+        R: UblkQueueResult,
+        W: FnOnce(&UblkCtrl) + Send + Sync + 'static,
+    {
+        let old = Self::new_simple(id)?;
+        let state = old.state();
+        if state != UblkDevState::Quiesced {
+            return Err(UblkError::InvalidDevState {
+                dev_id: id as u32,
+                state,
+            });
+        }
+        let export = old
+            .get_export()
+            .ok_or(UblkOpError::new("load_json", -libc::ENOENT).dev(id as u32))?;
+        old.get_inner().check_recovery()?;
+        if old.is_owner_alive() {
+            return Err(UblkError::OwnerAlive {
+                dev_id: id as u32,
+                pid: export.dev_info.ublksrv_pid,
+            });
+        }
+
+        let info = old.dev_info();
+        let ctrl = UblkCtrl::new_with_config(
+            Some(export.target.tgt_type.clone()),
+            id,
+            info.nr_hw_queues.into(),
+            info.queue_depth.into(),
+            info.max_io_buf_bytes,
+            info.flags,
+            info.ublksrv_flags,
+            UblkFlags::UBLK_DEV_F_RECOVER_DEV,
+            UblkCtrlConfig {
+                affinity_policy: export.affinity_policy.clone(),
+                cmd_timeout: old.cmd_timeout(),
+                cdev_timeout: old.cdev_timeout(),
+                bdev_ready_timeout: old.get_inner().bdev_ready_timeout,
+                run_dir: Some(old.get_inner().run_dir.clone()),
+                ..Default::default()
+            },
+        )?;
+        drop(old);
+        ctrl.start_user_recover()?;
+
+        let tgt_init = |dev: &mut UblkDev| tgt_fn(dev, &export.target, export.target_data.as_ref());
+        ctrl.run_target_report(tgt_init, q_fn, device_fn)
+    }
+
+    /// Return summary of this device from current device info and json
+    pub fn summary(&self) -> UblkDevSummary {
+        let ctrl = self.get_inner();
+
+        UblkDevSummary::new(&ctrl.dev_info, ctrl.json.clone())
+    }
+
+    /// Return IDs of all ublk devices discovered from sysfs or devfs
+    ///
+    /// `/sys/class/ublk-char/` is scanned first, and `/dev/ublkc*` is
+    /// the fallback if sysfs isn't available.
+    pub fn dev_ids() -> Vec<u32> {
+        fn scan(dir: &str) -> Option<Vec<u32>> {
+            let ids = std::fs::read_dir(dir)
+                .ok()?
+                .flatten()
+                .filter_map(|e| {
+                    e.file_name()
+                        .to_str()?
+                        .strip_prefix("ublkc")?
+                        .parse::<u32>()
+                        .ok()
+                })
+                .collect();
+            Some(ids)
+        }
+
+        let mut ids = scan("/sys/class/ublk-char")
+            .or_else(|| scan("/dev"))
+            .unwrap_or_default();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Iterator over summary of each ublk device
+    ///
+    /// Devices are discovered from ublk driver instead of exported json
+    /// files, so devices created by other utilities or whose json file is
+    /// gone are covered too. Each device is queried via GET_DEV_INFO2, and
+    /// device which can't be queried, such as it is just deleted, is skipped.
+    pub fn list_devs() -> impl Iterator<Item = UblkDevSummary> {
+        Self::dev_ids()
+            .into_iter()
+            .filter_map(|id| match Self::new_simple(id as i32) {
+                Ok(ctrl) => Some(ctrl.summary()),
+                Err(e) => {
+                    trace!("list_devs: query dev {} failed {:?}", id, e);
+                    None
+                }
+            })
+    }
+
+    /// Iterator over each ublk device ID
+    ///
+    /// Only devices with exported json file are covered, and both
+    /// `/run/ublk` and the legacy `$TMPDIR/ublk` are scanned unless run
+    /// directory is set by `UblkCtrl::set_run_dir()` or `LIBUBLK_RUN_DIR`.
+    /// See `UblkCtrl::list_devs()` for listing all ublk devices.
+    pub fn for_each_dev_id<T>(ops: T)
+    where
+        T: Fn(u32) + Clone + 'static,
+    {
+        let mut ids = std::collections::BTreeSet::new();
+
+        for dir in Self::run_dirs() {
+            if let Ok(entries) = std::fs::read_dir(dir) {
+                for entry in entries.flatten() {
+                    let f = entry.path();
+                    if f.is_file() && f.extension().is_some_and(|e| e == "json") {
+                        if let Some(file_stem) = f.file_stem() {
+                            if let Some(stem) = file_stem.to_str() {
+                                if let Ok(num) = stem.parse::<u32>() {
+                                    ids.insert(num);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for id in ids {
+            ops(id);
+        }
+    }
+}
+
+/// Control commands of one device issued with per-call options, which
+/// is created by `UblkCtrl::cmd_opts()`
+///
+/// Each command is same with the one of `UblkCtrl`, except that the
+/// command timeout is applied on commands issued from this instance only.
+pub struct UblkCtrlCmdOpts<'a> {
+    ctrl: &'a UblkCtrl,
+    timeout: Option<std::time::Duration>,
+}
+
+impl UblkCtrlCmdOpts<'_> {
+    /// Run sync control commands in `f` with the timeout
+    fn with_inner<R>(&self, f: impl FnOnce(&mut UblkCtrlInner) -> R) -> R {
+        self.ctrl.get_inner_mut().with_cmd_timeout(self.timeout, f)
+    }
+
+    /// Context for issuing async control commands with the timeout
+    fn cmd_ctx(&self) -> UblkCtrlCmdCtx {
+        self.ctrl.get_inner().cmd_ctx(self.timeout)
+    }
+
+    /// See `UblkCtrl::read_dev_info()`
+    pub fn read_dev_info(&self) -> Result<i32, UblkError> {
+        self.with_inner(|c| c.read_dev_info())
+    }
+
+    /// See `UblkCtrl::read_dev_info_async()`
+    pub async fn read_dev_info_async(&self) -> Result<i32, UblkError> {
+        let (ctx, mut info) = {
+            let ctrl = self.ctrl.get_inner();
+            (ctrl.cmd_ctx(self.timeout), ctrl.dev_info)
+        };
+
+        let res = ctx.read_dev_info_async(&mut info).await?;
+        self.ctrl.get_inner_mut().dev_info = info;
+        Ok(res)
+    }
+
+    /// See `UblkCtrl::get_params()`
+    pub fn get_params(&self, params: &mut sys::ublk_params) -> Result<i32, UblkError> {
+        self.with_inner(|c| c.get_params(params))
+    }
+
+    /// See `UblkCtrl::get_params_async()`
+    pub async fn get_params_async(&self, params: &mut sys::ublk_params) -> Result<i32, UblkError> {
+        self.cmd_ctx().get_params_async(params).await
+    }
+
+    /// See `UblkCtrl::set_params()`
+    pub fn set_params(&self, params: &sys::ublk_params) -> Result<i32, UblkError> {
+        self.with_inner(|c| c.set_params(params))
+    }
+
+    /// See `UblkCtrl::set_params_async()`
+    pub async fn set_params_async(&self, params: &sys::ublk_params) -> Result<i32, UblkError> {
+        let res = self.cmd_ctx().set_params_async(params).await?;
+        self.ctrl.get_inner().notify(|l, id| l.on_params_set(id));
+        Ok(res)
+    }
+
+    /// See `UblkCtrl::get_queue_affinity()`
+    pub fn get_queue_affinity(&self, q: u32, bm: &mut UblkQueueAffinity) -> Result<i32, UblkError> {
+        self.with_inner(|c| c.get_queue_affinity(q, bm))
+    }
+
+    /// See `UblkCtrl::get_queue_affinity_async()`
+    pub async fn get_queue_affinity_async(
+        &self,
+        q: u32,
+        bm: &mut UblkQueueAffinity,
+    ) -> Result<i32, UblkError> {
+        self.cmd_ctx().get_queue_affinity_async(q, bm).await
+    }
+
+    /// See `UblkCtrl::start_user_recover()`
+    pub fn start_user_recover(&self) -> Result<i32, UblkError> {
+        let mut count = 0u32;
+        let unit = 100_u32;
+
+        loop {
+            let res = self.with_inner(|c| c.__start_user_recover());
+            if matches!(&res, Err(e) if e.is_busy()) && count < 30000 {
+                std::thread::sleep(std::time::Duration::from_millis(unit as u64));
+                count += unit;
+                continue;
+            }
+            return res;
+        }
+    }
+
+    /// See `UblkCtrl::start_user_recover_async()`
+    pub async fn start_user_recover_async(&self) -> Result<i32, UblkError> {
+        let mut count = 0u32;
+        let unit = 100_u32;
+
+        loop {
+            let res = self.cmd_ctx().__start_user_recover_async().await;
+            if matches!(&res, Err(e) if e.is_busy()) && count < 30000 {
+                ublk_ctrl_sleep_async(std::time::Duration::from_millis(unit as u64)).await;
+                count += unit;
+                continue;
+            }
+            return res;
+        }
+    }
+
+    /// See `UblkCtrl::start_dev()`
+    pub fn start_dev(&self, dev: &UblkDev) -> Result<i32, UblkError> {
+        self.with_inner(|ctrl| {
+            ctrl.prep_start_dev(dev)?;
+
+            if ctrl.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+                ctrl.start(unsafe { libc::getpid() as i32 })
+            } else if ctrl.for_recover_dev() {
+                ctrl.end_user_recover(unsafe { libc::getpid() as i32 })
+            } else {
+                Err(crate::UblkError::OtherError(-libc::EINVAL))
+            }
+        })
+    }
+
+    /// See `UblkCtrl::start_dev_async()`
+    pub async fn start_dev_async(&self, dev: &UblkDev) -> Result<i32, UblkError> {
+        let (ctx, recover) = {
+            let mut ctrl = self.ctrl.get_inner_mut();
+            ctrl.with_cmd_timeout(self.timeout, |c| c.prep_start_dev(dev))?;
+
+            if ctrl.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+                (ctrl.cmd_ctx(self.timeout), false)
+            } else if ctrl.for_recover_dev() {
+                (ctrl.cmd_ctx(self.timeout), true)
+            } else {
+                return Err(crate::UblkError::OtherError(-libc::EINVAL));
+            }
+        };
+        let pid = unsafe { libc::getpid() as i32 };
 
-        q_threads
+        let res = if recover {
+            ctx.end_user_recover_async(pid).await?
+        } else {
+            ctx.start_async(pid).await?
+        };
+        self.ctrl.get_inner().notify(|l, id| l.on_started(id));
+        Ok(res)
     }
 
-    /// Run ublk daemon and kick off the ublk device, and `/dev/ublkbN` will be
-    /// created and exposed to userspace.
-    ///
-    /// # Arguments:
-    ///
-    /// * `tgt_fn`: target initialization handler
-    /// * `q_fn`: queue handler for setting up the queue and its handler,
-    ///     all IO logical is implemented in queue handler
-    /// * `device_fn`: called after device is started, run in current
-    ///     context
-    ///
-    /// This one is the preferred interface for creating ublk daemon, and
-    /// is friendly for user, such as, user can customize queue setup and
-    /// io handler, such as setup async/await for handling io command.
-    pub fn run_target<T, Q, W>(&self, tgt_fn: T, q_fn: Q, device_fn: W) -> Result<i32, UblkError>
-    where
-        T: FnOnce(&mut UblkDev) -> Result<(), UblkError>,
-        Q: FnOnce(u16, &UblkDev) + Send + Sync + Clone + 'static,
-        W: FnOnce(&UblkCtrl) + Send + Sync + 'static,
-    {
-        let dev = &Arc::new(UblkDev::new(self.get_name(), tgt_fn, self)?);
-        let handles = self.create_queue_handlers(dev, q_fn);
+    /// See `UblkCtrl::stop_dev()`
+    pub fn stop_dev(&self) -> Result<i32, UblkError> {
+        self.with_inner(|ctrl| {
+            if ctrl.for_add_dev() {
+                ctrl.remove_json()?;
+            }
+            ctrl.stop()
+        })
+    }
 
-        self.start_dev(dev)?;
+    /// See `UblkCtrl::stop_dev_async()`
+    pub async fn stop_dev_async(&self) -> Result<i32, UblkError> {
+        let ctx = {
+            let mut ctrl = self.ctrl.get_inner_mut();
 
-        device_fn(self);
+            if ctrl.for_add_dev() {
+                ctrl.remove_json()?;
+            }
+            ctrl.cmd_ctx(self.timeout)
+        };
 
-        for qh in handles {
-            qh.join().unwrap_or_else(|_| {
-                eprintln!("dev-{} join queue thread failed", dev.dev_info.dev_id)
-            });
-        }
+        let res = ctx.stop_async().await?;
+        self.ctrl.get_inner().notify(|l, id| l.on_stopped(id));
+        Ok(res)
+    }
 
-        //device may be deleted from another context, so it is normal
-        //to see -ENOENT failure here
-        let _ = self.stop_dev();
+    /// See `UblkCtrl::kill_dev()`
+    pub fn kill_dev(&self) -> Result<i32, UblkError> {
+        self.with_inner(|c| c.stop())
+    }
 
-        Ok(0)
+    /// See `UblkCtrl::kill_dev_async()`
+    pub async fn kill_dev_async(&self) -> Result<i32, UblkError> {
+        let res = self.cmd_ctx().stop_async().await?;
+        self.ctrl.get_inner().notify(|l, id| l.on_stopped(id));
+        Ok(res)
     }
 
-    /// Iterator over each ublk device ID
-    pub fn for_each_dev_id<T>(ops: T)
-    where
-        T: Fn(u32) + Clone + 'static,
-    {
-        if let Ok(entries) = std::fs::read_dir(UblkCtrl::run_dir()) {
-            for entry in entries.flatten() {
-                let f = entry.path();
-                if f.is_file() {
-                    if let Some(file_stem) = f.file_stem() {
-                        if let Some(stem) = file_stem.to_str() {
-                            if let Ok(num) = stem.parse::<u32>() {
-                                ops(num);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    /// See `UblkCtrl::del_dev()`
+    pub fn del_dev(&self) -> Result<i32, UblkError> {
+        self.with_inner(|ctrl| {
+            ctrl.del()?;
+            ctrl.remove_json()
+        })
     }
-}
 
+    /// See `UblkCtrl::del_dev_async()`
+    pub async fn del_dev_async(&self) -> Result<i32, UblkError> {
+        let (ctx, data) = {
+            let ctrl = self.ctrl.get_inner();
+            (ctrl.cmd_ctx(self.timeout), ctrl.del_cmd_data())
+        };
+
+        ctx.ublk_ctrl_cmd_async(&data).await?;
+        self.ctrl.get_inner_mut().remove_json()
+    }
+}
 #[cfg(test)]
 mod tests {
     use crate::ctrl::UblkCtrlBuilder;
+    use crate::ctrl::{
+        UblkAffinityPolicy, UblkCtrl, UblkDevExport, UblkDevReport, UblkDevState,
+        UblkJsonParseMode, UblkLimitError, UblkQueueAffinity, UblkQueueExport, UblkQueueOutcome,
+        UblkQueueResult, UblkRecoveryMode, UblkRunReport, UblkShutdownMode, UblkSignalMask,
+        UBLK_DEV_EXPORT_VERSION,
+    };
     use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
-    use crate::{ctrl::UblkCtrl, UblkFlags, UblkIORes};
+    use crate::{UblkError, UblkEventListener, UblkFeatures, UblkFlags, UblkIORes};
     use std::cell::Cell;
     use std::path::Path;
     use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_ublk_ctrl_builder_limits() {
+        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let add = || UblkCtrlBuilder::default().dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV);
+
+        // rejected before sending ADD_DEV
+        for (b, err) in [
+            (add().nr_queues(0), UblkLimitError::NrQueues(0)),
+            (add().nr_queues(4097), UblkLimitError::NrQueues(4097)),
+            (add().depth(0), UblkLimitError::QueueDepth(0)),
+            (add().depth(8192), UblkLimitError::QueueDepth(8192)),
+            (add().io_buf_bytes(0), UblkLimitError::IoBufBytes(0)),
+            (
+                add().io_buf_bytes(64 << 20),
+                UblkLimitError::IoBufBytes(64 << 20),
+            ),
+            (
+                add().io_buf_bytes(page_sz + 512),
+                UblkLimitError::IoBufUnaligned(page_sz + 512),
+            ),
+        ] {
+            assert_eq!(b.dev_limits(), Err(err));
+            assert!(matches!(b.build(), Err(UblkError::InvalidLimits(e)) if e == err));
+        }
+
+        let nr_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as u16;
+        let b = add().auto_limits(true).nr_queues(0);
+        assert_eq!(b.dev_limits(), Ok((nr_cpus, 64, 524288)));
+        let b = add().auto_limits(true).io_buf_bytes(page_sz + 512);
+        assert_eq!(b.dev_limits().unwrap().2, page_sz);
+        let b = add().auto_limits(true).io_buf_bytes(u32::MAX);
+        assert_eq!(b.dev_limits().unwrap().2, 32 << 20);
+        let b = add().auto_limits(true).io_buf_bytes(0);
+        assert_eq!(b.dev_limits().unwrap().2, page_sz);
+    }
 
     #[test]
     fn test_ublk_get_features() {
-        match UblkCtrl::get_features() {
-            Some(f) => eprintln!("features is {:04x}", f),
+        match UblkCtrl::get_features_flags() {
+            Some(f) => eprintln!("features is {:04x}: {}", f.bits(), f),
             None => eprintln!("not support GET_FEATURES, require linux v6.5"),
         }
     }
 
+    #[test]
+    fn test_ublk_required_features() {
+        // the top bit isn't defined, so it can't be supported by any driver
+        let unknown = UblkFeatures::from_bits_retain(1_u64 << 63);
+        let res = UblkCtrlBuilder::default()
+            .required_features(unknown | UblkFeatures::UBLK_F_CMD_IOCTL_ENCODE)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build();
+        match res {
+            Err(UblkError::MissingFeatures(f)) => assert!(f.contains(unknown)),
+            _ => panic!("unknown feature shouldn't be supported"),
+        }
+
+        let ctrl = UblkCtrlBuilder::default()
+            .preferred_features(UblkFeatures::UBLK_F_CMD_IOCTL_ENCODE)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let enabled = UblkFeatures::from_bits_retain(ctrl.dev_info().flags);
+        let supported = ctrl
+            .get_driver_features_flags()
+            .unwrap_or(UblkFeatures::empty());
+        assert!(
+            enabled.contains(UblkFeatures::UBLK_F_CMD_IOCTL_ENCODE)
+                == supported.contains(UblkFeatures::UBLK_F_CMD_IOCTL_ENCODE)
+        );
+    }
+
     fn __test_add_ctrl_dev(del_async: bool) {
         let ctrl = UblkCtrl::new(
             None,
@@ -1532,6 +4156,157 @@ mod tests {
         __test_add_ctrl_dev(true);
     }
 
+    /// drive control task by reaping CQEs from the per-thread control uring
+    fn run_ctrl_task<T>(exe: &smol::LocalExecutor, task: &smol::Task<T>) {
+        while exe.try_tick() {}
+        while !task.is_finished() {
+            let cqes: Vec<io_uring::cqueue::Entry> = super::CTRL_URING.with(|refcell| {
+                let mut r = refcell.borrow_mut();
+
+                r.submit_and_wait(1).unwrap();
+                r.completion().collect()
+            });
+            for cqe in cqes {
+                crate::uring_async::ublk_wake_task(cqe.user_data(), &cqe);
+            }
+            while exe.try_tick() {}
+        }
+    }
+
+    #[test]
+    fn test_ublk_ctrl_cmd_async() {
+        let ctrl = Rc::new(
+            UblkCtrlBuilder::default()
+                .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+                .build()
+                .unwrap(),
+        );
+        let exe = smol::LocalExecutor::new();
+        let _ctrl = ctrl.clone();
+
+        let task = exe.spawn(async move {
+            let ctrl = _ctrl;
+            let mut p = crate::io::UblkParamsBuilder::default()
+                .dev_size(1_u64 << 20)
+                .build(&ctrl.dev_info())
+                .unwrap();
+
+            ctrl.set_params_async(&p).await.unwrap();
+            let dev_sectors = p.basic.dev_sectors;
+            p.basic.dev_sectors = 0;
+            ctrl.get_params_async(&mut p).await.unwrap();
+            assert!(p.basic.dev_sectors == dev_sectors);
+
+            let mut affinity = UblkQueueAffinity::new();
+            ctrl.get_queue_affinity_async(0, &mut affinity)
+                .await
+                .unwrap();
+            assert!(!affinity.to_bits_vec().is_empty());
+
+            ctrl.wait_for_state_async(UblkDevState::Dead, std::time::Duration::from_secs(1))
+                .await
+                .unwrap();
+            ctrl.del_dev_async().await.unwrap();
+        });
+        run_ctrl_task(&exe, &task);
+        assert!(!Path::new(&ctrl.get_cdev_path()).exists());
+    }
+
+    #[test]
+    fn test_ublk_ctrl_cmd_buf() {
+        use crate::ctrl::{UblkCtrlCmdCtx, UblkCtrlCmdData, CTRL_UBLKC_PATH_MAX};
+        use crate::ctrl::{CTRL_CMD_BUF_READ, CTRL_CMD_HAS_BUF};
+
+        let ctx = UblkCtrlCmdCtx {
+            fd: -1,
+            dev_id: 3,
+            unprivileged: true,
+            cdev_path: "/dev/ublkc3".to_string(),
+            cmd_timeout: None,
+            driver: Arc::new(crate::driver::UblkKernelDriver),
+        };
+        let mut val = 0x1234_u64;
+        let mut data = UblkCtrlCmdData {
+            cmd_op: crate::sys::UBLK_U_CMD_GET_PARAMS,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_READ,
+            addr: std::ptr::addr_of_mut!(val) as u64,
+            len: 8,
+            ..Default::default()
+        };
+
+        // payload is copied into the owned buffer after the dev path
+        let (addr, buf) = data.prep_cmd_buf(&ctx);
+        let mut buf = buf.unwrap();
+        assert_eq!(addr, std::ptr::addr_of!(val) as u64);
+        assert_eq!(data.addr, buf.as_ptr() as u64);
+        assert_eq!(data.len as usize, CTRL_UBLKC_PATH_MAX + 8);
+        assert!(buf.starts_with(b"/dev/ublkc3\0"));
+        assert_eq!(buf[CTRL_UBLKC_PATH_MAX..], 0x1234_u64.to_ne_bytes());
+
+        // caller's buffer isn't touched until the result is copied back
+        buf[CTRL_UBLKC_PATH_MAX..].copy_from_slice(&0x5678_u64.to_ne_bytes());
+        assert_eq!(val, 0x1234);
+        data.unprep_cmd_buf(addr);
+        assert_eq!(val, 0x5678);
+    }
+
+    #[test]
+    fn test_ublk_ctrl_cmd_timeout() {
+        let ctrl = UblkCtrlBuilder::default()
+            .cmd_timeout(std::time::Duration::from_millis(500))
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let id = ctrl.dev_info().dev_id;
+
+        ctrl.cmd_opts(None).read_dev_info().unwrap();
+        assert!(ctrl.cmd_timeout() == Some(std::time::Duration::from_millis(500)));
+
+        // DEL_DEV can't be completed until the char device is closed
+        let cdev = std::fs::File::open(ctrl.get_cdev_path()).unwrap();
+
+        // per-call timeout is applied instead of the configured one
+        let start = std::time::Instant::now();
+        let res = ctrl
+            .cmd_opts(Some(std::time::Duration::from_millis(100)))
+            .del_dev();
+        assert!(matches!(res, Err(UblkError::CtrlTimeout { .. })));
+        assert!(start.elapsed() < std::time::Duration::from_millis(500));
+        match ctrl.del_dev() {
+            Err(UblkError::CtrlTimeout { cmd, dev_id }) => {
+                assert!(cmd == crate::sys::UBLK_U_CMD_DEL_DEV);
+                assert!(dev_id == id);
+            }
+            _ => panic!("DEL_DEV should be timed out"),
+        }
+        drop(cdev);
+    }
+
+    #[test]
+    fn test_ublk_wait_for_state() {
+        let ctrl = UblkCtrlBuilder::default()
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let timeout = std::time::Duration::from_millis(100);
+
+        // device isn't started yet
+        ctrl.wait_for_state(UblkDevState::Dead, timeout).unwrap();
+        assert!(ctrl.state() == UblkDevState::Dead);
+
+        match ctrl.wait_for_state(UblkDevState::Live, timeout) {
+            Err(e) => {
+                assert!(e.is_timeout());
+                assert_eq!(e.op().map(|op| op.op), Some("wait_for_state"));
+                assert_eq!(
+                    e.op().and_then(|op| op.dev_id),
+                    Some(ctrl.dev_info().dev_id)
+                );
+            }
+            _ => panic!("device shouldn't be live"),
+        }
+    }
+
     /// minimized unprivileged ublk test, may just run in root privilege
     #[test]
     fn test_add_un_privileted_ublk() {
@@ -1552,6 +4327,397 @@ mod tests {
         assert!(Path::new(&dev_path).exists() == true);
     }
 
+    /// only quiesced device can be recovered
+    #[test]
+    fn test_ublk_recover_target() {
+        let ctrl = UblkCtrlBuilder::default()
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let id = ctrl.dev_info().dev_id;
+
+        // device isn't quiesced, so it can't be recovered
+        let res = UblkCtrl::recover_target(
+            id as i32,
+            |_, _, _| panic!("target shouldn't be initialized"),
+            |_, _| {},
+            |_| {},
+        );
+        match res {
+            Err(UblkError::InvalidDevState { dev_id, state }) => {
+                assert!(dev_id == id);
+                assert!(state == UblkDevState::Dead);
+            }
+            _ => panic!("dead device shouldn't be recovered"),
+        }
+    }
+
+    #[test]
+    fn test_ublk_dev_export_parse() {
+        let mut export = UblkDevExport {
+            version: UBLK_DEV_EXPORT_VERSION,
+            target_flags: 0x2,
+            target_data: Some(serde_json::json!({"null": "test_data"})),
+            ..Default::default()
+        };
+        export.queues.insert(
+            0,
+            UblkQueueExport {
+                qid: 0,
+                tid: 100,
+                affinity: UblkQueueAffinity::from_cpus(&[0, 1]).unwrap(),
+            },
+        );
+        let val = serde_json::to_value(&export).unwrap();
+        assert!(val["queues"]["0"]["tid"] == 100);
+
+        let e = UblkDevExport::from_value(val.clone(), UblkJsonParseMode::Strict).unwrap();
+        assert!(e.queues == export.queues);
+        assert!(e.target_data == export.target_data);
+
+        // unknown field is only allowed in lenient mode
+        let mut v = val.clone();
+        v["queues"]["0"]["cpu"] = serde_json::json!(1);
+        assert!(UblkDevExport::from_value(v.clone(), UblkJsonParseMode::Strict).is_err());
+        assert!(UblkDevExport::from_value(v, UblkJsonParseMode::Lenient).is_ok());
+
+        let mut v = val.clone();
+        v["version"] = serde_json::json!(UBLK_DEV_EXPORT_VERSION + 1);
+        assert!(UblkDevExport::from_value(v, UblkJsonParseMode::Strict).is_err());
+
+        // missing field is only filled with default in lenient mode
+        let mut v = val.clone();
+        v.as_object_mut().unwrap().remove("affinity_policy");
+        v["queues"]["0"].as_object_mut().unwrap().remove("affinity");
+        assert!(UblkDevExport::from_value(v.clone(), UblkJsonParseMode::Strict).is_err());
+        let e = UblkDevExport::from_value(v, UblkJsonParseMode::Lenient).unwrap();
+        assert!(e.affinity_policy == UblkAffinityPolicy::default());
+        assert!(e.queues[&0].affinity.is_empty());
+
+        // version 0 layout: no version & target flags
+        let mut v = val.clone();
+        let obj = v.as_object_mut().unwrap();
+        obj.remove("version");
+        obj.remove("target_flags");
+        assert!(UblkDevExport::from_value(v.clone(), UblkJsonParseMode::Strict).is_err());
+        let e = UblkDevExport::from_value(v, UblkJsonParseMode::Lenient).unwrap();
+        assert!(e.version == UBLK_DEV_EXPORT_VERSION);
+        assert!(e.target_flags == 0);
+        assert!(e.queues[&0].tid == 100);
+    }
+
+    #[test]
+    fn test_ublk_recovery_mode() {
+        for mode in [
+            UblkRecoveryMode::Disabled,
+            UblkRecoveryMode::Requeue,
+            UblkRecoveryMode::Reissue,
+            UblkRecoveryMode::FailIo,
+        ] {
+            assert!(UblkRecoveryMode::from_flags(mode.features().bits()) == mode);
+        }
+
+        // raw recovery flags conflict with recovery mode
+        let res = UblkCtrlBuilder::default()
+            .ctrl_flags(UblkFeatures::UBLK_F_USER_RECOVERY.bits())
+            .recovery_mode(UblkRecoveryMode::Reissue)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build();
+        assert!(matches!(res, Err(UblkError::InvalidVal)));
+
+        // version 2 layout: no recovery mode, which is built from flags
+        let mut export = UblkDevExport::default();
+        export.dev_info.flags = UblkRecoveryMode::Reissue.features().bits();
+        let mut v = serde_json::to_value(&export).unwrap();
+        let obj = v.as_object_mut().unwrap();
+        obj.remove("recovery_mode");
+        obj.insert("version".to_string(), serde_json::json!(2));
+        let e = UblkDevExport::from_value(v, UblkJsonParseMode::Lenient).unwrap();
+        assert!(e.recovery_mode == UblkRecoveryMode::Reissue);
+    }
+
+    #[test]
+    fn test_ublk_shutdown_mode() {
+        let timeout = std::time::Duration::from_millis(100);
+        let mask = UblkSignalMask::new().unwrap();
+
+        assert!(mask.wait(timeout).is_none());
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(mask.wait(timeout) == Some(libc::SIGTERM));
+        drop(mask);
+
+        // quiesce requires recovery support
+        let res = UblkCtrlBuilder::default()
+            .shutdown_mode(UblkShutdownMode::Quiesce)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build();
+        assert!(matches!(res, Err(UblkError::InvalidVal)));
+
+        let ctrl = UblkCtrlBuilder::default()
+            .shutdown_mode(UblkShutdownMode::Quiesce)
+            .recovery_mode(UblkRecoveryMode::Requeue)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        assert!(ctrl.get_recovery_mode() == UblkRecoveryMode::Requeue);
+    }
+
+    #[test]
+    fn test_ublk_run_report() {
+        assert!(().into_queue_result().is_ok());
+        assert!(Err::<(), _>(UblkError::QueueIsDown)
+            .into_queue_result()
+            .is_err());
+
+        let report = UblkRunReport {
+            dev_id: 0,
+            queues: vec![
+                UblkQueueOutcome::Exited,
+                UblkQueueOutcome::Panicked("queue panic".to_string()),
+                UblkQueueOutcome::Failed(UblkError::QueueIsDown),
+            ],
+            stop_error: None,
+        };
+        assert!(!report.is_clean());
+
+        let failed: Vec<u16> = report.failed_queues().map(|(qid, _)| qid).collect();
+        assert!(failed == vec![1, 2]);
+        assert!(!report.is_quiesced());
+
+        let report = UblkRunReport {
+            dev_id: 0,
+            queues: vec![UblkQueueOutcome::Quiesced, UblkQueueOutcome::Exited],
+            stop_error: None,
+        };
+        assert!(report.is_clean() && report.is_quiesced());
+        assert!(report.failed_queues().next().is_none());
+        assert!(matches!(report.into_result(), Ok(0)));
+
+        let report = UblkRunReport {
+            dev_id: 1,
+            queues: vec![UblkQueueOutcome::Exited, UblkQueueOutcome::Detached],
+            stop_error: None,
+        };
+        let err = report.into_result().unwrap_err();
+        let op = err.op().unwrap();
+        assert_eq!(
+            (op.op, op.dev_id, op.q_id),
+            ("join_queue", Some(1), Some(1))
+        );
+    }
+
+    #[test]
+    fn test_ublk_run_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().to_str().unwrap();
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .run_dir(run_dir)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+
+        assert!(ctrl.get_run_dir() == run_dir);
+        assert!(ctrl.run_path() == format!("{}/{:04}.json", run_dir, ctrl.dev_info().dev_id));
+
+        // json file in per-device run dir is found via the same dir
+        {
+            let mut inner = ctrl.get_inner_mut();
+            inner.json = Some(UblkDevExport {
+                version: UBLK_DEV_EXPORT_VERSION,
+                dev_info: inner.dev_info,
+                ..Default::default()
+            });
+            inner.flush_json().unwrap();
+        }
+        let id = ctrl.dev_info().dev_id as i32;
+        let ctrl2 = UblkCtrl::new_simple_with_run_dir(id, run_dir).unwrap();
+        assert!(ctrl2.get_run_dir() == run_dir);
+        assert!(ctrl2.get_export().is_some());
+    }
+
+    #[test]
+    fn test_ublk_json_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().to_str().unwrap();
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .run_dir(run_dir)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let id = ctrl.dev_info().dev_id as i32;
+        let lock_path = ctrl.get_inner().lock_path();
+
+        // json isn't locked until it is flushed
+        assert!(ctrl.get_inner().lock_file.is_none());
+        assert!(!Path::new(&lock_path).exists());
+
+        {
+            let mut inner = ctrl.get_inner_mut();
+            inner.json = Some(UblkDevExport {
+                version: UBLK_DEV_EXPORT_VERSION,
+                dev_info: inner.dev_info,
+                ..Default::default()
+            });
+            inner.flush_json().unwrap();
+        }
+        assert!(Path::new(&ctrl.run_path()).exists());
+        assert!(!Path::new(&format!("{}.tmp", ctrl.run_path())).exists());
+
+        // the exported json is locked by `ctrl`
+        let ctrl2 = UblkCtrlBuilder::default()
+            .name("null")
+            .id(id)
+            .run_dir(run_dir)
+            .build()
+            .unwrap();
+        assert!(ctrl2.get_owner_pid() == Some(unsafe { libc::getpid() }));
+        assert!(ctrl2.is_owner_alive());
+
+        // simulate the owner is gone, and the json becomes stale
+        ctrl.get_inner_mut().lock_file = None;
+        assert!(!ctrl2.is_owner_alive());
+        ctrl.get_inner_mut().lock_json().unwrap();
+
+        // lock file is removed with the lock held
+        ctrl.del_dev().unwrap();
+        assert!(!Path::new(&ctrl.run_path()).exists());
+        assert!(!Path::new(&lock_path).exists());
+    }
+
+    #[test]
+    fn test_ublk_list_devs() {
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(2_u16)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let id = ctrl.dev_info().dev_id;
+
+        assert!(UblkCtrl::dev_ids().contains(&id));
+        let dev = UblkCtrl::list_devs().find(|d| d.dev_id == id).unwrap();
+        assert!(dev.state == UblkDevState::Dead);
+        assert!(dev.nr_queues == 2);
+    }
+
+    #[test]
+    fn test_ublk_dev_report() {
+        let report = UblkDevReport {
+            dev_info: crate::sys::ublksrv_ctrl_dev_info {
+                dev_id: 3,
+                nr_hw_queues: 1,
+                queue_depth: 64,
+                ..Default::default()
+            },
+            params: Default::default(),
+            state: UblkDevState::Live,
+            devt: crate::sys::ublk_param_devt {
+                char_major: 240,
+                char_minor: 3,
+                disk_major: 259,
+                disk_minor: 1,
+            },
+            owner_uid: 0,
+            owner_gid: 0,
+            queues: vec![UblkQueueExport {
+                qid: 0,
+                tid: 1000,
+                affinity: UblkQueueAffinity::from_cpus(&[2, 3]).unwrap(),
+            }],
+            target: Some(crate::io::UblkTgt {
+                tgt_type: "null".to_string(),
+                ..Default::default()
+            }),
+            target_data: None,
+        };
+
+        let text = report.to_text();
+        assert!(text.starts_with("dev id 3: nr_hw_queues 1 queue_depth 64"));
+        assert!(text.contains("state LIVE"));
+        assert!(text.contains("ublkc: 240:3 ublkb: 259:1"));
+        assert!(text.contains("queue 0 tid: 1000 affinity(2 3)"));
+
+        let val: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert!(val["state"] == "Live");
+        assert!(val["devt"]["disk_major"] == 259);
+        assert!(val["queues"][0]["affinity"][1] == 3);
+        assert!(val["target"]["tgt_type"] == "null");
+    }
+
+    #[test]
+    fn test_ublk_queue_affinity() {
+        let a: UblkQueueAffinity = "0-3,8".parse().unwrap();
+        let b = UblkQueueAffinity::from_cpus(&[2, 3, 4, 8]).unwrap();
+
+        assert!(a.cpus() == vec![0, 1, 2, 3, 8]);
+        assert!(a.to_string() == "0-3,8");
+        assert!(a.union(&b).to_string() == "0-4,8");
+        assert!(a.intersection(&b).to_string() == "2-3,8");
+        assert!(a.difference(&b).to_string() == "0-1");
+        assert!(UblkQueueAffinity::from_cpu_set(&a.to_cpu_set()) == a);
+        assert!("1-x".parse::<UblkQueueAffinity>().is_err());
+        assert!("0-4294967295".parse::<UblkQueueAffinity>().is_err());
+        assert!(UblkQueueAffinity::from_cpus(&[1024]).is_err());
+
+        let json = serde_json::to_string(&a).unwrap();
+        assert!(json == "[0,1,2,3,8]");
+        assert!(serde_json::from_str::<UblkQueueAffinity>(&json).unwrap() == a);
+        assert!(serde_json::from_str::<UblkQueueAffinity>("\"0-3,8\"").unwrap() == a);
+
+        let cur = UblkQueueAffinity::from_current_thread().unwrap();
+        assert!(!cur.is_empty());
+        assert!(UblkQueueAffinity::from_thread(unsafe { libc::gettid() }).unwrap() == cur);
+        if let Some(node) = cur.first_cpu().and_then(UblkQueueAffinity::cpu_numa_node) {
+            let node_cpus = UblkQueueAffinity::from_numa_node(node).unwrap();
+            assert!(node_cpus.contains(cur.first_cpu().unwrap()));
+            assert!(node_cpus.numa_nodes() == vec![node]);
+        }
+    }
+
+    #[test]
+    fn test_ublk_affinity_policy() {
+        assert!(UblkQueueAffinity::parse_cpu_list("0-3,8\n") == Some(vec![0, 1, 2, 3, 8]));
+        assert!(UblkQueueAffinity::parse_cpu_list("3-1").is_none());
+        assert!(UblkQueueAffinity::parse_cpu_list("0-4294967295").is_none());
+        assert!(UblkQueueAffinity::parse_cpu_list("1020-1024").is_none());
+        assert!(
+            UblkQueueAffinity::parse_cpu_list("1020-1023")
+                .unwrap()
+                .len()
+                == 4
+        );
+        assert!(UblkQueueAffinity::from_cpus(&[1, 5]).unwrap().to_bits_vec() == vec![1, 5]);
+
+        let res = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(2)
+            .affinity_policy(UblkAffinityPolicy::Explicit(vec![vec![0]]))
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build();
+        assert!(matches!(res, Err(UblkError::InvalidVal)));
+
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(2)
+            .affinity_policy(UblkAffinityPolicy::Explicit(vec![vec![0], vec![0, 1]]))
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap();
+        let mut inner = ctrl.get_inner_mut();
+        assert!(inner.queue_affinity(1).unwrap().unwrap().to_bits_vec() == vec![0, 1]);
+
+        // the applied cpus are exported instead of the configured ones
+        inner.store_queue_tid(1, unsafe { libc::gettid() });
+        let cur = UblkQueueAffinity::from_current_thread().unwrap();
+        assert!(inner.applied_queue_affinity(1) == cur);
+
+        inner.affinity_policy = UblkAffinityPolicy::None;
+        assert!(inner.queue_affinity(0).unwrap().is_none());
+        assert!(inner.applied_queue_affinity(1).is_empty());
+    }
+
     #[test]
     fn test_ublk_target_json() {
         let ctrl = UblkCtrlBuilder::default()
@@ -1576,6 +4742,16 @@ mod tests {
     }
 
     fn __test_ublk_session<T>(w_fn: T) -> String
+    where
+        T: Fn(&UblkCtrl) + Send + Sync + Clone + 'static,
+    {
+        __test_ublk_session_with_listener(w_fn, None)
+    }
+
+    fn __test_ublk_session_with_listener<T>(
+        w_fn: T,
+        listener: Option<Arc<dyn UblkEventListener>>,
+    ) -> String
     where
         T: Fn(&UblkCtrl) + Send + Sync + Clone + 'static,
     {
@@ -1586,6 +4762,9 @@ mod tests {
             .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
             .build()
             .unwrap();
+        if let Some(l) = listener {
+            ctrl.set_event_listener(l);
+        }
 
         let tgt_init = |dev: &mut UblkDev| {
             dev.set_default_params(250_u64 << 30);
@@ -1612,10 +4791,12 @@ mod tests {
                 .wait_and_handle_io(io_handler);
         };
 
-        ctrl.run_target(tgt_init, q_fn, move |ctrl: &UblkCtrl| {
-            w_fn(ctrl);
-        })
-        .unwrap();
+        let report = ctrl
+            .run_target_report(tgt_init, q_fn, move |ctrl: &UblkCtrl| {
+                w_fn(ctrl);
+            })
+            .unwrap();
+        assert!(report.is_clean());
 
         // could be too strict because of udev
         let bdev = ctrl.get_bdev_path();
@@ -1638,6 +4819,53 @@ mod tests {
         // could be too strict because of udev
         assert!(Path::new(&cdev).exists() == false);
     }
+
+    /// Covers device lifecycle events observed by UblkEventListener
+    #[test]
+    fn test_ublk_event_listener() {
+        #[derive(Default)]
+        struct EventLog(Mutex<Vec<String>>);
+
+        impl UblkEventListener for EventLog {
+            fn on_cdev_opened(&self, dev_id: u32) {
+                self.0.lock().unwrap().push(format!("cdev {}", dev_id));
+            }
+            fn on_params_set(&self, _dev_id: u32) {
+                self.0.lock().unwrap().push("params".to_string());
+            }
+            fn on_queue_configured(&self, _dev_id: u32, qid: u16, _tid: i32) {
+                self.0.lock().unwrap().push(format!("queue {}", qid));
+            }
+            fn on_json_flushed(&self, _dev_id: u32, _path: &str) {
+                self.0.lock().unwrap().push("json".to_string());
+            }
+            fn on_started(&self, _dev_id: u32) {
+                self.0.lock().unwrap().push("started".to_string());
+            }
+            fn on_stopped(&self, _dev_id: u32) {
+                self.0.lock().unwrap().push("stopped".to_string());
+            }
+        }
+
+        let log = Arc::new(EventLog::default());
+        __test_ublk_session_with_listener(
+            |ctrl: &UblkCtrl| {
+                ctrl.kill_dev().unwrap();
+            },
+            Some(log.clone()),
+        );
+
+        let events = log.0.lock().unwrap();
+        assert!(events[0].starts_with("cdev"));
+        assert!(events.contains(&"queue 0".to_string()));
+        assert!(events.contains(&"queue 1".to_string()));
+
+        // params are sent and json is flushed before starting device
+        let pos = |e: &str| events.iter().position(|x| x == e).unwrap();
+        assert!(pos("params") < pos("started"));
+        assert!(pos("json") < pos("started"));
+        assert!(pos("started") < pos("stopped"));
+    }
     /// test for_each_dev_id
     #[test]
     fn test_ublk_for_each_dev_id() {
@@ -0,0 +1,388 @@
+use crate::{sys, UblkError, UblkOpError};
+use io_uring::{opcode, squeue, types};
+use std::cell::RefCell;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CTRL_PATH: &str = "/dev/ublk-control";
+const CDEV_SYSFS_DIR: &str = "/sys/class/ublk-char";
+const BDEV_SYSFS_DIR: &str = "/sys/class/block";
+
+#[repr(C)]
+union CtrlCmd {
+    ctrl_cmd: sys::ublksrv_ctrl_cmd,
+    buf: [u8; 80],
+}
+
+/// Interactions with ublk driver
+///
+/// Control commands and io commands are issued via io_uring, and the
+/// driver may either return one SQE for the caller to queue, or post the
+/// command's CQE to the caller's uring by itself, so that sync & async
+/// command handling is same for all drivers.
+pub(crate) trait UblkDriver: Send + Sync {
+    /// Open control device
+    fn open_ctrl(&self) -> Result<fs::File, UblkError>;
+
+    /// Open char device `path` of device `dev_id`, and wait at most
+    /// `timeout` until the node is created and becomes accessible
+    fn open_cdev(&self, dev_id: u32, path: &str, timeout: Duration) -> Result<fs::File, UblkError>;
+
+    /// Wait at most `timeout` until char device `path` of device `dev_id`
+    /// is created and accessible, and the node is never opened since
+    /// the char device can only be opened by one process
+    fn wait_cdev(&self, dev_id: u32, path: &str, timeout: Duration) -> Result<(), UblkError>;
+
+    /// Open block device `path` of started device `dev_id`, and wait at
+    /// most `timeout` until the node is created, accessible and matches
+    /// `devt`(major, minor) if it is provided
+    fn open_bdev(
+        &self,
+        dev_id: u32,
+        path: &str,
+        devt: Option<(u32, u32)>,
+        timeout: Duration,
+    ) -> Result<fs::File, UblkError>;
+
+    /// Issue one control command
+    ///
+    /// None is returned if the command's CQE will be posted to uring
+    /// `ring_fd` with `user_data` by the driver.
+    fn ctrl_cmd(
+        &self,
+        ring_fd: RawFd,
+        fd: RawFd,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry128>;
+
+    /// Issue one io command of device `dev_id`, see `ctrl_cmd()`
+    fn io_cmd(
+        &self,
+        ring_fd: RawFd,
+        dev_id: u32,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry>;
+
+    /// Queue `q_id` of device `dev_id` is released, and its uring is
+    /// closed after this call, so its in-flight io commands are cancelled
+    fn release_queue(&self, _dev_id: u32, _q_id: u16) {}
+
+    /// Char device of device `dev_id` is closed by the daemon
+    fn release_cdev(&self, _dev_id: u32) {}
+}
+
+/// Linux kernel ublk driver
+pub(crate) struct UblkKernelDriver;
+
+impl UblkKernelDriver {
+    /// Open device node `path` which is registered in sysfs `class_dir`,
+    /// and the node will never show up if it isn't in sysfs
+    fn open_node(
+        op: &'static str,
+        dev_id: u32,
+        class_dir: &str,
+        path: &str,
+        rdev: Option<libc::dev_t>,
+        timeout: Duration,
+    ) -> Result<fs::File, UblkError> {
+        let sysfs = Path::new(class_dir);
+        let name = Path::new(path).file_name().unwrap_or_default();
+        if sysfs.exists() && !sysfs.join(name).exists() {
+            return Err(UblkOpError::new(op, -libc::ENOENT).dev(dev_id).into());
+        }
+
+        wait_open(Path::new(path), rdev, timeout)
+            .map_err(|e| UblkOpError::from_io(op, &e).dev(dev_id).into())
+    }
+}
+
+impl UblkDriver for UblkKernelDriver {
+    fn open_ctrl(&self) -> Result<fs::File, UblkError> {
+        if !Path::new(CTRL_PATH).exists() {
+            eprintln!("Please run `modprobe ublk_drv` first");
+            return Err(UblkOpError::new("open_ctrl", -libc::ENOENT).into());
+        }
+
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(CTRL_PATH)
+            .map_err(|e| UblkOpError::from_io("open_ctrl", &e).into())
+    }
+
+    fn open_cdev(&self, dev_id: u32, path: &str, timeout: Duration) -> Result<fs::File, UblkError> {
+        // the char device is registered by driver before ADD_DEV returns
+        Self::open_node("open_cdev", dev_id, CDEV_SYSFS_DIR, path, None, timeout)
+    }
+
+    fn wait_cdev(&self, dev_id: u32, path: &str, timeout: Duration) -> Result<(), UblkError> {
+        let err = |e: std::io::Error| UblkOpError::from_io("wait_cdev", &e).dev(dev_id).into();
+        let name = Path::new(path).file_name().unwrap_or_default();
+        let sysfs = Path::new(CDEV_SYSFS_DIR);
+
+        // "major:minor" of the registered char device
+        let rdev = if sysfs.exists() {
+            let devt = fs::read_to_string(sysfs.join(name).join("dev")).map_err(err)?;
+            devt.trim()
+                .split_once(':')
+                .and_then(|(ma, mi)| Some(libc::makedev(ma.parse().ok()?, mi.parse().ok()?)))
+        } else {
+            None
+        };
+        wait_access(Path::new(path), rdev, timeout).map_err(err)
+    }
+
+    fn open_bdev(
+        &self,
+        dev_id: u32,
+        path: &str,
+        devt: Option<(u32, u32)>,
+        timeout: Duration,
+    ) -> Result<fs::File, UblkError> {
+        // the disk is added by driver before START_DEV returns
+        let rdev = devt.map(|(major, minor)| libc::makedev(major, minor));
+        Self::open_node("open_bdev", dev_id, BDEV_SYSFS_DIR, path, rdev, timeout)
+    }
+
+    fn ctrl_cmd(
+        &self,
+        _ring_fd: RawFd,
+        fd: RawFd,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry128> {
+        let c_cmd = CtrlCmd { ctrl_cmd: *cmd };
+
+        Some(
+            opcode::UringCmd80::new(types::Fd(fd), cmd_op)
+                .cmd(unsafe { c_cmd.buf })
+                .build()
+                .user_data(user_data),
+        )
+    }
+
+    fn io_cmd(
+        &self,
+        _ring_fd: RawFd,
+        _dev_id: u32,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry> {
+        Some(
+            opcode::UringCmd16::new(types::Fixed(0), cmd_op)
+                .cmd(unsafe { core::mem::transmute::<sys::ublksrv_io_cmd, [u8; 16]>(*cmd) })
+                .build()
+                .user_data(user_data),
+        )
+    }
+}
+
+/// Open `path` for read & write, and wait until the node is created and
+/// its permission is applied by udev, or `timeout` expires
+///
+/// If `rdev` is provided, the node is ready only if its device number
+/// matches, so that stale node of one removed device is skipped.
+///
+/// Parent directory of `path` is watched via inotify, and polling is
+/// fallback if inotify isn't available. -ETIMEDOUT is returned if the node
+/// isn't created in time, and -EACCES or -EPERM if it still can't be
+/// opened because of permission.
+pub(crate) fn wait_open(
+    path: &Path,
+    rdev: Option<libc::dev_t>,
+    timeout: Duration,
+) -> std::io::Result<fs::File> {
+    wait_node(path, timeout, || {
+        let f = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        match rdev {
+            Some(r) if f.metadata()?.rdev() != r => {
+                Err(std::io::Error::from_raw_os_error(libc::ENOENT))
+            }
+            _ => Ok(f),
+        }
+    })
+}
+
+/// Same with `wait_open()`, but the node is checked by stat() and
+/// access(R_OK | W_OK) only, and is never opened
+fn wait_access(path: &Path, rdev: Option<libc::dev_t>, timeout: Duration) -> std::io::Result<()> {
+    let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    wait_node(path, timeout, || {
+        let meta = fs::metadata(path)?;
+        if matches!(rdev, Some(r) if meta.rdev() != r) {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        if unsafe { libc::access(cpath.as_ptr(), libc::R_OK | libc::W_OK) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    })
+}
+
+/// Retry `check` until it succeeds, or fails with errno other than
+/// -ENOENT, -EACCES or -EPERM, or `timeout` expires
+fn wait_node<T>(
+    path: &Path,
+    timeout: Duration,
+    mut check: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let deadline = Instant::now() + timeout;
+    let watch = path.parent().and_then(DirWatch::new);
+
+    loop {
+        let err = match check() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let errno = err.raw_os_error().unwrap_or(libc::EIO);
+
+        if errno != libc::ENOENT && errno != libc::EACCES && errno != libc::EPERM {
+            return Err(err);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(match errno {
+                libc::ENOENT => std::io::Error::from_raw_os_error(libc::ETIMEDOUT),
+                _ => err,
+            });
+        }
+
+        let left = deadline - now;
+        match &watch {
+            Some(w) => w.wait(left),
+            None => std::thread::sleep(left.min(Duration::from_millis(10))),
+        }
+    }
+}
+
+/// inotify watch of node creation & attribute change in one directory
+struct DirWatch {
+    file: fs::File,
+}
+
+impl DirWatch {
+    fn new(dir: &Path) -> Option<Self> {
+        let dir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return None;
+        }
+
+        let file = unsafe { fs::File::from_raw_fd(fd) };
+        let mask = libc::IN_CREATE | libc::IN_ATTRIB | libc::IN_MOVED_TO;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return None;
+        }
+        Some(DirWatch { file })
+    }
+
+    /// Wait for any event in `timeout`, and drain all queued events
+    fn wait(&self, timeout: Duration) {
+        let mut pfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+
+        if unsafe { libc::poll(&mut pfd, 1, ms) } > 0 {
+            let mut buf = [0_u8; 4096];
+            while unsafe { libc::read(pfd.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) }
+                > 0
+            {}
+        }
+    }
+}
+
+std::thread_local! {
+    static DRIVER: RefCell<Option<Arc<dyn UblkDriver>>> = const { RefCell::new(None) };
+}
+
+/// Return driver for control devices created in this thread
+///
+/// `UblkDev` and `UblkQueue` use the driver of their control device.
+pub(crate) fn current_driver() -> Arc<dyn UblkDriver> {
+    DRIVER.with(|d| match &*d.borrow() {
+        Some(drv) => drv.clone(),
+        None => Arc::new(UblkKernelDriver),
+    })
+}
+
+/// Replace driver of this thread, and return the old one
+#[cfg(any(test, feature = "mock"))]
+pub(crate) fn set_driver(drv: Option<Arc<dyn UblkDriver>>) -> Option<Arc<dyn UblkDriver>> {
+    DRIVER.with(|d| d.replace(drv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wait_access, wait_open};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_wait_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ublkc0");
+
+        let start = Instant::now();
+        let err = wait_open(&path, None, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // node is created when we are waiting
+        let p = path.clone();
+        let h = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            std::fs::File::create(p).unwrap();
+        });
+        assert!(wait_open(&path, None, Duration::from_secs(10)).is_ok());
+        h.join().unwrap();
+
+        // regular file doesn't match any device number
+        let rdev = Some(libc::makedev(1, 3));
+        let err = wait_open(&path, rdev, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+
+        let err = wait_open(dir.path(), None, Duration::from_secs(10)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+    }
+
+    #[test]
+    fn test_wait_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ublkc0");
+
+        let err = wait_access(&path, None, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+
+        let p = path.clone();
+        let h = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            std::fs::File::create(p).unwrap();
+        });
+        wait_access(&path, None, Duration::from_secs(10)).unwrap();
+        h.join().unwrap();
+
+        // node held exclusively is still ready since it isn't opened
+        let _f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        wait_access(&path, None, Duration::ZERO).unwrap();
+
+        let rdev = Some(libc::makedev(1, 3));
+        let err = wait_access(&path, rdev, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+    }
+}
@@ -1,8 +1,11 @@
+use super::driver::UblkDriver;
 use super::uring_async::UblkUringOpFuture;
 #[cfg(feature = "fat_complete")]
 use super::UblkFatRes;
-use super::{ctrl::UblkCtrl, sys, UblkError, UblkFlags, UblkIORes};
+use super::{ctrl::UblkCtrl, sys, UblkError, UblkEventListener, UblkFlags, UblkIORes, UblkOpError};
 use crate::helpers::IoBuf;
+use bitflags::bitflags;
+use derive_setters::*;
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
@@ -195,6 +198,266 @@ pub struct UblkTgt {
     pub params: sys::ublk_params,
 }
 
+bitflags! {
+    #[derive(Default, Debug, PartialEq, Eq, Copy, Clone)]
+    /// UblkAttrs: queue attributes stored in `sys::ublk_param_basic.attrs`
+    pub struct UblkAttrs: u32 {
+        /// device is read-only
+        const READ_ONLY = sys::UBLK_ATTR_READ_ONLY;
+
+        /// device is rotational, such as HDD
+        const ROTATIONAL = sys::UBLK_ATTR_ROTATIONAL;
+
+        /// device has volatile write cache, FLUSH is required for
+        /// persisting written data
+        const VOLATILE_CACHE = sys::UBLK_ATTR_VOLATILE_CACHE;
+
+        /// device supports FUA write
+        const FUA = sys::UBLK_ATTR_FUA;
+    }
+}
+
+/// Why `UblkParamsBuilder::build()` rejects one parameter set
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UblkParamsError {
+    #[error("logical block shift {0} isn't in range of [9, page shift]")]
+    LogicalBlockShift(u8),
+
+    #[error("physical block shift {0} is less than logical block shift")]
+    PhysicalBlockShift(u8),
+
+    #[error("io min shift {0} is less than logical block shift")]
+    IoMinShift(u8),
+
+    #[error("io opt shift {0} is less than logical block shift")]
+    IoOptShift(u8),
+
+    #[error("max sectors {0} is bigger than max_io_buf_bytes")]
+    MaxSectors(u32),
+
+    #[error("device size isn't aligned with logical block size")]
+    DevSizeUnaligned,
+
+    #[error("discard granularity {0} isn't multiple of logical block size")]
+    DiscardGranularity(u32),
+
+    #[error("max discard segments {0} isn't supported, only single segment")]
+    DiscardSegments(u16),
+
+    #[error("zoned parameters require UBLK_F_ZONED, and vice versa")]
+    ZonedMismatch,
+
+    #[error("chunk sectors {0} has to be non-zero & power of 2 for zoned device")]
+    ChunkSectors(u32),
+
+    #[error("max zone append sectors can't be zero")]
+    ZoneAppendSectors,
+}
+
+/// Basic parameters of ublk device, mapped to `sys::ublk_param_basic`
+#[derive(Setters, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UblkBasicParams {
+    /// logical block size shift, 9 means 512 bytes
+    logical_bs_shift: u8,
+
+    /// physical block size shift, can't be less than `logical_bs_shift`
+    physical_bs_shift: u8,
+
+    /// optimal io size shift
+    io_opt_shift: u8,
+
+    /// minimal io size shift
+    io_min_shift: u8,
+
+    /// max sectors of single IO, zero means it is derived from
+    /// `sys::ublksrv_ctrl_dev_info.max_io_buf_bytes`
+    max_sectors: u32,
+
+    /// chunk size in sectors, zone size for zoned device
+    chunk_sectors: u32,
+
+    virt_boundary_mask: u64,
+}
+
+impl Default for UblkBasicParams {
+    fn default() -> Self {
+        UblkBasicParams {
+            logical_bs_shift: 9,
+            physical_bs_shift: 12,
+            io_opt_shift: 12,
+            io_min_shift: 12,
+            max_sectors: 0,
+            chunk_sectors: 0,
+            virt_boundary_mask: 0,
+        }
+    }
+}
+
+/// Discard parameters of ublk device, mapped to `sys::ublk_param_discard`
+#[derive(Setters, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UblkDiscardParams {
+    discard_alignment: u32,
+
+    /// discard granularity in bytes
+    discard_granularity: u32,
+    max_discard_sectors: u32,
+    max_write_zeroes_sectors: u32,
+
+    /// ublk driver only supports single segment discard
+    max_discard_segments: u16,
+}
+
+impl Default for UblkDiscardParams {
+    fn default() -> Self {
+        UblkDiscardParams {
+            discard_alignment: 0,
+            discard_granularity: 4096,
+            max_discard_sectors: u32::MAX >> 9,
+            max_write_zeroes_sectors: u32::MAX >> 9,
+            max_discard_segments: 1,
+        }
+    }
+}
+
+/// Zoned parameters of ublk device, mapped to `sys::ublk_param_zoned`
+///
+/// Only allowed if `UBLK_F_ZONED` is set for this device.
+#[derive(Setters, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UblkZonedParams {
+    max_open_zones: u32,
+    max_active_zones: u32,
+    max_zone_append_sectors: u32,
+}
+
+/// Build one validated `sys::ublk_params`
+///
+/// The `types` mask is figured out from the included sections, and
+/// invariants checked by ublk driver's SET_PARAMS are validated in
+/// `build()`, so that the mistake is caught before sending parameters
+/// to driver.
+///
+/// ```no_run
+/// use libublk::io::{UblkAttrs, UblkBasicParams, UblkDev, UblkParamsBuilder};
+///
+/// fn tgt_init(dev: &mut UblkDev) -> Result<(), libublk::UblkError> {
+///     dev.set_params(
+///         UblkParamsBuilder::default()
+///             .dev_size(1_u64 << 30)
+///             .basic(UblkBasicParams::default().logical_bs_shift(12))
+///             .attrs(UblkAttrs::VOLATILE_CACHE),
+///     )
+/// }
+/// ```
+#[derive(Setters, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UblkParamsBuilder {
+    /// device size in bytes
+    dev_size: u64,
+
+    basic: UblkBasicParams,
+    attrs: UblkAttrs,
+
+    #[setters(strip_option)]
+    discard: Option<UblkDiscardParams>,
+
+    #[setters(strip_option)]
+    zoned: Option<UblkZonedParams>,
+}
+
+impl UblkParamsBuilder {
+    /// Validate & build `sys::ublk_params` for the device described by
+    /// `dev_info`
+    pub fn build(
+        self,
+        dev_info: &sys::ublksrv_ctrl_dev_info,
+    ) -> Result<sys::ublk_params, UblkError> {
+        let b = &self.basic;
+        let page_shift = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.trailing_zeros() as u8;
+        let max_sectors = dev_info.max_io_buf_bytes >> 9;
+        let zoned_dev = (dev_info.flags & (sys::UBLK_F_ZONED as u64)) != 0;
+
+        if b.logical_bs_shift < 9 || b.logical_bs_shift > page_shift {
+            return Err(UblkParamsError::LogicalBlockShift(b.logical_bs_shift).into());
+        }
+        if b.physical_bs_shift < b.logical_bs_shift {
+            return Err(UblkParamsError::PhysicalBlockShift(b.physical_bs_shift).into());
+        }
+        if b.io_min_shift < b.logical_bs_shift {
+            return Err(UblkParamsError::IoMinShift(b.io_min_shift).into());
+        }
+        if b.io_opt_shift < b.logical_bs_shift {
+            return Err(UblkParamsError::IoOptShift(b.io_opt_shift).into());
+        }
+        if b.max_sectors > max_sectors {
+            return Err(UblkParamsError::MaxSectors(b.max_sectors).into());
+        }
+        if self.dev_size & ((1_u64 << b.logical_bs_shift) - 1) != 0 {
+            return Err(UblkParamsError::DevSizeUnaligned.into());
+        }
+
+        let mut params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: self.attrs.bits(),
+                logical_bs_shift: b.logical_bs_shift,
+                physical_bs_shift: b.physical_bs_shift,
+                io_opt_shift: b.io_opt_shift,
+                io_min_shift: b.io_min_shift,
+                max_sectors: if b.max_sectors == 0 {
+                    max_sectors
+                } else {
+                    b.max_sectors
+                },
+                chunk_sectors: b.chunk_sectors,
+                dev_sectors: self.dev_size >> 9,
+                virt_boundary_mask: b.virt_boundary_mask,
+            },
+            ..Default::default()
+        };
+
+        if let Some(d) = self.discard {
+            let lbs = 1_u32 << b.logical_bs_shift;
+
+            if d.discard_granularity == 0 || d.discard_granularity & (lbs - 1) != 0 {
+                return Err(UblkParamsError::DiscardGranularity(d.discard_granularity).into());
+            }
+            if d.max_discard_sectors != 0 && d.max_discard_segments != 1 {
+                return Err(UblkParamsError::DiscardSegments(d.max_discard_segments).into());
+            }
+            params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+            params.discard = sys::ublk_param_discard {
+                discard_alignment: d.discard_alignment,
+                discard_granularity: d.discard_granularity,
+                max_discard_sectors: d.max_discard_sectors,
+                max_write_zeroes_sectors: d.max_write_zeroes_sectors,
+                max_discard_segments: d.max_discard_segments,
+                ..Default::default()
+            };
+        }
+
+        match self.zoned {
+            Some(z) if zoned_dev => {
+                if b.chunk_sectors == 0 || !b.chunk_sectors.is_power_of_two() {
+                    return Err(UblkParamsError::ChunkSectors(b.chunk_sectors).into());
+                }
+                if z.max_zone_append_sectors == 0 {
+                    return Err(UblkParamsError::ZoneAppendSectors.into());
+                }
+                params.types |= sys::UBLK_PARAM_TYPE_ZONED;
+                params.zoned = sys::ublk_param_zoned {
+                    max_open_zones: z.max_open_zones,
+                    max_active_zones: z.max_active_zones,
+                    max_zone_append_sectors: z.max_zone_append_sectors,
+                    ..Default::default()
+                };
+            }
+            None if !zoned_dev => {}
+            _ => return Err(UblkParamsError::ZonedMismatch.into()),
+        }
+
+        Ok(params)
+    }
+}
+
 /// For supporting ublk device IO path, and one thin layer of device
 /// abstract in handling IO level. Ublk device supports multiple queue(MQ),
 /// and each queue has its IO depth.
@@ -213,6 +476,13 @@ pub struct UblkDev {
 
     pub tgt: UblkTgt,
     tgt_json: Option<serde_json::Value>,
+    listener: Option<std::sync::Arc<dyn UblkEventListener>>,
+    driver: std::sync::Arc<dyn UblkDriver>,
+
+    // (queue id, uring fd) of live queues, see `quiesce_queues()`
+    q_rings: std::sync::Mutex<Vec<(u16, RawFd)>>,
+    quiescing: std::sync::atomic::AtomicBool,
+    quiesce_deadline: std::sync::Mutex<Option<std::time::Instant>>,
 }
 
 unsafe impl Send for UblkDev {}
@@ -243,27 +513,12 @@ impl UblkDev {
             ring_flags: 0,
             ..Default::default()
         };
-        let mut cnt = 0;
-        let cdev_path = ctrl.get_cdev_path();
+        let driver = ctrl.get_driver();
 
         // ublk char device setup(udev event handling, ...) may not be done
-        // successfully, so wait a while. And the timeout is set as 3sec now.
-        let cdev_file = loop {
-            let f_result = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&cdev_path);
-
-            if let Ok(f) = f_result {
-                break f;
-            }
-
-            cnt += 1;
-            std::thread::sleep(std::time::Duration::from_millis(10));
-            if cnt >= 300 {
-                return Err(UblkError::OtherError(-libc::EACCES));
-            }
-        };
+        // yet, so wait until it is ready or `UblkCtrl::cdev_timeout()`
+        let cdev_file =
+            driver.open_cdev(info.dev_id, &ctrl.get_cdev_path(), ctrl.cdev_timeout())?;
 
         tgt.fds[0] = cdev_file.as_raw_fd();
         tgt.nr_fds = 1;
@@ -274,7 +529,13 @@ impl UblkDev {
             tgt,
             flags: ctrl.get_dev_flags(),
             tgt_json: None,
+            listener: ctrl.get_event_listener(),
+            driver,
+            q_rings: std::sync::Mutex::new(Vec::new()),
+            quiescing: std::sync::atomic::AtomicBool::new(false),
+            quiesce_deadline: std::sync::Mutex::new(None),
         };
+        dev.notify(|l, id| l.on_cdev_opened(id));
 
         ops(&mut dev)?;
         log::info!("dev {} initialized", dev.dev_info.dev_id);
@@ -286,9 +547,85 @@ impl UblkDev {
     fn deinit_cdev(&mut self) {
         let id = self.dev_info.dev_id;
 
+        self.driver.release_cdev(id);
+
         log::info!("dev {} deinitialized", id);
     }
 
+    /// Install event listener for this device, which is inherited from
+    /// `UblkCtrl` by default
+    pub fn set_event_listener(&mut self, listener: std::sync::Arc<dyn UblkEventListener>) {
+        self.listener = Some(listener);
+    }
+
+    /// Notify event listener if it is installed
+    #[inline]
+    pub(crate) fn notify<F: FnOnce(&dyn UblkEventListener, u32)>(&self, f: F) {
+        if let Some(l) = &self.listener {
+            f(l.as_ref(), self.dev_info.dev_id);
+        }
+    }
+
+    /// Make all queues of this device exit without stopping the device
+    ///
+    /// Queues blocked in waiting for IO are woken up by `IORING_OP_MSG_RING`,
+    /// and stop issuing FETCH and COMMIT io commands, then keep handling
+    /// in-flight target IOs until they are done or `timeout` expires, and
+    /// finally `UblkQueue::wait_and_handle_io()` and `ublk_wait_and_handle_ios()`
+    /// return without waiting for in-flight io commands. Once all queues
+    /// exit and the char device is closed, ublk driver quiesces the device
+    /// if it supports recovery, and IOs whose results aren't committed are
+    /// handled according to the recovery mode.
+    pub fn quiesce_queues(&self, timeout: std::time::Duration) -> Result<(), UblkError> {
+        use std::sync::atomic::Ordering;
+
+        // set them before waking up, so that queues registered after the
+        // wakeup observe them before waiting
+        *self.quiesce_deadline.lock().unwrap() = Some(std::time::Instant::now() + timeout);
+        self.quiescing.store(true, Ordering::SeqCst);
+
+        let rings = self.q_rings.lock().unwrap();
+        if rings.is_empty() {
+            return Ok(());
+        }
+        let mut ring = IoUring::<squeue::Entry, cqueue::Entry>::new(rings.len() as u32)
+            .map_err(|e| UblkOpError::from_io("quiesce_queues", &e).dev(self.dev_info.dev_id))?;
+        for (qid, fd) in rings.iter() {
+            let sqe =
+                opcode::MsgRingData::new(types::Fd(*fd), 0, UblkQueue::UBLK_QUEUE_WAKE_DATA, None)
+                    .build()
+                    .user_data(*qid as u64);
+            unsafe { ring.submission().push(&sqe) }.expect("submission queue is full");
+        }
+        ring.submit_and_wait(rings.len())
+            .map_err(|e| UblkOpError::from_io("quiesce_queues", &e).dev(self.dev_info.dev_id))?;
+
+        for cqe in ring.completion() {
+            if cqe.result() < 0 {
+                return Err(UblkOpError::new("quiesce_queues", cqe.result())
+                    .dev(self.dev_info.dev_id)
+                    .queue(cqe.user_data() as u16)
+                    .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Return true if `quiesce_queues()` is called
+    #[inline]
+    pub fn is_quiescing(&self) -> bool {
+        self.quiescing.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Return time left for queues to drain target IOs after
+    /// `quiesce_queues()` is called
+    fn quiesce_time_left(&self) -> std::time::Duration {
+        match *self.quiesce_deadline.lock().unwrap() {
+            Some(d) => d.saturating_duration_since(std::time::Instant::now()),
+            None => std::time::Duration::ZERO,
+        }
+    }
+
     /// Allocate IoBufs for one queue
     pub fn alloc_queue_io_bufs(&self) -> Vec<IoBuf<u8>> {
         let depth = self.dev_info.queue_depth;
@@ -321,6 +658,17 @@ impl UblkDev {
         };
     }
 
+    /// Validate parameters built from `UblkParamsBuilder`, and store them
+    /// as this device's parameters, which will be sent to driver when
+    /// starting device
+    pub fn set_params(&mut self, builder: UblkParamsBuilder) -> Result<(), UblkError> {
+        let params = builder.build(&self.dev_info)?;
+
+        self.tgt.dev_size = params.basic.dev_sectors << 9;
+        self.tgt.params = params;
+        Ok(())
+    }
+
     // Store target specific json data, json["target_data"]
     pub fn set_target_json(&mut self, val: serde_json::Value) {
         self.tgt_json = Some(val);
@@ -351,6 +699,9 @@ impl Drop for UblkDev {
 #[derive(Debug, Clone, Default)]
 struct UblkQueueState {
     cmd_inflight: u32,
+
+    // io commands delivered to target and not committed yet
+    tgt_inflight: u32,
     state: u32,
 }
 
@@ -393,6 +744,11 @@ impl UblkQueueState {
         self.cmd_inflight -= 1;
     }
 
+    #[inline(always)]
+    fn get_nr_tgt_inflight(&self) -> u32 {
+        self.tgt_inflight
+    }
+
     fn mark_stopping(&mut self) {
         self.state |= Self::UBLK_QUEUE_STOPPING;
     }
@@ -445,6 +801,10 @@ impl Drop for UblkQueue<'_> {
         let dev = self.dev;
         log::trace!("dev {} queue {} dropped", dev.dev_info.dev_id, self.q_id);
 
+        // the uring fd is closed after this
+        dev.q_rings.lock().unwrap().retain(|(q, _)| *q != self.q_id);
+        dev.driver.release_queue(dev.dev_info.dev_id, self.q_id);
+
         if let Err(r) = self.q_ring.borrow_mut().submitter().unregister_files() {
             log::error!("unregister fixed files failed {}", r);
         }
@@ -468,6 +828,10 @@ impl UblkQueue<'_> {
     const UBLK_QUEUE_IDLE_SECS: u32 = 20;
     const UBLK_QUEUE_IOCTL_ENCODE: UblkFlags = UblkFlags::UBLK_DEV_F_INTERNAL_0;
 
+    // user_data of CQE posted by `UblkDev::quiesce_queues()`, which isn't
+    // used by io command, target io or io task
+    const UBLK_QUEUE_WAKE_DATA: u64 = u64::MAX;
+
     #[inline(always)]
     fn cmd_buf_sz(depth: u32) -> u32 {
         let size = depth * core::mem::size_of::<sys::ublksrv_io_desc>() as u32;
@@ -495,11 +859,18 @@ impl UblkQueue<'_> {
         let tgt = &dev.tgt;
         let sq_depth = tgt.sq_depth;
         let cq_depth = tgt.cq_depth;
+        let q_err = |op, e: &std::io::Error| -> UblkError {
+            UblkOpError::from_io(op, e)
+                .dev(dev.dev_info.dev_id)
+                .queue(q_id)
+                .into()
+        };
 
         let ring = IoUring::<squeue::Entry, cqueue::Entry>::builder()
             .setup_cqsize(cq_depth as u32)
             .setup_coop_taskrun()
-            .build(sq_depth as u32)?;
+            .build(sq_depth as u32)
+            .map_err(|e| q_err("setup_ring", &e))?;
 
         //todo: apply io_uring flags from tgt.ring_flags
 
@@ -508,7 +879,8 @@ impl UblkQueue<'_> {
         let cmd_buf_sz = UblkQueue::cmd_buf_sz(depth) as usize;
 
         ring.submitter()
-            .register_files(&tgt.fds[0..tgt.nr_fds as usize])?;
+            .register_files(&tgt.fds[0..tgt.nr_fds as usize])
+            .map_err(|e| q_err("register_files", &e))?;
 
         let off = sys::UBLKSRV_CMD_BUF_OFFSET as i64
             + q_id as i64
@@ -525,7 +897,7 @@ impl UblkQueue<'_> {
             )
         };
         if io_cmd_buf == libc::MAP_FAILED {
-            return Err(UblkError::IOError(std::io::Error::last_os_error()));
+            return Err(q_err("mmap_io_desc", &std::io::Error::last_os_error()));
         }
 
         let nr_ios = depth + tgt.extra_ios as u32;
@@ -553,11 +925,13 @@ impl UblkQueue<'_> {
             dev,
             state: RefCell::new(UblkQueueState {
                 cmd_inflight: 0,
+                tgt_inflight: 0,
                 state: 0,
             }),
             q_ring: RefCell::new(ring),
             bufs: RefCell::new(bufs),
         };
+        dev.q_rings.lock().unwrap().push((q_id, q.as_raw_fd()));
 
         log::info!("dev {} queue {} started", dev.dev_info.dev_id, q_id);
 
@@ -677,6 +1051,9 @@ impl UblkQueue<'_> {
         res: i32,
     ) -> i32 {
         let mut state = self.state.borrow_mut();
+        if cmd_op & 0xff == sys::UBLK_IO_COMMIT_AND_FETCH_REQ {
+            state.tgt_inflight = state.tgt_inflight.saturating_sub(1);
+        }
         if state.is_stopping() {
             return 0;
         }
@@ -694,19 +1071,25 @@ impl UblkQueue<'_> {
             cmd_op
         };
 
-        let sqe = opcode::UringCmd16::new(types::Fixed(0), cmd_op)
-            .cmd(unsafe { core::mem::transmute::<sys::ublksrv_io_cmd, [u8; 16]>(io_cmd) })
-            .build()
-            .user_data(user_data);
+        let sqe = self.dev.driver.io_cmd(
+            r.as_raw_fd(),
+            self.dev.dev_info.dev_id,
+            cmd_op,
+            &io_cmd,
+            user_data,
+        );
 
-        loop {
-            let res = unsafe { r.submission().push(&sqe) };
+        // CQE may be posted by driver directly
+        if let Some(sqe) = sqe {
+            loop {
+                let res = unsafe { r.submission().push(&sqe) };
 
-            match res {
-                Ok(_) => break,
-                Err(_) => {
-                    log::debug!("__queue_io_cmd: flush submission and retry");
-                    r.submit_and_wait(0).unwrap();
+                match res {
+                    Ok(_) => break,
+                    Err(_) => {
+                        log::debug!("__queue_io_cmd: flush submission and retry");
+                        r.submit_and_wait(0).unwrap();
+                    }
                 }
             }
         }
@@ -870,17 +1253,22 @@ impl UblkQueue<'_> {
     ///
     /// When calling this API, target code has to make sure that q_ring
     /// won't be borrowed.
+    ///
+    /// Error is committed with its errno, see `UblkError::errno()`, and
+    /// -EIO is used if the error doesn't carry errno.
     #[inline]
     pub fn complete_io_cmd(&self, tag: u16, buf_addr: *mut u8, res: Result<UblkIORes, UblkError>) {
         let r = &mut self.q_ring.borrow_mut();
 
         match res {
-            Ok(UblkIORes::Result(res))
-            | Err(UblkError::OtherError(res))
-            | Err(UblkError::UringIOError(res)) => {
+            Ok(UblkIORes::Result(res)) => {
                 self.commit_and_queue_io_cmd(r, tag, buf_addr as u64, res);
             }
             Err(UblkError::UringIoQueued) => {}
+            Err(e) => {
+                let res = e.errno().unwrap_or(-libc::EIO);
+                self.commit_and_queue_io_cmd(r, tag, buf_addr as u64, res);
+            }
             #[cfg(feature = "fat_complete")]
             Ok(UblkIORes::FatRes(fat)) => match fat {
                 UblkFatRes::BatchRes(ios) => {
@@ -894,7 +1282,6 @@ impl UblkQueue<'_> {
                     self.commit_and_queue_io_cmd(r, tag, lba, res);
                 }
             },
-            _ => {}
         };
     }
 
@@ -904,7 +1291,13 @@ impl UblkQueue<'_> {
             let mut state = self.state.borrow_mut();
 
             state.dec_cmd_inflight();
+            if cqe.result() == sys::UBLK_IO_RES_OK as i32 {
+                state.tgt_inflight += 1;
+            }
             if cqe.result() == sys::UBLK_IO_RES_ABORT {
+                if !state.is_stopping() {
+                    self.dev.notify(|l, id| l.on_queue_aborting(id, self.q_id));
+                }
                 state.mark_stopping();
             }
         }
@@ -920,6 +1313,10 @@ impl UblkQueue<'_> {
         let tag = UblkIOCtx::user_data_to_tag(data);
         let cmd_op = UblkIOCtx::user_data_to_op(data);
 
+        if data == Self::UBLK_QUEUE_WAKE_DATA {
+            return;
+        }
+
         {
             log::trace!(
                 "{}: res {} (qid {} tag {} cmd_op {} target {}) state {:?}",
@@ -1001,7 +1398,7 @@ impl UblkQueue<'_> {
         }
     }
 
-    fn enter_queue_idle(&self) {
+    pub(crate) fn enter_queue_idle(&self) {
         let mut state = self.state.borrow_mut();
         let empty = self.q_ring.borrow_mut().submission().is_empty();
 
@@ -1013,6 +1410,7 @@ impl UblkQueue<'_> {
             );
             state.set_idle(true);
             self.discard_io_pages();
+            self.dev.notify(|l, id| l.on_queue_idle(id, self.q_id));
         }
     }
 
@@ -1027,6 +1425,7 @@ impl UblkQueue<'_> {
                 self.q_id
             );
             self.state.borrow_mut().set_idle(false);
+            self.dev.notify(|l, id| l.on_queue_busy(id, self.q_id));
         }
     }
 
@@ -1036,9 +1435,40 @@ impl UblkQueue<'_> {
         self.q_depth - self.state.borrow().get_nr_cmd_inflight()
     }
 
+    /// Stop issuing io commands after the device is quiescing, and return
+    /// how long to wait for in-flight target IOs
+    ///
+    /// `UblkError::QueueIsDown` is returned after all target IOs are done,
+    /// or the time for draining them is used up.
+    fn quiesce_wait_time(&self) -> Result<std::time::Duration, UblkError> {
+        let mut state = self.state.borrow_mut();
+        let left = self.dev.quiesce_time_left();
+
+        state.mark_stopping();
+        if state.get_nr_tgt_inflight() == 0 {
+            return Err(UblkError::QueueIsDown);
+        }
+        if left.is_zero() {
+            log::warn!(
+                "dev{}-q{}: {} target ios aren't drained in quiescing",
+                self.dev.dev_info.dev_id,
+                self.q_id,
+                state.get_nr_tgt_inflight()
+            );
+            return Err(UblkError::QueueIsDown);
+        }
+        Ok(left.min(std::time::Duration::from_secs(
+            Self::UBLK_QUEUE_IDLE_SECS as u64,
+        )))
+    }
+
     #[inline]
     fn __wait_ios(&self, to_wait: usize) -> Result<i32, UblkError> {
-        let ts = types::Timespec::new().sec(Self::UBLK_QUEUE_IDLE_SECS as u64);
+        let ts = if self.dev.is_quiescing() {
+            types::Timespec::from(self.quiesce_wait_time()?)
+        } else {
+            types::Timespec::new().sec(Self::UBLK_QUEUE_IDLE_SECS as u64)
+        };
         let args = types::SubmitArgs::new().timespec(&ts);
 
         let state = self.state.borrow();
@@ -1064,7 +1494,12 @@ impl UblkQueue<'_> {
             Err(ref err) if err.raw_os_error() == Some(libc::ETIME) => {
                 return Err(UblkError::UringTimeout);
             }
-            Err(err) => return Err(UblkError::IOError(err)),
+            Err(err) => {
+                return Err(UblkOpError::from_io("wait_ios", &err)
+                    .dev(self.dev.dev_info.dev_id)
+                    .queue(self.q_id)
+                    .into())
+            }
             Ok(_) => {}
         };
 
@@ -1155,7 +1590,7 @@ impl UblkQueue<'_> {
                 for idx in 0..done {
                     self.reap_one_event(&mut ops, idx, done);
                 }
-                Ok(0)
+                Ok(done)
             }
         }
     }
@@ -1215,6 +1650,9 @@ impl UblkQueue<'_> {
                         }
                     };
                     let user_data = cqe.user_data();
+                    if user_data == Self::UBLK_QUEUE_WAKE_DATA {
+                        continue;
+                    }
                     if UblkIOCtx::is_io_command(user_data) {
                         self.update_state(&cqe);
                     }
@@ -1229,8 +1667,11 @@ impl UblkQueue<'_> {
 #[cfg(test)]
 mod tests {
     use crate::ctrl::UblkCtrlBuilder;
-    use crate::io::{UblkDev, UblkQueue};
-    use crate::{UblkError, UblkFlags};
+    use crate::io::{
+        UblkAttrs, UblkBasicParams, UblkDev, UblkDiscardParams, UblkParamsBuilder, UblkParamsError,
+        UblkQueue, UblkZonedParams,
+    };
+    use crate::{sys, UblkError, UblkFlags};
     use io_uring::IoUring;
 
     fn __submit_uring_nop(ring: &mut IoUring<io_uring::squeue::Entry>) -> Result<usize, UblkError> {
@@ -1269,4 +1710,83 @@ mod tests {
 
         UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
     }
+
+    fn params_dev_info(flags: u64) -> sys::ublksrv_ctrl_dev_info {
+        sys::ublksrv_ctrl_dev_info {
+            max_io_buf_bytes: 512 << 10,
+            flags,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_params_builder() {
+        let info = params_dev_info(0);
+        let p = UblkParamsBuilder::default()
+            .dev_size(1_u64 << 30)
+            .attrs(UblkAttrs::VOLATILE_CACHE)
+            .discard(UblkDiscardParams::default())
+            .build(&info)
+            .unwrap();
+
+        assert!(p.types == (sys::UBLK_PARAM_TYPE_BASIC | sys::UBLK_PARAM_TYPE_DISCARD));
+        assert!(p.basic.dev_sectors == (1_u64 << 30) >> 9);
+        assert!(p.basic.max_sectors == (512 << 10) >> 9);
+        assert!(p.basic.attrs == sys::UBLK_ATTR_VOLATILE_CACHE);
+        assert!(p.discard.max_discard_segments == 1);
+    }
+
+    #[test]
+    fn test_params_builder_invalid() {
+        let info = params_dev_info(0);
+        let basic = UblkBasicParams::default();
+        let check = |b: UblkParamsBuilder, e: UblkParamsError| match b.build(&info) {
+            Err(UblkError::InvalidParams(err)) => assert!(err == e),
+            _ => panic!("{:?} isn't caught", e),
+        };
+
+        check(
+            UblkParamsBuilder::default().basic(basic.logical_bs_shift(8)),
+            UblkParamsError::LogicalBlockShift(8),
+        );
+        check(
+            UblkParamsBuilder::default().basic(basic.physical_bs_shift(9).logical_bs_shift(12)),
+            UblkParamsError::PhysicalBlockShift(9),
+        );
+        check(
+            UblkParamsBuilder::default().basic(basic.max_sectors(2048)),
+            UblkParamsError::MaxSectors(2048),
+        );
+        check(
+            UblkParamsBuilder::default().dev_size(4097),
+            UblkParamsError::DevSizeUnaligned,
+        );
+        check(
+            UblkParamsBuilder::default()
+                .discard(UblkDiscardParams::default().discard_granularity(0)),
+            UblkParamsError::DiscardGranularity(0),
+        );
+        check(
+            UblkParamsBuilder::default().zoned(UblkZonedParams::default()),
+            UblkParamsError::ZonedMismatch,
+        );
+    }
+
+    #[test]
+    fn test_params_builder_zoned() {
+        let info = params_dev_info(sys::UBLK_F_ZONED as u64);
+
+        assert!(matches!(
+            UblkParamsBuilder::default().build(&info),
+            Err(UblkError::InvalidParams(UblkParamsError::ZonedMismatch))
+        ));
+
+        let p = UblkParamsBuilder::default()
+            .dev_size(1_u64 << 30)
+            .basic(UblkBasicParams::default().chunk_sectors(512))
+            .zoned(UblkZonedParams::default().max_zone_append_sectors(512))
+            .build(&info)
+            .unwrap();
+        assert!(p.types == (sys::UBLK_PARAM_TYPE_BASIC | sys::UBLK_PARAM_TYPE_ZONED));
+    }
 }
@@ -8,8 +8,12 @@
 use bitflags::bitflags;
 
 pub mod ctrl;
+mod driver;
 pub mod helpers;
 pub mod io;
+pub mod manager;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
 pub mod sys;
 pub mod uring_async;
 
@@ -34,6 +38,64 @@ bitflags! {
     }
 }
 
+bitflags! {
+    #[derive(Default, Debug, PartialEq, Eq, Copy, Clone)]
+    /// UblkFeatures: ublk driver features, passed to driver via
+    /// `sys::ublksrv_ctrl_dev_info.flags`, and reported by GET_FEATURES
+    pub struct UblkFeatures: u64 {
+        /// zero copy, not supported by ublk driver yet
+        const UBLK_F_SUPPORT_ZERO_COPY = sys::UBLK_F_SUPPORT_ZERO_COPY as u64;
+
+        /// complete uring_cmd in task work context
+        const UBLK_F_URING_CMD_COMP_IN_TASK = sys::UBLK_F_URING_CMD_COMP_IN_TASK as u64;
+
+        /// WRITE data is fetched by UBLK_IO_NEED_GET_DATA
+        const UBLK_F_NEED_GET_DATA = sys::UBLK_F_NEED_GET_DATA as u64;
+
+        /// device can be recovered after the ublk server is crashed
+        const UBLK_F_USER_RECOVERY = sys::UBLK_F_USER_RECOVERY as u64;
+
+        /// inflight IOs are re-issued after the device is recovered
+        const UBLK_F_USER_RECOVERY_REISSUE = sys::UBLK_F_USER_RECOVERY_REISSUE as u64;
+
+        /// device can be created by unprivileged user
+        const UBLK_F_UNPRIVILEGED_DEV = sys::UBLK_F_UNPRIVILEGED_DEV as u64;
+
+        /// command opcode is encoded as ioctl
+        const UBLK_F_CMD_IOCTL_ENCODE = sys::UBLK_F_CMD_IOCTL_ENCODE as u64;
+
+        /// IO data is copied via pread()/pwrite() on ublk char device
+        const UBLK_F_USER_COPY = sys::UBLK_F_USER_COPY as u64;
+
+        /// zoned block device
+        const UBLK_F_ZONED = sys::UBLK_F_ZONED as u64;
+
+        /// IOs are failed when the device is quiesced for recovery
+        const UBLK_F_USER_RECOVERY_FAIL_IO = sys::UBLK_F_USER_RECOVERY_FAIL_IO as u64;
+    }
+}
+
+/// Human-readable feature names, such as "user_copy|zoned"; unknown bits
+/// are shown in hex
+impl std::fmt::Display for UblkFeatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<String> = self
+            .iter_names()
+            .map(|(name, _)| name.trim_start_matches("UBLK_F_").to_lowercase())
+            .collect();
+        let unknown = self.bits() & !Self::all().bits();
+
+        if unknown != 0 {
+            names.push(format!("{:#x}", unknown));
+        }
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join("|"))
+        }
+    }
+}
+
 /// Ublk Fat completion result
 pub enum UblkFatRes {
     /// Batch completion
@@ -62,6 +124,116 @@ pub enum UblkIORes {
     FatRes(UblkFatRes),
 }
 
+/// Listener of ublk device lifecycle events
+///
+/// Installed via `ctrl::UblkCtrl::set_event_listener()` and inherited by
+/// `io::UblkDev`, or installed via `io::UblkDev::set_event_listener()`.
+/// All callbacks are no-op by default.
+///
+/// Callbacks from `UblkCtrl` are called with its internal lock held, so
+/// they can't call into `UblkCtrl`; queue callbacks are called from queue
+/// pthread context, so they have to be lightweight.
+pub trait UblkEventListener: Send + Sync {
+    /// /dev/ublkcN is opened for creating `io::UblkDev`
+    fn on_cdev_opened(&self, _dev_id: u32) {}
+
+    /// device parameters are sent to ublk driver
+    fn on_params_set(&self, _dev_id: u32) {}
+
+    /// queue pthread is configured, and its tid is recorded
+    fn on_queue_configured(&self, _dev_id: u32, _qid: u16, _tid: i32) {}
+
+    /// device is started or recovered
+    fn on_started(&self, _dev_id: u32) {}
+
+    /// all io commands of this queue are idle for a while
+    fn on_queue_idle(&self, _dev_id: u32, _qid: u16) {}
+
+    /// queue becomes busy after being idle
+    fn on_queue_busy(&self, _dev_id: u32, _qid: u16) {}
+
+    /// queue observes UBLK_IO_RES_ABORT, and starts to abort
+    fn on_queue_aborting(&self, _dev_id: u32, _qid: u16) {}
+
+    /// device is stopped
+    fn on_stopped(&self, _dev_id: u32) {}
+
+    /// exported json file is flushed to `path`
+    fn on_json_flushed(&self, _dev_id: u32, _path: &str) {}
+}
+
+/// Context of one failed ublk operation, carried by `UblkError::Op`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UblkOpError {
+    /// control command name such as "START_DEV", or queue operation
+    /// such as "wait_ios"
+    pub op: &'static str,
+    pub dev_id: Option<u32>,
+    pub q_id: Option<u16>,
+    pub tag: Option<u16>,
+
+    /// negative errno
+    pub errno: i32,
+}
+
+impl UblkOpError {
+    pub fn new(op: &'static str, errno: i32) -> Self {
+        UblkOpError {
+            op,
+            dev_id: None,
+            q_id: None,
+            tag: None,
+            errno,
+        }
+    }
+
+    /// Build from `std::io::Error`, and -EIO is used if it has no errno
+    pub fn from_io(op: &'static str, err: &std::io::Error) -> Self {
+        Self::new(op, -err.raw_os_error().unwrap_or(libc::EIO))
+    }
+
+    pub fn dev(mut self, dev_id: u32) -> Self {
+        self.dev_id = Some(dev_id);
+        self
+    }
+
+    pub fn queue(mut self, q_id: u16) -> Self {
+        self.q_id = Some(q_id);
+        self
+    }
+
+    pub fn tag(mut self, tag: u16) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+}
+
+impl std::fmt::Display for UblkOpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed", self.op)?;
+        if let Some(id) = self.dev_id {
+            write!(f, " on dev {}", id)?;
+        }
+        if let Some(q) = self.q_id {
+            write!(f, " queue {}", q)?;
+        }
+        if let Some(t) = self.tag {
+            write!(f, " tag {}", t)?;
+        }
+        write!(f, ": {}", errno_desc(&self.errno))
+    }
+}
+
+impl From<UblkOpError> for UblkError {
+    fn from(e: UblkOpError) -> Self {
+        UblkError::Op(Box::new(e))
+    }
+}
+
+fn errno_desc(errno: &i32) -> std::io::Error {
+    std::io::Error::from_raw_os_error(-errno)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum UblkError {
     #[error("uring submission timeout")]
@@ -70,7 +242,10 @@ pub enum UblkError {
     #[error("IO Queued")]
     UringIoQueued,
 
-    #[error("io_uring IO failure")]
+    /// failed io_uring operation without context; failed control command
+    /// is reported as `UblkError::Op` with command name and device id
+    /// instead, and its errno is still available via `errno()`
+    #[error("io_uring IO failure: {}", errno_desc(.0))]
     UringIOError(i32),
 
     #[error("json failure")]
@@ -85,13 +260,110 @@ pub enum UblkError {
     #[error("Invalid input")]
     InvalidVal,
 
-    #[error("other failure")]
+    #[error("invalid parameters: {0}")]
+    InvalidParams(#[from] io::UblkParamsError),
+
+    #[error("invalid device limits: {0}")]
+    InvalidLimits(#[from] ctrl::UblkLimitError),
+
+    #[error("ublk driver doesn't support required features: {0}")]
+    MissingFeatures(UblkFeatures),
+
+    #[error("control command {} on device {dev_id} timed out", ctrl::ctrl_cmd_name(*.cmd))]
+    CtrlTimeout { cmd: u32, dev_id: u32 },
+
+    #[error("device {dev_id} is in state {state}, which isn't expected")]
+    InvalidDevState {
+        dev_id: u32,
+        state: ctrl::UblkDevState,
+    },
+
+    #[error("device {dev_id} is still owned by live daemon {pid}")]
+    OwnerAlive { dev_id: u32, pid: i32 },
+
+    #[error("device {dev_id} can't be recovered, recovery mode {mode}")]
+    RecoveryNotSupported {
+        dev_id: u32,
+        mode: ctrl::UblkRecoveryMode,
+    },
+
+    /// boxed for keeping `Result<UblkIORes, UblkError>` small
+    #[error("{0}")]
+    Op(Box<UblkOpError>),
+
+    #[error("other failure: {}", errno_desc(.0))]
     OtherError(i32),
 }
 
+impl UblkError {
+    /// Negative errno of this error, if it is from one failed syscall or
+    /// ublk command
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            UblkError::UringIOError(e) | UblkError::OtherError(e) => Some(*e),
+            UblkError::Op(op) => Some(op.errno),
+            UblkError::IOError(e) => e.raw_os_error().map(|e| -e),
+            UblkError::CtrlTimeout { .. } => Some(-libc::ETIMEDOUT),
+            _ => None,
+        }
+    }
+
+    /// Context of the failed operation
+    pub fn op(&self) -> Option<&UblkOpError> {
+        match self {
+            UblkError::Op(op) => Some(op),
+            _ => None,
+        }
+    }
+
+    /// Decode this error as `std::io::ErrorKind`
+    pub fn kind(&self) -> std::io::ErrorKind {
+        use std::io::ErrorKind;
+
+        match self {
+            UblkError::IOError(e) => e.kind(),
+            UblkError::UringTimeout | UblkError::CtrlTimeout { .. } => ErrorKind::TimedOut,
+            UblkError::InvalidVal | UblkError::InvalidParams(_) | UblkError::InvalidLimits(_) => {
+                ErrorKind::InvalidInput
+            }
+            UblkError::JsonError(_) => ErrorKind::InvalidData,
+            UblkError::MissingFeatures(_) => ErrorKind::Unsupported,
+            _ => match self.errno() {
+                Some(e) => errno_desc(&e).kind(),
+                None => ErrorKind::Other,
+            },
+        }
+    }
+
+    /// Device or file doesn't exist, such as device is deleted concurrently
+    ///
+    /// ublk driver fails command on unknown device with -ENODEV, and
+    /// -ENOENT is from removed device node or json file.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self.errno(), Some(e) if e == -libc::ENOENT || e == -libc::ENODEV)
+    }
+
+    /// Device or resource is busy(-EBUSY), and the operation may be retried
+    pub fn is_busy(&self) -> bool {
+        self.errno() == Some(-libc::EBUSY)
+    }
+
+    /// Operation timed out(-ETIMEDOUT), such as control command or
+    /// waiting for char device
+    pub fn is_timeout(&self) -> bool {
+        self.errno() == Some(-libc::ETIMEDOUT)
+    }
+
+    /// Permission denied(-EACCES or -EPERM), such as char device isn't
+    /// chown-ed for unprivileged device yet
+    pub fn is_permission_denied(&self) -> bool {
+        matches!(self.errno(), Some(e) if e == -libc::EACCES || e == -libc::EPERM)
+    }
+}
+
 #[cfg(test)]
 mod libublk {
-    use crate::{UblkError, UblkIORes};
+    use crate::{UblkError, UblkFeatures, UblkIORes, UblkOpError};
 
     #[cfg(not(feature = "fat_complete"))]
     #[test]
@@ -106,4 +378,58 @@ mod libublk {
         let sz = core::mem::size_of::<Result<UblkIORes, UblkError>>();
         assert!(sz == 32);
     }
+
+    #[test]
+    fn test_features_display() {
+        let f = UblkFeatures::UBLK_F_USER_COPY | UblkFeatures::UBLK_F_ZONED;
+
+        assert!(f.to_string() == "user_copy|zoned");
+        assert!(UblkFeatures::empty().to_string() == "none");
+        assert!(UblkFeatures::from_bits_retain(1 << 40).to_string() == "0x10000000000");
+    }
+
+    #[test]
+    fn test_error_context() {
+        let e = UblkError::from(UblkOpError::new("START_DEV", -libc::EBUSY).dev(3));
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "START_DEV failed on dev 3: {}",
+                std::io::Error::from_raw_os_error(libc::EBUSY)
+            )
+        );
+        assert!(e.is_busy() && !e.is_not_found());
+        assert_eq!(
+            e.op().map(|op| (op.op, op.dev_id)),
+            Some(("START_DEV", Some(3)))
+        );
+
+        let e: UblkError = UblkOpError::new("queue_io", -libc::ENODEV)
+            .dev(1)
+            .queue(2)
+            .tag(7)
+            .into();
+        assert!(e
+            .to_string()
+            .starts_with("queue_io failed on dev 1 queue 2 tag 7: "));
+        assert!(e.is_not_found());
+        assert_eq!(e.errno(), Some(-libc::ENODEV));
+
+        let e = UblkError::IOError(std::io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(e.is_not_found());
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(
+            UblkError::OtherError(-libc::EACCES).kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            UblkError::InvalidVal.kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(UblkError::QueueIsDown.errno(), None);
+
+        let e = UblkError::from(UblkOpError::new("open_cdev", -libc::ETIMEDOUT).dev(0));
+        assert!(e.is_timeout() && !e.is_not_found() && !e.is_permission_denied());
+        assert!(UblkError::OtherError(-libc::EPERM).is_permission_denied());
+    }
 }
@@ -0,0 +1,516 @@
+use crate::ctrl::UblkCtrl;
+use crate::io::{UblkDev, UblkQueue};
+use crate::{UblkError, UblkOpError};
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::task::{Poll, Waker};
+
+/// poll user_data of the per-thread control uring
+const POLL_CTRL_KEY: u64 = u64::MAX;
+/// user_data of poll remove command
+const POLL_REMOVE_KEY: u64 = u64::MAX - 1;
+
+#[inline(always)]
+fn queue_poll_key(dev_id: u32, qid: u16) -> u64 {
+    ((dev_id as u64) << 16) | qid as u64
+}
+
+/// Queue state shared by the manager and the queue task
+#[derive(Default)]
+struct UblkManagedQueue {
+    // uring fd, which is set after the queue task creates the queue
+    fd: Cell<Option<RawFd>>,
+    armed: Cell<bool>,
+    done: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+impl UblkManagedQueue {
+    fn wake(&self) {
+        if let Some(w) = self.waker.take() {
+            w.wake();
+        }
+    }
+}
+
+/// One device managed by `UblkDeviceManager`
+///
+/// Each queue is created and owned by its queue task, which holds one
+/// reference of the device. Fields are dropped in declaration order, and
+/// the control device is the last one since DEL_DEV from its drop() waits
+/// until the char device is released.
+struct UblkManagedDev {
+    tasks: Vec<Option<smol::Task<Result<(), UblkError>>>>,
+    queues: Vec<Rc<UblkManagedQueue>>,
+    dev: Rc<UblkDev>,
+    ctrl: Rc<UblkCtrl>,
+}
+
+impl UblkManagedDev {
+    fn is_live(&self) -> bool {
+        self.queues.iter().any(|q| !q.done.get())
+    }
+
+    fn is_idle(&self) -> bool {
+        self.queues.iter().all(|q| !q.armed.get())
+            && self.tasks.iter().flatten().all(|t| t.is_finished())
+    }
+}
+
+/// Serve many ublk devices in single thread
+///
+/// All devices' queues and the per-thread control uring are driven by one
+/// `smol::LocalExecutor`, and one dedicated uring is used for polling all
+/// these urings, so hundreds of small devices can be served without
+/// creating one thread for each queue.
+///
+/// `UblkDeviceManager` isn't `Send`, and has to be used in the thread
+/// creating it, since the control uring is per-thread.
+pub struct UblkDeviceManager {
+    // devices have to be released before the executor
+    devs: HashMap<u32, UblkManagedDev>,
+    poll_ring: IoUring<squeue::Entry>,
+    ctrl_armed: Cell<bool>,
+    exe: smol::LocalExecutor<'static>,
+}
+
+impl UblkDeviceManager {
+    const POLL_RING_DEPTH: u32 = 128;
+
+    pub fn new() -> Result<UblkDeviceManager, UblkError> {
+        Ok(UblkDeviceManager {
+            devs: HashMap::new(),
+            poll_ring: IoUring::builder().build(Self::POLL_RING_DEPTH)?,
+            ctrl_armed: Cell::new(false),
+            exe: smol::LocalExecutor::new(),
+        })
+    }
+
+    /// Return the executor for spawning target's own tasks
+    pub fn executor(&self) -> &smol::LocalExecutor<'static> {
+        &self.exe
+    }
+
+    /// Return IDs of all managed devices
+    pub fn dev_ids(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.devs.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Return control device of `dev_id`
+    pub fn get_ctrl(&self, dev_id: u32) -> Option<Rc<UblkCtrl>> {
+        self.devs.get(&dev_id).map(|d| d.ctrl.clone())
+    }
+
+    /// Return if any queue of `dev_id` is still handling IO
+    pub fn is_live(&self, dev_id: u32) -> bool {
+        self.devs.get(&dev_id).is_some_and(|d| d.is_live())
+    }
+
+    // device's io tasks are finished, and its queues aren't polled
+    fn is_idle(&self, dev_id: u32) -> bool {
+        self.devs.get(&dev_id).into_iter().all(|m| m.is_idle())
+    }
+
+    fn get_dev(&self, dev_id: u32) -> Result<&UblkManagedDev, UblkError> {
+        self.devs.get(&dev_id).ok_or(
+            UblkOpError::new("find_device", -libc::ENOENT)
+                .dev(dev_id)
+                .into(),
+        )
+    }
+
+    /// Add one device
+    ///
+    /// # Arguments:
+    ///
+    /// * `ctrl`: control device built by `UblkCtrlBuilder` in this thread
+    /// * `tgt_fn`: target initialization handler
+    /// * `io_fn`: handler for creating io task of (queue, tag), which is
+    ///   spawned for every tag of each queue
+    ///
+    /// Queues are created and io tasks are spawned, but the device isn't
+    /// started until `start_device()` is called. Returns the device ID.
+    pub fn add_device<T, F>(
+        &mut self,
+        ctrl: UblkCtrl,
+        tgt_fn: T,
+        io_fn: F,
+    ) -> Result<u32, UblkError>
+    where
+        T: FnOnce(&mut UblkDev) -> Result<(), UblkError>,
+        F: for<'q> Fn(&'q UblkQueue<'q>, u16) -> LocalBoxFuture<'q, ()> + Clone + 'static,
+    {
+        let dev = Rc::new(UblkDev::new(ctrl.get_name(), tgt_fn, &ctrl)?);
+        let dev_id = dev.dev_info.dev_id;
+        let mut m = UblkManagedDev {
+            tasks: Vec::new(),
+            queues: Vec::new(),
+            dev,
+            ctrl: Rc::new(ctrl),
+        };
+
+        for qid in 0..m.dev.dev_info.nr_hw_queues {
+            let mq = Rc::new(UblkManagedQueue::default());
+            let task = Self::run_queue(m.dev.clone(), qid, io_fn.clone(), mq.clone());
+
+            m.tasks.push(Some(self.exe.spawn(task)));
+            m.queues.push(mq);
+        }
+
+        // queue is created in the first poll of its task
+        while self.exe.try_tick() {}
+        for t in m.tasks.iter_mut() {
+            if t.as_ref().is_some_and(|t| t.is_finished()) {
+                smol::block_on(t.take().unwrap())?;
+            }
+        }
+        self.devs.insert(dev_id, m);
+
+        Ok(dev_id)
+    }
+
+    /// Create queue `qid`, and run its io tasks until the queue is down
+    async fn run_queue<F>(
+        dev: Rc<UblkDev>,
+        qid: u16,
+        io_fn: F,
+        mq: Rc<UblkManagedQueue>,
+    ) -> Result<(), UblkError>
+    where
+        F: for<'q> Fn(&'q UblkQueue<'q>, u16) -> LocalBoxFuture<'q, ()> + Clone + 'static,
+    {
+        let res = Self::handle_queue(&dev, qid, io_fn, &mq).await;
+
+        mq.done.set(true);
+        res
+    }
+
+    async fn handle_queue<F>(
+        dev: &UblkDev,
+        qid: u16,
+        io_fn: F,
+        mq: &UblkManagedQueue,
+    ) -> Result<(), UblkError>
+    where
+        F: for<'q> Fn(&'q UblkQueue<'q>, u16) -> LocalBoxFuture<'q, ()> + Clone + 'static,
+    {
+        let q = UblkQueue::new(qid, dev)?;
+        mq.fd.set(Some(q.as_raw_fd()));
+
+        let mut ios: FuturesUnordered<_> = (0..dev.dev_info.queue_depth)
+            .map(|tag| io_fn(&q, tag))
+            .collect();
+
+        // run io tasks and flush their SQEs until there isn't any progress,
+        // then wait until the uring becomes readable, see `wait_events()`
+        let res = futures::future::poll_fn(|cx| loop {
+            while let Poll::Ready(Some(_)) = ios.poll_next_unpin(cx) {}
+
+            match q.flush_and_wake_io_tasks(
+                |data, cqe, _| crate::uring_async::ublk_wake_task(data, cqe),
+                0,
+            ) {
+                Ok(0) => {
+                    mq.waker.replace(Some(cx.waker().clone()));
+                    return Poll::Pending;
+                }
+                Ok(_) => {}
+                Err(UblkError::QueueIsDown) => return Poll::Ready(Ok(())),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        })
+        .await;
+
+        q.unregister_io_bufs();
+        res
+    }
+
+    /// Start device `dev_id`
+    ///
+    /// All queues are handled in this thread, then IOs of other devices
+    /// are handled while waiting for the START command.
+    pub fn start_device(&mut self, dev_id: u32) -> Result<i32, UblkError> {
+        let m = self.get_dev(dev_id)?;
+        let tid = unsafe { libc::gettid() };
+
+        for qid in 0..m.queues.len() {
+            m.ctrl.configure_queue(&m.dev, qid as u16, tid)?;
+        }
+
+        let ctrl = m.ctrl.clone();
+        let dev = m.dev.clone();
+        let task = self
+            .exe
+            .spawn(async move { ctrl.start_dev_async(&dev).await });
+        self.run_task(task)
+    }
+
+    /// Stop device `dev_id`, and wait until all its io tasks are done
+    pub fn stop_device(&mut self, dev_id: u32) -> Result<i32, UblkError> {
+        let ctrl = self.get_dev(dev_id)?.ctrl.clone();
+        let task = self.exe.spawn(async move { ctrl.stop_dev_async().await });
+        let res = self.run_task(task)?;
+
+        self.run_until(|mgr| mgr.is_idle(dev_id))?;
+        Ok(res)
+    }
+
+    /// Stop device `dev_id` if it is live, then remove it
+    ///
+    /// All resources of the device are released before sending DEL
+    /// command, so other devices are still served when waiting for the
+    /// device to be released.
+    pub fn remove_device(&mut self, dev_id: u32) -> Result<i32, UblkError> {
+        if self.get_dev(dev_id)?.is_live() {
+            self.stop_device(dev_id)?;
+        }
+        self.run_until(|mgr| mgr.is_idle(dev_id))?;
+
+        let ctrl = match self.devs.remove(&dev_id) {
+            Some(m) => m.ctrl.clone(),
+            None => {
+                return Err(UblkOpError::new("find_device", -libc::ENOENT)
+                    .dev(dev_id)
+                    .into())
+            }
+        };
+        let task = self.exe.spawn(async move { ctrl.del_dev_async().await });
+        self.run_task(task)
+    }
+
+    /// Handle IOs of all devices until every device's queues are down,
+    /// such as all devices are deleted from command line
+    pub fn run(&mut self) -> Result<(), UblkError> {
+        self.run_until(|mgr| mgr.devs.values().all(|m| !m.is_live()))
+    }
+
+    fn run_task<T>(&mut self, task: smol::Task<Result<T, UblkError>>) -> Result<T, UblkError> {
+        self.run_until(|_| task.is_finished())?;
+        smol::block_on(task)
+    }
+
+    fn run_until<F>(&mut self, done: F) -> Result<(), UblkError>
+    where
+        F: Fn(&Self) -> bool,
+    {
+        loop {
+            self.flush_and_wake()?;
+            if done(self) {
+                return Ok(());
+            }
+            self.wait_events()?;
+        }
+    }
+
+    /// Flush queued SQEs of all urings and wake up tasks until there
+    /// isn't any progress
+    fn flush_and_wake(&mut self) -> Result<(), UblkError> {
+        loop {
+            while self.exe.try_tick() {}
+
+            let mut busy = crate::ctrl::ublk_ctrl_flush_and_wake()? > 0;
+            let mut to_cancel = Vec::new();
+            let mut res = Ok(());
+            for (dev_id, m) in self.devs.iter_mut() {
+                for (qid, t) in m.tasks.iter_mut().enumerate() {
+                    if !t.as_ref().is_some_and(|t| t.is_finished()) {
+                        continue;
+                    }
+                    // the queue is released, and its uring is polled
+                    // until the poll is removed
+                    if let Err(e) = smol::block_on(t.take().unwrap()) {
+                        res = res.and(Err(e));
+                    }
+                    if m.queues[qid].armed.get() {
+                        to_cancel.push(queue_poll_key(*dev_id, qid as u16));
+                    }
+                    busy = true;
+                }
+            }
+            res?;
+            for key in to_cancel {
+                let sqe = opcode::PollRemove::new(key)
+                    .build()
+                    .user_data(POLL_REMOVE_KEY);
+                self.push_sqe(&sqe)?;
+            }
+            if !busy {
+                return Ok(());
+            }
+        }
+    }
+
+    fn push_sqe(&mut self, sqe: &squeue::Entry) -> Result<(), UblkError> {
+        loop {
+            if unsafe { self.poll_ring.submission().push(sqe) }.is_ok() {
+                return Ok(());
+            }
+            self.poll_ring.submit()?;
+        }
+    }
+
+    fn poll_sqe(fd: i32, key: u64) -> squeue::Entry {
+        opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as _)
+            .build()
+            .user_data(key)
+    }
+
+    /// Poll all urings, and wait until any one becomes readable
+    fn wait_events(&mut self) -> Result<(), UblkError> {
+        let mut sqes = Vec::new();
+
+        if !self.ctrl_armed.replace(true) {
+            let fd = crate::ctrl::CTRL_URING.with(|refcell| refcell.borrow().as_raw_fd());
+            sqes.push(Self::poll_sqe(fd, POLL_CTRL_KEY));
+        }
+        for (dev_id, m) in self.devs.iter() {
+            for (qid, mq) in m.queues.iter().enumerate() {
+                let fd = match mq.fd.get() {
+                    Some(fd) if !mq.done.get() => fd,
+                    _ => continue,
+                };
+                if !mq.armed.replace(true) {
+                    let key = queue_poll_key(*dev_id, qid as u16);
+                    sqes.push(Self::poll_sqe(fd, key));
+                }
+            }
+        }
+        for sqe in &sqes {
+            self.push_sqe(sqe)?;
+        }
+
+        self.poll_ring.submit_and_wait(1)?;
+        for cqe in self.poll_ring.completion() {
+            let key = cqe.user_data();
+
+            if key == POLL_CTRL_KEY {
+                self.ctrl_armed.set(false);
+            } else if key != POLL_REMOVE_KEY {
+                let mq = self
+                    .devs
+                    .get(&((key >> 16) as u32))
+                    .and_then(|m| m.queues.get((key & 0xffff) as usize));
+                if let Some(mq) = mq {
+                    mq.armed.set(false);
+                    mq.wake();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UblkDeviceManager {
+    fn drop(&mut self) {
+        // release queues by cancelling their tasks, then remove polls on
+        // their urings, which hold the char devices via registered files
+        let mut keys = Vec::new();
+        for (dev_id, m) in self.devs.iter_mut() {
+            m.tasks.clear();
+            for (qid, mq) in m.queues.iter().enumerate() {
+                if mq.armed.replace(false) {
+                    keys.push(queue_poll_key(*dev_id, qid as u16));
+                }
+            }
+        }
+        // future of cancelled task is dropped when the executor runs it
+        while self.exe.try_tick() {}
+        for key in keys.iter() {
+            let sqe = opcode::PollRemove::new(*key)
+                .build()
+                .user_data(POLL_REMOVE_KEY);
+            if let Err(e) = self.push_sqe(&sqe) {
+                log::error!("remove poll {:x} failed {:?}", key, e);
+            }
+        }
+        // each removal posts CQEs of the poll and the remove command
+        if let Err(e) = self.poll_ring.submit_and_wait(keys.len() * 2) {
+            log::error!("wait poll removal failed {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctrl::UblkCtrlBuilder;
+    use crate::io::UblkQueue;
+    use crate::manager::UblkDeviceManager;
+    use crate::{sys, UblkFlags};
+    use std::path::Path;
+
+    async fn null_io_task(q: &UblkQueue<'_>, tag: u16) {
+        let mut cmd_op = sys::UBLK_U_IO_FETCH_REQ;
+        let mut res = 0;
+
+        loop {
+            let cmd_res = q
+                .submit_io_cmd(tag, cmd_op, std::ptr::null_mut(), res)
+                .await;
+            if cmd_res == sys::UBLK_IO_RES_ABORT {
+                break;
+            }
+
+            let iod = q.get_iod(tag);
+            res = (iod.nr_sectors << 9) as i32;
+            cmd_op = sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ;
+        }
+    }
+
+    #[test]
+    fn test_ublk_device_manager() {
+        let mut mgr = UblkDeviceManager::new().unwrap();
+        let mut ids = Vec::new();
+
+        for _ in 0..4 {
+            let ctrl = UblkCtrlBuilder::default()
+                .name("null")
+                .nr_queues(2_u16)
+                .depth(16_u16)
+                .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+                .ctrl_flags(sys::UBLK_F_USER_COPY as u64)
+                .build()
+                .unwrap();
+            let id = mgr
+                .add_device(
+                    ctrl,
+                    |dev| {
+                        dev.set_default_params(1_u64 << 30);
+                        Ok(())
+                    },
+                    |q, tag| Box::pin(null_io_task(q, tag)),
+                )
+                .unwrap();
+            ids.push(id);
+        }
+        assert_eq!(mgr.dev_ids().len(), 4);
+
+        for id in &ids {
+            mgr.start_device(*id).unwrap();
+
+            let ctrl = mgr.get_ctrl(*id).unwrap();
+            ctrl.read_dev_info().unwrap();
+            assert!(ctrl.dev_info().state == sys::UBLK_S_DEV_LIVE as u16);
+            assert!(mgr.is_live(*id));
+        }
+
+        // stopping one device doesn't affect others
+        mgr.stop_device(ids[0]).unwrap();
+        assert!(!mgr.is_live(ids[0]));
+        assert!(mgr.is_live(ids[1]));
+
+        for id in &ids {
+            let cdev = mgr.get_ctrl(*id).unwrap().get_cdev_path();
+
+            mgr.remove_device(*id).unwrap();
+            assert!(!Path::new(&cdev).exists());
+        }
+        assert!(mgr.dev_ids().is_empty());
+    }
+}
@@ -0,0 +1,1380 @@
+//! In-process mock of ublk driver
+//!
+//! `UblkMockDriver` implements control commands(ADD/DEL/START/STOP/
+//! SET_PARAMS/GET_*, user recovery) and io commands(FETCH/COMMIT_AND_FETCH/
+//! NEED_GET_DATA) without `/dev/ublk-control`, so target code can be covered
+//! end-to-end in unit tests without root and ublk_drv.
+//!
+//! Char device is emulated by one memfd, and io descriptors are written to
+//! it by `UblkMockDriver::queue_io()`, then `UblkQueue` can read them from
+//! its mapping. Command completions are posted to the issuer's io_uring via
+//! `IORING_OP_MSG_RING`, so the control and queue urings are driven exactly
+//! as with the kernel driver.
+//!
+//! `UblkQueueHarness` drives one `UblkQueue` step by step in the caller's
+//! context, for deterministic tests of IO closures and async io tasks.
+//!
+//! Only available for unit test or with feature `mock`.
+
+use crate::driver::{set_driver, UblkDriver};
+use crate::io::{UblkIOCtx, UblkQueue};
+use crate::uring_async::ublk_wake_task;
+use crate::{sys, UblkError, UblkOpError};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Issued command, whose CQE is posted to uring `ring_fd` with `user_data`
+#[derive(Debug, Clone, Copy)]
+struct MockCmd {
+    ring_fd: RawFd,
+    user_data: u64,
+}
+
+#[derive(Default)]
+struct MockTag {
+    /// FETCH or COMMIT_AND_FETCH command waiting for incoming IO
+    fetch: Option<MockCmd>,
+    /// IO is queued before this tag is fetched
+    queued: bool,
+    /// IO is being handled by ublk server
+    inflight: bool,
+    /// waiting for UBLK_IO_NEED_GET_DATA
+    need_data: bool,
+    /// committed results
+    results: VecDeque<i32>,
+}
+
+struct MockDev {
+    info: sys::ublksrv_ctrl_dev_info,
+    params: Option<sys::ublk_params>,
+    cdev: fs::File,
+    tags: Vec<Vec<MockTag>>,
+    /// START_DEV or END_USER_RECOVERY waiting for all tags fetched
+    start: Option<MockCmd>,
+    recovering: bool,
+    aborting: bool,
+    /// how many times the char device is opened by `UblkDev`
+    cdev_users: u32,
+}
+
+impl MockDev {
+    /// live device becomes quiesced if user recovery is enabled after
+    /// the daemon is gone, otherwise it is stopped
+    fn daemon_gone(&mut self) {
+        if self.info.state == sys::UBLK_S_DEV_LIVE as u16 {
+            self.info.state = if self.info.flags & sys::UBLK_F_USER_RECOVERY as u64 != 0 {
+                sys::UBLK_S_DEV_QUIESCED
+            } else {
+                sys::UBLK_S_DEV_DEAD
+            } as u16;
+        }
+    }
+
+    fn all_fetched(&self) -> bool {
+        self.tags.iter().flatten().all(|t| t.fetch.is_some())
+    }
+
+    fn reset_tags(&mut self) {
+        for t in self.tags.iter_mut().flatten() {
+            t.fetch = None;
+            t.queued = false;
+            t.inflight = false;
+            t.need_data = false;
+        }
+    }
+
+    fn tag_mut(&mut self, qid: u16, tag: u16) -> Option<&mut MockTag> {
+        self.tags
+            .get_mut(qid as usize)
+            .and_then(|q| q.get_mut(tag as usize))
+    }
+
+    fn write_io_desc(&self, qid: u16, tag: u16, iod: &sys::ublksrv_io_desc) -> std::io::Result<()> {
+        let buf = unsafe {
+            std::slice::from_raw_parts(
+                iod as *const sys::ublksrv_io_desc as *const u8,
+                core::mem::size_of::<sys::ublksrv_io_desc>(),
+            )
+        };
+        self.cdev.write_all_at(buf, io_desc_off(qid, tag))
+    }
+}
+
+fn memfd(name: &str, size: u64) -> std::io::Result<fs::File> {
+    let name = std::ffi::CString::new(name).unwrap();
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+    file.set_len(size)?;
+    Ok(file)
+}
+
+/// offset of io descriptor (qid, tag) in char device
+fn io_desc_off(qid: u16, tag: u16) -> u64 {
+    sys::UBLKSRV_CMD_BUF_OFFSET as u64
+        + (qid as u64 * sys::UBLK_MAX_QUEUE_DEPTH as u64 + tag as u64)
+            * core::mem::size_of::<sys::ublksrv_io_desc>() as u64
+}
+
+/// Copy command buffer to `T`
+///
+/// # Safety
+///
+/// `addr` has to point to one valid buffer of `len` bytes
+unsafe fn read_cmd_buf<T: Copy + Default>(addr: u64, len: usize) -> T {
+    let mut val = T::default();
+    let len = len.min(core::mem::size_of::<T>());
+
+    std::ptr::copy_nonoverlapping(addr as *const u8, &mut val as *mut T as *mut u8, len);
+    val
+}
+
+/// Copy `val` to command buffer
+///
+/// # Safety
+///
+/// `addr` has to point to one valid buffer of `len` bytes
+unsafe fn write_cmd_buf<T: Copy>(addr: u64, len: usize, val: &T) {
+    let len = len.min(core::mem::size_of::<T>());
+
+    std::ptr::copy_nonoverlapping(val as *const T as *const u8, addr as *mut u8, len);
+}
+
+/// Mock ublk driver
+///
+/// Installed for the current thread by `UblkMockDriver::install()`, then
+/// control devices created in this thread, and their `UblkDev` and
+/// `UblkQueue`, are served by this driver.
+///
+/// ```no_run
+/// use libublk::mock::UblkMockDriver;
+/// use std::sync::Arc;
+///
+/// let drv = Arc::new(UblkMockDriver::new().unwrap());
+/// let _guard = drv.install();
+/// // build UblkCtrl and run target as usual, then inject IO via
+/// // drv.queue_io() and check result via drv.wait_io_result()
+/// ```
+pub struct UblkMockDriver {
+    features: u64,
+    devs: Mutex<BTreeMap<u32, MockDev>>,
+    committed: Condvar,
+
+    /// for posting CQEs to command issuer's uring
+    ring: Mutex<IoUring>,
+}
+
+/// Restore the previous driver when dropped
+pub struct UblkMockGuard {
+    old: Option<Arc<dyn UblkDriver>>,
+}
+
+impl Drop for UblkMockGuard {
+    fn drop(&mut self) {
+        set_driver(self.old.take());
+    }
+}
+
+impl UblkMockDriver {
+    /// Features supported by mock driver
+    pub const FEATURES: u64 = (sys::UBLK_F_URING_CMD_COMP_IN_TASK
+        | sys::UBLK_F_NEED_GET_DATA
+        | sys::UBLK_F_USER_RECOVERY
+        | sys::UBLK_F_USER_RECOVERY_REISSUE
+        | sys::UBLK_F_UNPRIVILEGED_DEV
+        | sys::UBLK_F_CMD_IOCTL_ENCODE
+        | sys::UBLK_F_USER_COPY
+        | sys::UBLK_F_USER_RECOVERY_FAIL_IO) as u64;
+
+    pub fn new() -> Result<UblkMockDriver, UblkError> {
+        Self::with_features(Self::FEATURES)
+    }
+
+    /// Create mock driver which only supports `features`, such as
+    /// emulating old kernel
+    pub fn with_features(features: u64) -> Result<UblkMockDriver, UblkError> {
+        Ok(UblkMockDriver {
+            features,
+            devs: Mutex::new(BTreeMap::new()),
+            committed: Condvar::new(),
+            ring: Mutex::new(IoUring::new(32)?),
+        })
+    }
+
+    /// Serve control devices created in current thread by this driver
+    /// until the returned guard is dropped
+    pub fn install(self: &Arc<Self>) -> UblkMockGuard {
+        UblkMockGuard {
+            old: set_driver(Some(self.clone())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, MockDev>> {
+        self.devs.lock().unwrap()
+    }
+
+    /// Post one CQE with `res` to the command issuer
+    fn post(&self, cmd: MockCmd, res: i32) -> Result<(), UblkError> {
+        let sqe =
+            opcode::MsgRingData::new(types::Fd(cmd.ring_fd), res, cmd.user_data, None).build();
+        let mut r = self.ring.lock().unwrap();
+
+        // only one SQE is in-flight, since its CQE is reaped here
+        unsafe { r.submission().push(&sqe) }.expect("mock uring is full");
+        r.submit_and_wait(1)
+            .map_err(|e| UblkOpError::from_io("post_cqe", &e))?;
+        let res = r.completion().next().map_or(0, |cqe| cqe.result());
+        if res < 0 {
+            return Err(UblkOpError::new("post_cqe", res).into());
+        }
+        Ok(())
+    }
+
+    /// Post one CQE to the issuer whose uring has to be live, and the
+    /// mock is used wrongly if it fails
+    fn post_live(&self, cmd: MockCmd, res: i32) {
+        if let Err(e) = self.post(cmd, res) {
+            panic!(
+                "mock: post cqe {:x} to uring {} failed: {}",
+                cmd.user_data, cmd.ring_fd, e
+            );
+        }
+    }
+
+    /// Complete pending START_DEV or END_USER_RECOVERY if all tags are
+    /// fetched
+    fn try_start(&self, dev: &mut MockDev) {
+        if dev.start.is_none() || !dev.all_fetched() {
+            return;
+        }
+
+        dev.info.state = sys::UBLK_S_DEV_LIVE as u16;
+        dev.recovering = false;
+        self.post_live(dev.start.take().unwrap(), 0);
+    }
+
+    /// Abort all fetched io commands, and fail the following ones
+    fn abort(&self, dev: &mut MockDev) {
+        dev.aborting = true;
+        if let Some(cmd) = dev.start.take() {
+            self.post_live(cmd, -libc::ENODEV);
+        }
+        for t in dev.tags.iter_mut().flatten() {
+            if let Some(cmd) = t.fetch.take() {
+                self.post_live(cmd, sys::UBLK_IO_RES_ABORT);
+            }
+            t.queued = false;
+            t.inflight = false;
+            t.need_data = false;
+        }
+    }
+
+    fn add_dev(&self, devs: &mut BTreeMap<u32, MockDev>, addr: u64, len: usize) -> i32 {
+        if len < core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() {
+            return -libc::EINVAL;
+        }
+
+        let mut info: sys::ublksrv_ctrl_dev_info = unsafe { read_cmd_buf(addr, len) };
+        let nr_queues = info.nr_hw_queues as u32;
+        let depth = info.queue_depth as u32;
+
+        if nr_queues == 0 || nr_queues > sys::UBLK_MAX_NR_QUEUES {
+            return -libc::EINVAL;
+        }
+        if depth == 0 || depth > sys::UBLK_MAX_QUEUE_DEPTH {
+            return -libc::EINVAL;
+        }
+
+        if info.dev_id == u32::MAX {
+            info.dev_id = (0..).find(|id| !devs.contains_key(id)).unwrap();
+        } else if devs.contains_key(&info.dev_id) {
+            return -libc::EEXIST;
+        }
+
+        let cdev = match memfd(
+            &format!("ublkc{}", info.dev_id),
+            io_desc_off(info.nr_hw_queues, 0),
+        ) {
+            Ok(f) => f,
+            Err(e) => return -e.raw_os_error().unwrap_or(libc::ENOMEM),
+        };
+
+        info.flags &= self.features;
+        info.state = sys::UBLK_S_DEV_DEAD as u16;
+        info.owner_uid = unsafe { libc::getuid() };
+        info.owner_gid = unsafe { libc::getgid() };
+        unsafe { write_cmd_buf(addr, len, &info) };
+
+        devs.insert(
+            info.dev_id,
+            MockDev {
+                info,
+                params: None,
+                cdev,
+                tags: (0..nr_queues)
+                    .map(|_| (0..depth).map(|_| MockTag::default()).collect())
+                    .collect(),
+                start: None,
+                recovering: false,
+                aborting: false,
+                cdev_users: 0,
+            },
+        );
+        0
+    }
+
+    fn get_queue_affinity(dev: &MockDev, qid: u64, addr: u64, len: usize) -> i32 {
+        let nr_queues = dev.info.nr_hw_queues as usize;
+        let nr_cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) }.max(1) as usize;
+
+        if qid >= nr_queues as u64 {
+            return -libc::EINVAL;
+        }
+
+        // map cpus to queues in round robin
+        let qid = qid as usize;
+        let mut cpus: Vec<usize> = (0..nr_cpus).filter(|c| c % nr_queues == qid).collect();
+        if cpus.is_empty() {
+            cpus.push(qid % nr_cpus);
+        }
+
+        let mut bits = vec![0_u8; len];
+        for cpu in cpus.into_iter().filter(|c| c / 8 < len) {
+            bits[cpu / 8] |= 1 << (cpu % 8);
+        }
+        unsafe { std::ptr::copy_nonoverlapping(bits.as_ptr(), addr as *mut u8, len) };
+        0
+    }
+
+    /// Handle one control command, and None is returned if the command
+    /// is completed later
+    fn handle_ctrl_cmd(&self, cmd: MockCmd, op: u32, c: &sys::ublksrv_ctrl_cmd) -> Option<i32> {
+        // dev_path is attached in buffer head for unprivileged device
+        let addr = c.addr + c.dev_path_len as u64;
+        let len = c.len.saturating_sub(c.dev_path_len) as usize;
+        let mut devs = self.lock();
+
+        if op == sys::UBLK_CMD_ADD_DEV {
+            return Some(self.add_dev(&mut devs, addr, len));
+        }
+        if op == sys::UBLK_U_CMD_GET_FEATURES & 0xff {
+            unsafe { write_cmd_buf(addr, len, &self.features) };
+            return Some(0);
+        }
+
+        let dev = match devs.get_mut(&c.dev_id) {
+            Some(dev) => dev,
+            None => return Some(-libc::ENODEV),
+        };
+        let res = match op {
+            sys::UBLK_CMD_GET_DEV_INFO | sys::UBLK_CMD_GET_DEV_INFO2 => {
+                unsafe { write_cmd_buf(addr, len, &dev.info) };
+                0
+            }
+            sys::UBLK_CMD_GET_QUEUE_AFFINITY => Self::get_queue_affinity(dev, c.data[0], addr, len),
+            sys::UBLK_CMD_SET_PARAMS => {
+                if dev.info.state == sys::UBLK_S_DEV_LIVE as u16 {
+                    -libc::EACCES
+                } else {
+                    dev.params = Some(unsafe { read_cmd_buf(addr, len) });
+                    0
+                }
+            }
+            sys::UBLK_CMD_GET_PARAMS => match &dev.params {
+                Some(p) => {
+                    unsafe { write_cmd_buf(addr, len, p) };
+                    0
+                }
+                None => -libc::EINVAL,
+            },
+            sys::UBLK_CMD_START_DEV => {
+                if dev.info.state != sys::UBLK_S_DEV_DEAD as u16 || dev.start.is_some() {
+                    -libc::EEXIST
+                } else if dev.params.is_none() {
+                    -libc::EINVAL
+                } else {
+                    dev.info.ublksrv_pid = c.data[0] as i32;
+                    dev.start = Some(cmd);
+                    self.try_start(dev);
+                    return None;
+                }
+            }
+            sys::UBLK_CMD_STOP_DEV => {
+                dev.info.state = sys::UBLK_S_DEV_DEAD as u16;
+                self.abort(dev);
+                0
+            }
+            sys::UBLK_CMD_START_USER_RECOVERY => {
+                if dev.info.flags & sys::UBLK_F_USER_RECOVERY as u64 == 0 {
+                    -libc::EINVAL
+                } else if dev.info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+                    -libc::EBUSY
+                } else {
+                    dev.reset_tags();
+                    dev.recovering = true;
+                    dev.aborting = false;
+                    0
+                }
+            }
+            sys::UBLK_CMD_END_USER_RECOVERY => {
+                if !dev.recovering || dev.start.is_some() {
+                    -libc::EINVAL
+                } else {
+                    dev.info.ublksrv_pid = c.data[0] as i32;
+                    dev.start = Some(cmd);
+                    self.try_start(dev);
+                    return None;
+                }
+            }
+            _ if op == sys::UBLK_CMD_DEL_DEV || op == sys::UBLK_U_CMD_DEL_DEV_ASYNC & 0xff => {
+                // ublk driver completes DEL_DEV after the char device is released
+                assert!(
+                    op != sys::UBLK_CMD_DEL_DEV || dev.cdev_users == 0,
+                    "mock: DEL_DEV of device {} hangs since its char device is open",
+                    c.dev_id
+                );
+                self.abort(dev);
+                devs.remove(&c.dev_id);
+                0
+            }
+            _ => -libc::EINVAL,
+        };
+        Some(res)
+    }
+
+    /// Handle one io command, and None is returned if the command is
+    /// completed later
+    fn handle_io_cmd(
+        &self,
+        cmd: MockCmd,
+        dev_id: u32,
+        op: u32,
+        c: &sys::ublksrv_io_cmd,
+    ) -> Option<i32> {
+        let mut devs = self.lock();
+        let dev = match devs.get_mut(&dev_id) {
+            Some(dev) if !dev.aborting => dev,
+            _ => return Some(sys::UBLK_IO_RES_ABORT),
+        };
+        let t = match dev
+            .tags
+            .get_mut(c.q_id as usize)
+            .and_then(|q| q.get_mut(c.tag as usize))
+        {
+            Some(t) => t,
+            None => return Some(-libc::EINVAL),
+        };
+
+        match op {
+            sys::UBLK_IO_FETCH_REQ if t.fetch.is_some() || t.inflight || t.need_data => {
+                return Some(-libc::EBUSY);
+            }
+            sys::UBLK_IO_FETCH_REQ => {}
+            sys::UBLK_IO_COMMIT_AND_FETCH_REQ if !t.inflight => return Some(-libc::EINVAL),
+            sys::UBLK_IO_COMMIT_AND_FETCH_REQ => {
+                t.inflight = false;
+                t.results.push_back(c.result);
+                self.committed.notify_all();
+            }
+            sys::UBLK_IO_NEED_GET_DATA if !t.need_data => return Some(-libc::EINVAL),
+            sys::UBLK_IO_NEED_GET_DATA => {
+                // WRITE data is supposed to be copied to `c.addr`
+                t.need_data = false;
+                t.inflight = true;
+                return Some(sys::UBLK_IO_RES_OK as i32);
+            }
+            _ => return Some(-libc::EINVAL),
+        }
+
+        if std::mem::take(&mut t.queued) {
+            t.inflight = true;
+            self.post_live(cmd, sys::UBLK_IO_RES_OK as i32);
+        } else {
+            t.fetch = Some(cmd);
+        }
+        self.try_start(dev);
+        None
+    }
+
+    /// Return IDs of all devices
+    pub fn dev_ids(&self) -> Vec<u32> {
+        self.lock().keys().copied().collect()
+    }
+
+    /// Return device info of `dev_id`
+    pub fn dev_info(&self, dev_id: u32) -> Option<sys::ublksrv_ctrl_dev_info> {
+        self.lock().get(&dev_id).map(|d| d.info)
+    }
+
+    /// Queue one IO to (`qid`, `tag`) of device `dev_id`
+    ///
+    /// `iod` is written to the io descriptor buffer, and the IO is
+    /// delivered to ublk server once this tag is fetched.
+    pub fn queue_io(
+        &self,
+        dev_id: u32,
+        qid: u16,
+        tag: u16,
+        iod: &sys::ublksrv_io_desc,
+    ) -> Result<(), UblkError> {
+        let err = |errno| {
+            UblkOpError::new("queue_io", errno)
+                .dev(dev_id)
+                .queue(qid)
+                .tag(tag)
+        };
+        let mut devs = self.lock();
+        let dev = devs.get_mut(&dev_id).ok_or(err(-libc::ENODEV))?;
+
+        if dev.info.state != sys::UBLK_S_DEV_LIVE as u16 {
+            return Err(err(-libc::EBUSY).into());
+        }
+
+        let t = dev.tag_mut(qid, tag).ok_or(err(-libc::EINVAL))?;
+        if t.queued || t.inflight || t.need_data {
+            return Err(err(-libc::EBUSY).into());
+        }
+
+        dev.write_io_desc(qid, tag, iod)?;
+        let t = dev.tag_mut(qid, tag).ok_or(err(-libc::EINVAL))?;
+        match t.fetch.take() {
+            Some(cmd) => {
+                t.inflight = true;
+                self.post(cmd, sys::UBLK_IO_RES_OK as i32)?;
+            }
+            None => t.queued = true,
+        }
+        Ok(())
+    }
+
+    /// Complete the fetch command of (`qid`, `tag`) of device `dev_id`
+    /// with driver result `res`
+    ///
+    /// Covers results other than incoming IO, such as `UBLK_IO_RES_ABORT`
+    /// or `UBLK_IO_RES_NEED_GET_DATA`, which has to be followed by
+    /// UBLK_IO_NEED_GET_DATA command. `iod` is written to the io descriptor
+    /// buffer if it is provided.
+    pub fn complete_io_cmd(
+        &self,
+        dev_id: u32,
+        qid: u16,
+        tag: u16,
+        iod: Option<&sys::ublksrv_io_desc>,
+        res: i32,
+    ) -> Result<(), UblkError> {
+        let err = |errno| {
+            UblkOpError::new("complete_io_cmd", errno)
+                .dev(dev_id)
+                .queue(qid)
+                .tag(tag)
+        };
+        let mut devs = self.lock();
+        let dev = devs.get_mut(&dev_id).ok_or(err(-libc::ENODEV))?;
+
+        if let Some(iod) = iod {
+            dev.write_io_desc(qid, tag, iod)?;
+        }
+
+        let t = dev.tag_mut(qid, tag).ok_or(err(-libc::EINVAL))?;
+        let cmd = t.fetch.take().ok_or(err(-libc::EBUSY))?;
+        if res == sys::UBLK_IO_RES_OK as i32 {
+            t.inflight = true;
+        } else if res == sys::UBLK_IO_RES_NEED_GET_DATA as i32 {
+            t.need_data = true;
+        }
+        self.post(cmd, res)
+    }
+
+    /// Take all committed results of (`qid`, `tag`) of device `dev_id`,
+    /// in commit order
+    pub fn take_io_results(&self, dev_id: u32, qid: u16, tag: u16) -> Vec<i32> {
+        match self
+            .lock()
+            .get_mut(&dev_id)
+            .and_then(|d| d.tag_mut(qid, tag))
+        {
+            Some(t) => t.results.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Wait for the committed result of (`qid`, `tag`) of device `dev_id`
+    ///
+    /// None is returned if no result is committed in `timeout`.
+    pub fn wait_io_result(
+        &self,
+        dev_id: u32,
+        qid: u16,
+        tag: u16,
+        timeout: std::time::Duration,
+    ) -> Option<i32> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut devs = self.lock();
+
+        loop {
+            let res = devs
+                .get_mut(&dev_id)
+                .and_then(|d| d.tags.get_mut(qid as usize))
+                .and_then(|q| q.get_mut(tag as usize))
+                .and_then(|t| t.results.pop_front());
+            if res.is_some() {
+                return res;
+            }
+
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+            devs = self.committed.wait_timeout(devs, left).unwrap().0;
+        }
+    }
+
+    /// Emulate exit of ublk server of device `dev_id`
+    ///
+    /// Live device becomes quiesced if user recovery is enabled, otherwise
+    /// it is stopped, and all fetched io commands are aborted.
+    pub fn exit_daemon(&self, dev_id: u32) -> Result<(), UblkError> {
+        let mut devs = self.lock();
+        let dev = devs
+            .get_mut(&dev_id)
+            .ok_or(UblkOpError::new("exit_daemon", -libc::ENODEV).dev(dev_id))?;
+
+        dev.daemon_gone();
+        self.abort(dev);
+        Ok(())
+    }
+}
+
+impl UblkDriver for UblkMockDriver {
+    fn open_ctrl(&self) -> Result<fs::File, UblkError> {
+        Ok(memfd("ublk-control", 0)?)
+    }
+
+    fn open_cdev(
+        &self,
+        dev_id: u32,
+        _path: &str,
+        _timeout: std::time::Duration,
+    ) -> Result<fs::File, UblkError> {
+        let res = match self.lock().get_mut(&dev_id) {
+            Some(dev) => dev.cdev.try_clone().inspect(|_| dev.cdev_users += 1),
+            None => Err(std::io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        res.map_err(|e| UblkOpError::from_io("open_cdev", &e).dev(dev_id).into())
+    }
+
+    fn wait_cdev(
+        &self,
+        dev_id: u32,
+        _path: &str,
+        _timeout: std::time::Duration,
+    ) -> Result<(), UblkError> {
+        match self.lock().get(&dev_id) {
+            Some(_) => Ok(()),
+            None => Err(UblkOpError::new("wait_cdev", -libc::ENOENT)
+                .dev(dev_id)
+                .into()),
+        }
+    }
+
+    fn open_bdev(
+        &self,
+        dev_id: u32,
+        _path: &str,
+        _devt: Option<(u32, u32)>,
+        _timeout: std::time::Duration,
+    ) -> Result<fs::File, UblkError> {
+        // no block node, so return char device of the live device instead
+        let res = match self.lock().get(&dev_id) {
+            Some(dev) if dev.info.state == sys::UBLK_S_DEV_LIVE as u16 => dev.cdev.try_clone(),
+            _ => Err(std::io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        res.map_err(|e| UblkOpError::from_io("open_bdev", &e).dev(dev_id).into())
+    }
+
+    fn ctrl_cmd(
+        &self,
+        ring_fd: RawFd,
+        _fd: RawFd,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry128> {
+        let c = MockCmd { ring_fd, user_data };
+
+        if let Some(res) = self.handle_ctrl_cmd(c, cmd_op & 0xff, cmd) {
+            self.post_live(c, res);
+        }
+        None
+    }
+
+    fn io_cmd(
+        &self,
+        ring_fd: RawFd,
+        dev_id: u32,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Option<squeue::Entry> {
+        let c = MockCmd { ring_fd, user_data };
+
+        if let Some(res) = self.handle_io_cmd(c, dev_id, cmd_op & 0xff, cmd) {
+            self.post_live(c, res);
+        }
+        None
+    }
+
+    fn release_queue(&self, dev_id: u32, q_id: u16) {
+        // io commands are cancelled with the uring, so never post them
+        if let Some(q) = self
+            .lock()
+            .get_mut(&dev_id)
+            .and_then(|dev| dev.tags.get_mut(q_id as usize))
+        {
+            for t in q.iter_mut() {
+                t.fetch = None;
+                t.inflight = false;
+                t.need_data = false;
+            }
+        }
+    }
+
+    fn release_cdev(&self, dev_id: u32) {
+        if let Some(dev) = self.lock().get_mut(&dev_id) {
+            dev.cdev_users -= 1;
+            dev.daemon_gone();
+            self.abort(dev);
+        }
+    }
+}
+
+/// IO path harness of one `UblkQueue`
+///
+/// Synthetic io descriptors and CQEs are injected to the queue, and
+/// handled by `process_io()` or `process_io_tasks()` in current context
+/// without blocking, then committed results can be checked per tag.
+///
+/// ```no_run
+/// use libublk::io::{UblkDev, UblkQueue};
+/// use libublk::mock::{UblkMockDriver, UblkQueueHarness};
+///
+/// fn test_io(drv: &UblkMockDriver, dev: &UblkDev) {
+///     let q = UblkQueue::new(0, dev).unwrap().submit_fetch_commands(None);
+///     // start device, then inject IO
+///     let h = UblkQueueHarness::new(drv, &q);
+///     h.queue_io(0, &Default::default()).unwrap();
+///     h.process_io(|q, tag, _io| {
+///         q.complete_io_cmd(tag, std::ptr::null_mut(), Ok(libublk::UblkIORes::Result(0)))
+///     })
+///     .unwrap();
+///     assert_eq!(h.take_io_results(0), vec![0]);
+/// }
+/// ```
+pub struct UblkQueueHarness<'a> {
+    drv: &'a UblkMockDriver,
+    q: &'a UblkQueue<'a>,
+}
+
+impl<'a> UblkQueueHarness<'a> {
+    /// `q` has to be created with `drv` installed
+    pub fn new(drv: &'a UblkMockDriver, q: &'a UblkQueue<'a>) -> Self {
+        UblkQueueHarness { drv, q }
+    }
+
+    fn dev_id(&self) -> u32 {
+        self.q.dev.dev_info.dev_id
+    }
+
+    /// Deliver one IO to `tag`, see `UblkMockDriver::queue_io()`
+    pub fn queue_io(&self, tag: u16, iod: &sys::ublksrv_io_desc) -> Result<(), UblkError> {
+        self.drv.queue_io(self.dev_id(), self.q.get_qid(), tag, iod)
+    }
+
+    /// Complete fetch command of `tag` with `res`, see
+    /// `UblkMockDriver::complete_io_cmd()`
+    pub fn complete_io_cmd(
+        &self,
+        tag: u16,
+        iod: Option<&sys::ublksrv_io_desc>,
+        res: i32,
+    ) -> Result<(), UblkError> {
+        self.drv
+            .complete_io_cmd(self.dev_id(), self.q.get_qid(), tag, iod, res)
+    }
+
+    /// Post one CQE of `user_data` with `res` to queue uring
+    ///
+    /// Target IO completion can be injected in any order, and `user_data`
+    /// is often built by `UblkIOCtx::build_user_data()`, or taken from
+    /// `UblkUringOpFuture` for async io tasks.
+    pub fn post_cqe(&self, user_data: u64, res: i32) -> Result<(), UblkError> {
+        let cmd = MockCmd {
+            ring_fd: self.q.as_raw_fd(),
+            user_data,
+        };
+        self.drv.post(cmd, res)
+    }
+
+    /// Handle all available CQEs by IO closure `ops` until no more comes,
+    /// and return how many CQEs are handled
+    ///
+    /// `UblkError::QueueIsDown` is returned after queue is aborted.
+    pub fn process_io<F>(&self, mut ops: F) -> Result<usize, UblkError>
+    where
+        F: FnMut(&UblkQueue, u16, &UblkIOCtx),
+    {
+        let mut done = 0;
+        loop {
+            match self.q.process_ios(&mut ops, 0)? {
+                0 => return Ok(done),
+                nr => done += nr as usize,
+            }
+        }
+    }
+
+    /// Run io tasks of `exe` and wake them up by available CQEs until no
+    /// more comes, and return how many CQEs are handled
+    ///
+    /// `UblkError::QueueIsDown` is returned after queue is aborted.
+    pub fn process_io_tasks(&self, exe: &smol::LocalExecutor) -> Result<usize, UblkError> {
+        let mut done = 0;
+        loop {
+            while exe.try_tick() {}
+            match self
+                .q
+                .flush_and_wake_io_tasks(|data, cqe, _| ublk_wake_task(data, cqe), 0)?
+            {
+                0 => return Ok(done),
+                nr => done += nr as usize,
+            }
+        }
+    }
+
+    /// Emulate timeout of waiting for queue uring, so queue may become idle
+    pub fn idle_timeout(&self) {
+        self.q.enter_queue_idle();
+    }
+
+    /// Take all committed results of `tag` in commit order
+    pub fn take_io_results(&self, tag: u16) -> Vec<i32> {
+        self.drv
+            .take_io_results(self.dev_id(), self.q.get_qid(), tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctrl::{UblkCtrl, UblkCtrlBuilder, UblkDevState, UblkRecoveryMode};
+    use crate::io::{UblkDev, UblkIOCtx, UblkQueue};
+    use crate::manager::UblkDeviceManager;
+    use crate::mock::{UblkMockDriver, UblkQueueHarness};
+    use crate::uring_async::UblkUringOpFuture;
+    use crate::{sys, UblkError, UblkFlags, UblkIORes, UblkOpError};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn mock_ctrl(run_dir: &str, nr_queues: u16, recovery_mode: UblkRecoveryMode) -> UblkCtrl {
+        UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(nr_queues)
+            .depth(8_u16)
+            .ctrl_flags(sys::UBLK_F_USER_COPY as u64)
+            .recovery_mode(recovery_mode)
+            .run_dir(run_dir)
+            .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
+            .build()
+            .unwrap()
+    }
+
+    fn tgt_init(dev: &mut UblkDev) -> Result<(), UblkError> {
+        dev.set_default_params(1_u64 << 30);
+        Ok(())
+    }
+
+    fn io_desc(op: u32, nr_sectors: u32) -> sys::ublksrv_io_desc {
+        sys::ublksrv_io_desc {
+            op_flags: op,
+            nr_sectors,
+            ..Default::default()
+        }
+    }
+
+    fn null_q_fn(qid: u16, dev: &UblkDev) {
+        let io_handler = move |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
+            let iod = q.get_iod(tag);
+            let res = match iod.op_flags & 0xff {
+                sys::UBLK_IO_OP_READ | sys::UBLK_IO_OP_WRITE => (iod.nr_sectors << 9) as i32,
+                _ => -libc::EINVAL,
+            };
+
+            q.complete_io_cmd(tag, std::ptr::null_mut(), Ok(UblkIORes::Result(res)));
+        };
+
+        UblkQueue::new(qid, dev)
+            .unwrap()
+            .submit_fetch_commands(None)
+            .wait_and_handle_io(io_handler);
+    }
+
+    #[test]
+    fn test_mock_ctrl_cmds() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+
+        let run_dir = dir.path().join("run");
+        let ctrl = mock_ctrl(run_dir.to_str().unwrap(), 2, UblkRecoveryMode::Disabled);
+        let id = ctrl.dev_info().dev_id;
+        assert_eq!(drv.dev_ids(), vec![id]);
+
+        // nothing is created in run dir until json is flushed
+        assert!(!run_dir.exists());
+        assert_eq!(ctrl.get_driver_features(), Some(UblkMockDriver::FEATURES));
+        assert_eq!(ctrl.state(), UblkDevState::Dead);
+
+        let mut p = crate::io::UblkParamsBuilder::default()
+            .dev_size(1_u64 << 20)
+            .build(&ctrl.dev_info())
+            .unwrap();
+        ctrl.set_params(&p).unwrap();
+        let dev_sectors = p.basic.dev_sectors;
+        p.basic.dev_sectors = 0;
+        ctrl.get_params(&mut p).unwrap();
+        assert_eq!(p.basic.dev_sectors, dev_sectors);
+
+        let mut affinity = crate::ctrl::UblkQueueAffinity::new();
+        ctrl.get_queue_affinity(1, &mut affinity).unwrap();
+        assert!(!affinity.is_empty());
+        assert!(ctrl.get_queue_affinity(2, &mut affinity).is_err());
+
+        // not started yet, so user recovery can't be started
+        assert!(ctrl.start_user_recover().is_err());
+
+        ctrl.del_dev().unwrap();
+        assert!(drv.dev_ids().is_empty());
+        let err = ctrl.read_dev_info().unwrap_err();
+        assert!(err.is_not_found());
+        assert_eq!(
+            err.op().map(|op| (op.op, op.dev_id)),
+            Some(("GET_DEV_INFO", Some(id)))
+        );
+        assert!(matches!(
+            drv.queue_io(id, 0, 0, &Default::default()),
+            Err(UblkError::Op(op)) if op.tag == Some(0) && op.errno == -libc::ENODEV
+        ));
+    }
+
+    #[test]
+    fn test_mock_run_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 2, UblkRecoveryMode::Disabled);
+        let lock_path = dir
+            .path()
+            .join(format!("{:04}.lock", ctrl.dev_info().dev_id));
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(1_u64 << 30);
+            Ok(())
+        };
+        let d = drv.clone();
+        let lp = lock_path.clone();
+        let report = ctrl
+            .run_target_report(tgt_init, null_q_fn, move |ctrl: &UblkCtrl| {
+                let id = ctrl.dev_info().dev_id;
+                let timeout = Duration::from_secs(5);
+                assert_eq!(d.dev_info(id).unwrap().state, sys::UBLK_S_DEV_LIVE as u16);
+                assert!(lp.exists());
+                ctrl.wait_cdev_ready(timeout).unwrap();
+                ctrl.wait_bdev_ready(timeout).unwrap();
+
+                for (qid, tag, op) in [(0, 0, sys::UBLK_IO_OP_READ), (1, 7, 0xff)] {
+                    let iod = sys::ublksrv_io_desc {
+                        op_flags: op,
+                        nr_sectors: 8,
+                        ..Default::default()
+                    };
+                    d.queue_io(id, qid, tag, &iod).unwrap();
+                    let res = d.wait_io_result(id, qid, tag, timeout);
+                    assert_eq!(res, Some(if op == 0xff { -libc::EINVAL } else { 4096 }));
+                }
+
+                // same tag is reused for the following IOs
+                for i in 1..4 {
+                    let iod = sys::ublksrv_io_desc {
+                        op_flags: sys::UBLK_IO_OP_WRITE,
+                        nr_sectors: i,
+                        ..Default::default()
+                    };
+                    d.queue_io(id, 0, 3, &iod).unwrap();
+                    assert_eq!(d.wait_io_result(id, 0, 3, timeout), Some((i << 9) as i32));
+                }
+                ctrl.kill_dev().unwrap();
+            })
+            .unwrap();
+
+        assert!(report.is_clean());
+        assert_eq!(
+            drv.dev_info(report.dev_id).unwrap().state,
+            sys::UBLK_S_DEV_DEAD as u16
+        );
+        assert!(!lock_path.exists());
+
+        let err = ctrl.wait_bdev_ready(Duration::ZERO).unwrap_err();
+        assert_eq!(err.op().map(|op| op.op), Some("open_bdev"));
+        assert!(err.is_not_found());
+    }
+
+    #[test]
+    fn test_mock_exit_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 2, UblkRecoveryMode::Requeue);
+
+        let tgt_init = |dev: &mut UblkDev| {
+            dev.set_default_params(1_u64 << 30);
+            Ok(())
+        };
+        // drive queues by hand, since run_target() stops device at its end
+        let dev = UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
+        let id = ctrl.dev_info().dev_id;
+        let nr_queues = dev.dev_info.nr_hw_queues;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            for qid in 0..nr_queues {
+                let (dev, ctrl, tx) = (&dev, &ctrl, tx.clone());
+                s.spawn(move || {
+                    ctrl.configure_queue(dev, qid, unsafe { libc::gettid() })
+                        .unwrap();
+                    tx.send(qid).unwrap();
+                    null_q_fn(qid, dev);
+                });
+            }
+            for _ in 0..nr_queues {
+                rx.recv().unwrap();
+            }
+            ctrl.start_dev(&dev).unwrap();
+            drv.exit_daemon(id).unwrap();
+        });
+
+        // device is quiesced, and can be recovered
+        ctrl.read_dev_info().unwrap();
+        assert_eq!(ctrl.state(), UblkDevState::Quiesced);
+        ctrl.start_user_recover().unwrap();
+    }
+
+    #[test]
+    fn test_mock_quiesce_queues() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 2, UblkRecoveryMode::Requeue);
+
+        let dev = UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
+        let id = ctrl.dev_info().dev_id;
+        let nr_queues = dev.dev_info.nr_hw_queues;
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|s| {
+            for qid in 0..nr_queues {
+                let (dev, ctrl, tx) = (&dev, &ctrl, tx.clone());
+                s.spawn(move || {
+                    ctrl.configure_queue(dev, qid, unsafe { libc::gettid() })
+                        .unwrap();
+                    tx.send(qid).unwrap();
+                    null_q_fn(qid, dev);
+                });
+            }
+            for _ in 0..nr_queues {
+                rx.recv().unwrap();
+            }
+            ctrl.start_dev(&dev).unwrap();
+
+            // queues blocked in waiting for io commands exit
+            dev.quiesce_queues(Duration::from_secs(5)).unwrap();
+        });
+        assert!(dev.is_quiescing());
+        assert_eq!(drv.dev_info(id).unwrap().state, sys::UBLK_S_DEV_LIVE as u16);
+
+        // ublk driver quiesces the device after the char device is closed
+        drop(dev);
+        ctrl.read_dev_info().unwrap();
+        assert_eq!(ctrl.state(), UblkDevState::Quiesced);
+    }
+
+    #[test]
+    fn test_mock_queue_harness() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 1, UblkRecoveryMode::Disabled);
+        let dev = UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
+        let q = UblkQueue::new(0, &dev).unwrap().submit_fetch_commands(None);
+        ctrl.start_dev(&dev).unwrap();
+        let h = UblkQueueHarness::new(&drv, &q);
+
+        // READ is completed directly, and WRITE is completed after its
+        // target io is done
+        let handler = |q: &UblkQueue, tag: u16, io: &UblkIOCtx| {
+            let iod = q.get_iod(tag);
+            let res = if io.is_tgt_io() {
+                io.result()
+            } else if iod.op_flags & 0xff == sys::UBLK_IO_OP_WRITE {
+                return;
+            } else {
+                (iod.nr_sectors << 9) as i32
+            };
+            q.complete_io_cmd(tag, std::ptr::null_mut(), Ok(UblkIORes::Result(res)));
+        };
+
+        h.queue_io(0, &io_desc(sys::UBLK_IO_OP_READ, 8)).unwrap();
+        assert_eq!(h.process_io(handler).unwrap(), 1);
+        assert_eq!(h.take_io_results(0), vec![4096]);
+
+        // target ios are completed out of order, and one fails
+        for tag in 1..4 {
+            h.queue_io(tag, &io_desc(sys::UBLK_IO_OP_WRITE, 1)).unwrap();
+        }
+        assert_eq!(h.process_io(handler).unwrap(), 3);
+        assert_eq!(q.get_inflight_nr_io(), 3);
+        for (tag, res) in [(3, 512), (1, -libc::EIO), (2, 1024)] {
+            let data = UblkIOCtx::build_user_data(tag, sys::UBLK_IO_OP_WRITE, 0, true);
+            h.post_cqe(data, res).unwrap();
+        }
+        assert_eq!(h.process_io(handler).unwrap(), 3);
+        assert_eq!(q.get_inflight_nr_io(), 0);
+        assert_eq!(h.take_io_results(1), vec![-libc::EIO]);
+        assert_eq!(h.take_io_results(2), vec![1024]);
+        assert_eq!(h.take_io_results(3), vec![512]);
+
+        // idle queue becomes busy when new IO comes
+        h.idle_timeout();
+        assert!(q.is_idle());
+        h.queue_io(0, &io_desc(sys::UBLK_IO_OP_READ, 1)).unwrap();
+        assert_eq!(h.process_io(handler).unwrap(), 1);
+        assert!(!q.is_idle());
+        assert_eq!(h.take_io_results(0), vec![512]);
+
+        // error is committed with its errno, or -EIO if it hasn't errno
+        let err_handler = |q: &UblkQueue, tag: u16, _io: &UblkIOCtx| {
+            let e = match tag {
+                1 => UblkOpError::new("read", -libc::ENOSPC).into(),
+                _ => UblkError::InvalidVal,
+            };
+            q.complete_io_cmd(tag, std::ptr::null_mut(), Err(e));
+        };
+        for tag in 1..3 {
+            h.queue_io(tag, &io_desc(sys::UBLK_IO_OP_READ, 1)).unwrap();
+        }
+        assert_eq!(h.process_io(err_handler).unwrap(), 2);
+        assert_eq!(h.take_io_results(1), vec![-libc::ENOSPC]);
+        assert_eq!(h.take_io_results(2), vec![-libc::EIO]);
+
+        // queue is down after all io commands are aborted
+        for tag in 0..q.get_depth() as u16 {
+            h.complete_io_cmd(tag, None, sys::UBLK_IO_RES_ABORT)
+                .unwrap();
+        }
+        assert!(matches!(h.process_io(handler), Err(UblkError::QueueIsDown)));
+        assert!(q.is_stopping());
+    }
+
+    #[test]
+    fn test_mock_queue_harness_quiesce() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 2, UblkRecoveryMode::Requeue);
+        let dev = UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
+        let q0 = UblkQueue::new(0, &dev).unwrap().submit_fetch_commands(None);
+        let q1 = UblkQueue::new(1, &dev).unwrap().submit_fetch_commands(None);
+        ctrl.start_dev(&dev).unwrap();
+        let (h0, h1) = (
+            UblkQueueHarness::new(&drv, &q0),
+            UblkQueueHarness::new(&drv, &q1),
+        );
+
+        // WRITE is completed after its target io is done
+        let handler = |q: &UblkQueue, tag: u16, io: &UblkIOCtx| {
+            if !io.is_tgt_io() {
+                return;
+            }
+            q.complete_io_cmd(
+                tag,
+                std::ptr::null_mut(),
+                Ok(UblkIORes::Result(io.result())),
+            );
+        };
+        for h in [&h0, &h1] {
+            for tag in 1..4 {
+                h.queue_io(tag, &io_desc(sys::UBLK_IO_OP_WRITE, 1)).unwrap();
+            }
+            assert_eq!(h.process_io(handler).unwrap(), 3);
+        }
+
+        // in-flight target ios are drained, and their results aren't
+        // committed since queue stops issuing io commands
+        dev.quiesce_queues(Duration::from_secs(5)).unwrap();
+        assert!(h0.process_io(handler).is_ok());
+        assert!(q0.is_stopping());
+        for tag in 1..3 {
+            let data = UblkIOCtx::build_user_data(tag, sys::UBLK_IO_OP_WRITE, 0, true);
+            h0.post_cqe(data, 512).unwrap();
+            assert_eq!(h0.process_io(handler).unwrap(), 1);
+        }
+        let data = UblkIOCtx::build_user_data(3, sys::UBLK_IO_OP_WRITE, 0, true);
+        h0.post_cqe(data, 512).unwrap();
+        assert!(matches!(
+            h0.process_io(handler),
+            Err(UblkError::QueueIsDown)
+        ));
+        for tag in 1..4 {
+            assert!(h0.take_io_results(tag).is_empty());
+        }
+
+        // queue is down after the drain time is used up
+        dev.quiesce_queues(Duration::ZERO).unwrap();
+        assert!(matches!(
+            h1.process_io(handler),
+            Err(UblkError::QueueIsDown)
+        ));
+        assert!(q1.is_stopping());
+    }
+
+    #[test]
+    fn test_mock_queue_harness_io_tasks() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 1, UblkRecoveryMode::Disabled);
+        let dev = UblkDev::new(ctrl.get_name(), tgt_init, &ctrl).unwrap();
+        let q_rc = Rc::new(UblkQueue::new(0, &dev).unwrap());
+        let exe = smol::LocalExecutor::new();
+
+        // (tag, user_data) of target io waiting for completion
+        let tgt_ios = Rc::new(RefCell::new(Vec::new()));
+        let tasks: Vec<_> = (0..q_rc.get_depth() as u16)
+            .map(|tag| {
+                let q = q_rc.clone();
+                let tgt_ios = tgt_ios.clone();
+
+                exe.spawn(async move {
+                    let mut cmd_op = sys::UBLK_U_IO_FETCH_REQ;
+                    let mut res = 0;
+                    loop {
+                        let cmd_res = q
+                            .submit_io_cmd(tag, cmd_op, std::ptr::null_mut(), res)
+                            .await;
+                        if cmd_res == sys::UBLK_IO_RES_ABORT {
+                            break;
+                        }
+                        if cmd_res == sys::UBLK_IO_RES_NEED_GET_DATA as i32 {
+                            cmd_op = sys::UBLK_U_IO_NEED_GET_DATA;
+                            continue;
+                        }
+
+                        let f = UblkUringOpFuture::new(1_u64 << 63);
+                        tgt_ios.borrow_mut().push((tag, f.user_data));
+                        res = match f.await {
+                            r if r < 0 => r,
+                            _ => (q.get_iod(tag).nr_sectors << 9) as i32,
+                        };
+                        cmd_op = sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ;
+                    }
+                })
+            })
+            .collect();
+        let q = &q_rc;
+        let h = UblkQueueHarness::new(&drv, q);
+
+        // all tags have to be fetched before starting device
+        assert_eq!(h.process_io_tasks(&exe).unwrap(), 0);
+        ctrl.start_dev(&dev).unwrap();
+
+        // WRITE data is fetched via UBLK_IO_NEED_GET_DATA, then target ios
+        // are completed out of order
+        let iod = io_desc(sys::UBLK_IO_OP_WRITE, 2);
+        h.complete_io_cmd(0, Some(&iod), sys::UBLK_IO_RES_NEED_GET_DATA as i32)
+            .unwrap();
+        h.queue_io(5, &io_desc(sys::UBLK_IO_OP_READ, 4)).unwrap();
+        assert_eq!(h.process_io_tasks(&exe).unwrap(), 3);
+        let mut ios = tgt_ios.take();
+        ios.sort();
+        assert_eq!(ios.iter().map(|io| io.0).collect::<Vec<_>>(), vec![0, 5]);
+        h.post_cqe(ios[1].1, 0).unwrap();
+        h.post_cqe(ios[0].1, -libc::EIO).unwrap();
+        assert_eq!(h.process_io_tasks(&exe).unwrap(), 2);
+        assert_eq!(h.take_io_results(0), vec![-libc::EIO]);
+        assert_eq!(h.take_io_results(5), vec![2048]);
+        assert_eq!(q.get_inflight_nr_io(), 0);
+
+        for tag in 0..q.get_depth() as u16 {
+            h.complete_io_cmd(tag, None, sys::UBLK_IO_RES_ABORT)
+                .unwrap();
+        }
+        assert!(matches!(
+            h.process_io_tasks(&exe),
+            Err(UblkError::QueueIsDown)
+        ));
+        assert!(tasks.iter().all(|t| t.is_finished()));
+    }
+
+    async fn null_io_task(q: &UblkQueue<'_>, tag: u16) {
+        let mut cmd_op = sys::UBLK_U_IO_FETCH_REQ;
+        let mut res = 0;
+
+        while q
+            .submit_io_cmd(tag, cmd_op, std::ptr::null_mut(), res)
+            .await
+            != sys::UBLK_IO_RES_ABORT
+        {
+            res = (q.get_iod(tag).nr_sectors << 9) as i32;
+            cmd_op = sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ;
+        }
+    }
+
+    #[test]
+    fn test_mock_device_manager() {
+        let dir = tempfile::tempdir().unwrap();
+        let drv = Arc::new(UblkMockDriver::new().unwrap());
+        let _guard = drv.install();
+        let mut mgr = UblkDeviceManager::new().unwrap();
+
+        let ids: Vec<u32> = (0..3)
+            .map(|_| {
+                let ctrl = mock_ctrl(dir.path().to_str().unwrap(), 2, UblkRecoveryMode::Disabled);
+                let id = mgr
+                    .add_device(ctrl, tgt_init, |q, tag| Box::pin(null_io_task(q, tag)))
+                    .unwrap();
+                mgr.start_device(id).unwrap();
+                id
+            })
+            .collect();
+        for id in &ids {
+            assert_eq!(
+                drv.dev_info(*id).unwrap().state,
+                sys::UBLK_S_DEV_LIVE as u16
+            );
+            assert!(mgr.is_live(*id));
+        }
+
+        mgr.stop_device(ids[0]).unwrap();
+        assert!(!mgr.is_live(ids[0]) && mgr.is_live(ids[1]));
+        mgr.remove_device(ids[0]).unwrap();
+        assert_eq!(mgr.dev_ids(), ids[1..].to_vec());
+
+        // live devices are deleted after their queues are released
+        drop(mgr);
+        assert!(drv.dev_ids().is_empty());
+    }
+}
@@ -13,6 +13,18 @@ use std::{
 struct FutureData {
     waker: Option<Waker>,
     result: Option<i32>,
+
+    /// the future is dropped before its CQE is reaped, so the slot is
+    /// released when the CQE arrives
+    orphan: bool,
+
+    /// buffer which has to be live until the CQE is reaped
+    buf: Option<Vec<u8>>,
+}
+
+#[inline]
+fn slab_key(user_data: u64) -> usize {
+    ((user_data & !(1_u64 << 63)) >> 16) as usize
 }
 
 std::thread_local! {
@@ -21,8 +33,14 @@ std::thread_local! {
 
 /// User code creates one future with user_data used for submitting
 /// uring OP, then future.await returns this uring OP's result.
+///
+/// If the future is dropped before the OP is completed, such as
+/// cancelled by timeout, its slot is released after the CQE is reaped,
+/// so the slot can't be reused by others for the in-flight OP.
 pub struct UblkUringOpFuture {
     pub user_data: u64,
+    done: bool,
+    buf: Option<Vec<u8>>,
 }
 
 impl UblkUringOpFuture {
@@ -33,20 +51,59 @@ impl UblkUringOpFuture {
             let key = map.insert(FutureData {
                 waker: None,
                 result: None,
+                orphan: false,
+                buf: None,
             });
             let user_data = ((key as u32) << 16) as u64 | tgt_io;
             log::trace!("uring: new future {:x}", user_data);
-            UblkUringOpFuture { user_data }
+            UblkUringOpFuture {
+                user_data,
+                done: false,
+                buf: None,
+            }
+        })
+    }
+
+    /// Keep `buf` live until this OP's CQE is reaped, even though this
+    /// future is dropped before the OP is completed
+    pub(crate) fn keep_buf(&self, buf: Vec<u8>) {
+        MY_SLAB.with(|refcell| {
+            if let Some(fd) = refcell.borrow_mut().get_mut(slab_key(self.user_data)) {
+                fd.buf = Some(buf);
+            }
+        })
+    }
+}
+
+impl Drop for UblkUringOpFuture {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        MY_SLAB.with(|refcell| {
+            let mut map = refcell.borrow_mut();
+            let key = slab_key(self.user_data);
+
+            match map.get_mut(key) {
+                Some(fd) if fd.result.is_none() => {
+                    fd.orphan = true;
+                    fd.waker = None;
+                }
+                Some(_) => {
+                    map.remove(key);
+                }
+                None => {}
+            }
         })
     }
 }
 
 impl Future for UblkUringOpFuture {
     type Output = i32;
-    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         MY_SLAB.with(|refcell| {
             let mut map = refcell.borrow_mut();
-            let key = ((self.user_data & !(1_u64 << 63)) >> 16) as usize;
+            let key = slab_key(self.user_data);
             match map.get_mut(key) {
                 None => {
                     log::trace!("uring: null slab {:x}", self.user_data);
@@ -54,7 +111,8 @@ impl Future for UblkUringOpFuture {
                 }
                 Some(fd) => match fd.result {
                     Some(result) => {
-                        map.remove(key);
+                        self.buf = map.remove(key).buf;
+                        self.done = true;
                         log::trace!("uring: uring io ready userdata {:x} ready", self.user_data);
                         Poll::Ready(result)
                     }
@@ -83,8 +141,12 @@ pub fn ublk_wake_task(data: u64, cqe: &cqueue::Entry) {
             cqe.user_data(),
             cqe.result()
         );
-        let data = ((data & !(1_u64 << 63)) >> 16) as usize;
-        if let Some(fd) = map.get_mut(data) {
+        let key = slab_key(data);
+        if let Some(fd) = map.get_mut(key) {
+            if fd.orphan {
+                map.remove(key);
+                return;
+            }
             fd.result = Some(cqe.result());
             if let Some(w) = &fd.waker {
                 w.wake_by_ref();
@@ -214,7 +276,7 @@ pub fn ublk_run_ctrl_task<T>(
         let entry =
             crate::ctrl::CTRL_URING.with(|refcell| ublk_try_reap_cqe(&mut refcell.borrow_mut(), 0));
         if let Some(cqe) = entry {
-            ublk_wake_task(cqe.user_data(), &cqe);
+            crate::ctrl::ublk_ctrl_handle_cqe(&cqe);
             while exe.try_tick() {}
         }
     }
@@ -249,3 +311,43 @@ pub fn ublk_wait_and_handle_ios(exe: &smol::LocalExecutor, q: &UblkQueue) {
     }
     q.unregister_io_bufs();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slab_len() -> usize {
+        MY_SLAB.with(|refcell| refcell.borrow().len())
+    }
+
+    fn fake_cqe(user_data: u64) -> cqueue::Entry {
+        let mut ring = IoUring::<squeue::Entry, cqueue::Entry>::new(4).unwrap();
+        let sqe = opcode::Nop::new().build().user_data(user_data);
+
+        unsafe { ring.submission().push(&sqe).unwrap() };
+        ring.submit_and_wait(1).unwrap();
+        let cqe = ring.completion().next().unwrap();
+        cqe
+    }
+
+    /// slot of dropped future is released after its CQE is reaped
+    #[test]
+    fn test_uring_op_future_drop() {
+        let nr = slab_len();
+        let f = UblkUringOpFuture::new(0);
+        let data = f.user_data;
+
+        f.keep_buf(vec![0_u8; 64]);
+        drop(f);
+        assert_eq!(slab_len(), nr + 1);
+
+        ublk_wake_task(data, &fake_cqe(data));
+        assert_eq!(slab_len(), nr);
+
+        let f = UblkUringOpFuture::new(0);
+        let data = f.user_data;
+        ublk_wake_task(data, &fake_cqe(data));
+        assert_eq!(smol::block_on(f), 0);
+        assert_eq!(slab_len(), nr);
+    }
+}
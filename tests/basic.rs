@@ -1,21 +1,24 @@
 #[cfg(test)]
 mod integration {
     use io_uring::opcode;
+    use libublk::ctrl::{UblkCtrl, UblkCtrlBuilder, UblkDevState};
     use libublk::helpers::IoBuf;
     use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
     use libublk::uring_async::ublk_wait_and_handle_ios;
-    use libublk::{ctrl::UblkCtrl, ctrl::UblkCtrlBuilder, sys, UblkError, UblkFlags, UblkIORes};
+    use libublk::{sys, UblkError, UblkFlags, UblkIORes};
     use std::env;
     use std::path::Path;
     use std::process::{Command, Stdio};
     use std::rc::Rc;
     use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     fn run_ublk_disk_sanity_test(ctrl: &UblkCtrl, dev_flags: UblkFlags) {
         use std::os::unix::fs::PermissionsExt;
         let dev_path = ctrl.get_cdev_path();
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        ctrl.wait_cdev_ready(Duration::from_secs(3)).unwrap();
+        ctrl.wait_bdev_ready(Duration::from_secs(3)).unwrap();
 
         let tgt_flags = ctrl.get_target_flags_from_json().unwrap();
         assert!(UblkFlags::from_bits(tgt_flags).unwrap() == dev_flags);
@@ -397,22 +400,6 @@ mod integration {
             }
         }
 
-        fn ublk_state_wait_until(ctrl: &UblkCtrl, state: u16, timeout: u32) {
-            let mut count = 0;
-            let unit = 100_u32;
-            loop {
-                std::thread::sleep(std::time::Duration::from_millis(unit as u64));
-
-                ctrl.read_dev_info().unwrap();
-                if ctrl.dev_info().state == state {
-                    std::thread::sleep(std::time::Duration::from_millis(20));
-                    break;
-                }
-                count += unit;
-                assert!(count < timeout);
-            }
-        }
-
         let tgt_dir = get_curr_bin_dir().unwrap();
         let tmpfile = tempfile::NamedTempFile::new().unwrap();
         let file = std::fs::File::create(tmpfile.path()).unwrap();
@@ -450,7 +437,8 @@ mod integration {
         assert!(tid != 0);
 
         let ctrl = UblkCtrl::new_simple(id).unwrap();
-        ublk_state_wait_until(&ctrl, sys::UBLK_S_DEV_LIVE as u16, 2000);
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_millis(2000))
+            .unwrap();
 
         //ublk block device should be observed now
         let dev_path = ctrl.get_bdev_path();
@@ -462,7 +450,8 @@ mod integration {
         }
 
         //wait device becomes quiesced
-        ublk_state_wait_until(&ctrl, sys::UBLK_S_DEV_QUIESCED as u16, 6000);
+        ctrl.wait_for_state(UblkDevState::Quiesced, Duration::from_millis(6000))
+            .unwrap();
 
         let file = std::fs::File::create(tmpfile.path()).unwrap();
         //recover device
@@ -474,7 +463,8 @@ mod integration {
         cmd.wait().unwrap();
         //let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
         //println!("{}", buf);
-        ublk_state_wait_until(&ctrl, sys::UBLK_S_DEV_LIVE as u16, 20000);
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_millis(20000))
+            .unwrap();
         ctrl.del_dev().unwrap();
     }
 }
@@ -184,6 +184,12 @@
  */
 #define UBLK_F_ZONED (1ULL << 8)
 
+/*
+ * IOs are failed instead of being requeued while the device is quiesced
+ * for recovery, and it has to be set together with UBLK_F_USER_RECOVERY
+ */
+#define UBLK_F_USER_RECOVERY_FAIL_IO (1ULL << 9)
+
 /* device state */
 #define UBLK_S_DEV_DEAD	0
 #define UBLK_S_DEV_LIVE	1
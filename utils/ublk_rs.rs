@@ -0,0 +1,397 @@
+// SPDX-License-Identifier: MIT or Apache-2.0
+
+//! `ublk`: manage ublk devices via ublk control commands
+
+use libublk::ctrl::{UblkCtrl, UblkDevState, UblkDevSummary, UblkDumpFormat};
+use libublk::{sys, UblkFeatures, UblkFlags};
+use std::collections::HashMap;
+use std::io::Read;
+
+const USAGE: &str = "\
+Usage: ublk [--run-dir <dir>] <command> [options]
+
+Commands:
+    list [--json]                 list all ublk devices
+    dump [--json] <id>            show details of device <id>
+    del [--async] <id>            delete device <id>
+    kill <id>                     stop device <id>, which is deleted by its server
+    features                      show features supported by ublk driver
+    recover-start <id>            start user recovery of quiesced device <id>
+    wait <id> --state <state> [--timeout <secs>]
+                                  wait until device <id> becomes live, quiesced
+                                  or dead, 30 seconds by default
+    params get <id>               print parameters of device <id> in json
+    params set <id> <file>        set parameters of device <id> from json file,
+                                  which is read from stdin if <file> is '-'
+    help                          show this message
+
+<id> is device number, /dev/ublkbN or /dev/ublkcN, and --run-dir is the
+directory of exported json files, for device created with its own one";
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Bad command line, and usage is shown
+#[derive(Debug)]
+struct Usage(String);
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Usage {}
+
+fn usage<T>(msg: impl Into<String>) -> Result<T, Box<dyn std::error::Error>> {
+    Err(Box::new(Usage(msg.into())))
+}
+
+/// Parsed arguments of one command
+#[derive(Debug, Default)]
+struct Args {
+    flags: Vec<String>,
+    opts: HashMap<String, String>,
+    pos: Vec<String>,
+}
+
+impl Args {
+    /// `flags` are switches, and `opts` take one value
+    fn parse(args: &[String], flags: &[&str], opts: &[&str]) -> Result<Args, Usage> {
+        let mut res = Args::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            if !arg.starts_with("--") {
+                res.pos.push(arg.clone());
+                continue;
+            }
+
+            let (name, val) = match arg.split_once('=') {
+                Some((n, v)) => (n, Some(v.to_string())),
+                None => (arg.as_str(), None),
+            };
+            if flags.contains(&name) && val.is_none() {
+                res.flags.push(name.to_string());
+            } else if opts.contains(&name) {
+                let val = match val.or_else(|| iter.next().cloned()) {
+                    Some(v) => v,
+                    None => return Err(Usage(format!("{} requires one value", name))),
+                };
+                res.opts.insert(name.to_string(), val);
+            } else {
+                return Err(Usage(format!("unknown option {}", arg)));
+            }
+        }
+        Ok(res)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    fn opt(&self, name: &str) -> Option<&str> {
+        self.opts.get(name).map(String::as_str)
+    }
+
+    /// Positional arguments have to be exactly `names`
+    fn expect(&self, names: &[&str]) -> Result<(), Usage> {
+        if self.pos.len() != names.len() {
+            return Err(Usage(format!("expect arguments: {}", names.join(" "))));
+        }
+        Ok(())
+    }
+}
+
+/// Parse device id from "N", "ublkbN" or "/dev/ublkcN"
+fn parse_dev_id(s: &str) -> Result<i32, Usage> {
+    let n = s.strip_prefix("/dev/").unwrap_or(s);
+    let n = n
+        .strip_prefix("ublkb")
+        .or_else(|| n.strip_prefix("ublkc"))
+        .unwrap_or(n);
+
+    match n.parse::<i32>() {
+        Ok(id) if id >= 0 => Ok(id),
+        _ => Err(Usage(format!("invalid device id {}", s))),
+    }
+}
+
+fn parse_state(s: &str) -> Result<UblkDevState, Usage> {
+    match s.to_lowercase().as_str() {
+        "live" => Ok(UblkDevState::Live),
+        "quiesced" => Ok(UblkDevState::Quiesced),
+        "dead" => Ok(UblkDevState::Dead),
+        _ => Err(Usage(format!("invalid state {}", s))),
+    }
+}
+
+fn list_table(devs: &[UblkDevSummary]) {
+    println!(
+        "{:>4} {:<8} {:>6} {:>5} {:>8} {:>8} {:<11} {:<8} FEATURES",
+        "ID", "STATE", "QUEUES", "DEPTH", "BUF", "PID", "OWNER", "TARGET"
+    );
+    for d in devs {
+        println!(
+            "{:>4} {:<8} {:>6} {:>5} {:>8} {:>8} {:<11} {:<8} {}",
+            d.dev_id,
+            d.state,
+            d.nr_queues,
+            d.queue_depth,
+            d.max_io_buf_bytes,
+            d.pid,
+            format!("{}:{}", d.owner_uid, d.owner_gid),
+            d.target_type().unwrap_or("-"),
+            d.features()
+        );
+    }
+}
+
+fn cmd_list(args: &Args) -> CliResult {
+    args.expect(&[])?;
+
+    let devs: Vec<UblkDevSummary> = UblkCtrl::list_devs().collect();
+    if args.flag("--json") {
+        println!("{}", serde_json::to_string_pretty(&devs)?);
+    } else {
+        list_table(&devs);
+    }
+    Ok(())
+}
+
+fn cmd_dump(args: &Args) -> CliResult {
+    args.expect(&["<id>"])?;
+
+    let fmt = if args.flag("--json") {
+        UblkDumpFormat::Json
+    } else {
+        UblkDumpFormat::Text
+    };
+    let ctrl = UblkCtrl::new_simple(parse_dev_id(&args.pos[0])?)?;
+    println!("{}", ctrl.describe()?.render(fmt)?.trim_end());
+    Ok(())
+}
+
+fn cmd_del(args: &Args) -> CliResult {
+    args.expect(&["<id>"])?;
+
+    let dev_flags = if args.flag("--async") {
+        UblkFlags::UBLK_DEV_F_DEL_DEV_ASYNC
+    } else {
+        UblkFlags::empty()
+    };
+    let id = parse_dev_id(&args.pos[0])?;
+    UblkCtrl::new(None, id, 0, 0, 0, 0, 0, dev_flags)?.del_dev()?;
+    Ok(())
+}
+
+fn cmd_kill(args: &Args) -> CliResult {
+    args.expect(&["<id>"])?;
+
+    UblkCtrl::new_simple(parse_dev_id(&args.pos[0])?)?.kill_dev()?;
+    Ok(())
+}
+
+fn cmd_features(args: &Args) -> CliResult {
+    args.expect(&[])?;
+
+    let features = match UblkCtrl::get_features_flags() {
+        Some(f) => f,
+        None => return Err("GET_FEATURES isn't supported by ublk driver".into()),
+    };
+    println!("features: {:#x}", features.bits());
+    for (name, _) in features.iter_names() {
+        println!("\t{}", name.trim_start_matches("UBLK_F_").to_lowercase());
+    }
+    let unknown = features.bits() & !UblkFeatures::all().bits();
+    if unknown != 0 {
+        println!("\tunknown {:#x}", unknown);
+    }
+    Ok(())
+}
+
+fn cmd_recover_start(args: &Args) -> CliResult {
+    args.expect(&["<id>"])?;
+
+    UblkCtrl::new_simple(parse_dev_id(&args.pos[0])?)?.start_user_recover()?;
+    Ok(())
+}
+
+fn cmd_wait(args: &Args) -> CliResult {
+    args.expect(&["<id>"])?;
+
+    let state = match args.opt("--state") {
+        Some(s) => parse_state(s)?,
+        None => return usage("--state is required"),
+    };
+    let secs = match args.opt("--timeout").map(str::parse::<u64>) {
+        None => 30,
+        Some(Ok(s)) => s,
+        Some(Err(_)) => return usage("invalid timeout"),
+    };
+    let ctrl = UblkCtrl::new_simple(parse_dev_id(&args.pos[0])?)?;
+    ctrl.wait_for_state(state, std::time::Duration::from_secs(secs))?;
+    Ok(())
+}
+
+fn cmd_params(args: &Args) -> CliResult {
+    match args.pos.first().map(String::as_str) {
+        Some("get") => {
+            args.expect(&["get", "<id>"])?;
+
+            let ctrl = UblkCtrl::new_simple(parse_dev_id(&args.pos[1])?)?;
+            let mut p: sys::ublk_params = Default::default();
+            ctrl.get_params(&mut p)?;
+            println!("{}", serde_json::to_string_pretty(&p)?);
+        }
+        Some("set") => {
+            args.expect(&["set", "<id>", "<file>"])?;
+
+            let ctrl = UblkCtrl::new_simple(parse_dev_id(&args.pos[1])?)?;
+            let json = match args.pos[2].as_str() {
+                "-" => {
+                    let mut s = String::new();
+                    std::io::stdin().read_to_string(&mut s)?;
+                    s
+                }
+                f => std::fs::read_to_string(f)?,
+            };
+            let p: sys::ublk_params = serde_json::from_str(&json)?;
+            ctrl.set_params(&p)?;
+        }
+        _ => return usage("expect `params get` or `params set`"),
+    }
+    Ok(())
+}
+
+/// Strip the global `--run-dir` option, which is applied as the
+/// process-wide run directory
+fn parse_run_dir(argv: &[String]) -> Result<(Option<String>, &[String]), Usage> {
+    match argv.first().map(String::as_str) {
+        Some("--run-dir") => match argv.get(1) {
+            Some(dir) => Ok((Some(dir.clone()), &argv[2..])),
+            None => Err(Usage("--run-dir requires one value".to_string())),
+        },
+        Some(arg) if arg.starts_with("--run-dir=") => {
+            Ok((Some(arg["--run-dir=".len()..].to_string()), &argv[1..]))
+        }
+        _ => Ok((None, argv)),
+    }
+}
+
+fn run(argv: &[String]) -> CliResult {
+    let (run_dir, argv) = parse_run_dir(argv)?;
+    if let Some(dir) = run_dir {
+        UblkCtrl::set_run_dir(Some(&dir));
+    }
+
+    let (cmd, rest) = match argv.split_first() {
+        Some((cmd, rest)) => (cmd.as_str(), rest),
+        None => return usage("no command"),
+    };
+
+    match cmd {
+        "list" => cmd_list(&Args::parse(rest, &["--json"], &[])?),
+        "dump" => cmd_dump(&Args::parse(rest, &["--json"], &[])?),
+        "del" => cmd_del(&Args::parse(rest, &["--async"], &[])?),
+        "kill" => cmd_kill(&Args::parse(rest, &[], &[])?),
+        "features" => cmd_features(&Args::parse(rest, &[], &[])?),
+        "recover-start" => cmd_recover_start(&Args::parse(rest, &[], &[])?),
+        "wait" => cmd_wait(&Args::parse(rest, &[], &["--state", "--timeout"])?),
+        "params" => cmd_params(&Args::parse(rest, &[], &[])?),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => usage(format!("unknown command {}", cmd)),
+    }
+}
+
+fn main() {
+    let argv: Vec<String> = std::env::args().skip(1).collect();
+
+    if let Err(e) = run(&argv) {
+        eprintln!("ublk: {}", e);
+        if e.is::<Usage>() {
+            eprintln!("\n{}", USAGE);
+            std::process::exit(2);
+        }
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let a = Args::parse(
+            &argv("3 --state live --timeout=5"),
+            &[],
+            &["--state", "--timeout"],
+        )
+        .unwrap();
+        assert_eq!(a.pos, vec!["3"]);
+        assert_eq!(a.opt("--state"), Some("live"));
+        assert_eq!(a.opt("--timeout"), Some("5"));
+        assert!(a.expect(&["<id>"]).is_ok());
+        assert!(a.expect(&[]).is_err());
+
+        let a = Args::parse(&argv("--async 1"), &["--async"], &[]).unwrap();
+        assert!(a.flag("--async"));
+        assert_eq!(a.pos, vec!["1"]);
+
+        assert!(Args::parse(&argv("--json"), &[], &[]).is_err());
+        assert!(Args::parse(&argv("1 --state"), &[], &["--state"]).is_err());
+        assert!(Args::parse(&argv("--async=1"), &["--async"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_parse_dev_id() {
+        assert_eq!(parse_dev_id("12").unwrap(), 12);
+        assert_eq!(parse_dev_id("ublkb3").unwrap(), 3);
+        assert_eq!(parse_dev_id("/dev/ublkc7").unwrap(), 7);
+        assert!(parse_dev_id("-1").is_err());
+        assert!(parse_dev_id("/dev/sda").is_err());
+
+        assert_eq!(parse_state("LIVE").unwrap(), UblkDevState::Live);
+        assert!(parse_state("running").is_err());
+    }
+
+    #[test]
+    fn test_parse_run_dir() {
+        let a = argv("--run-dir /tmp/ublk dump 1");
+        let (dir, rest) = parse_run_dir(&a).unwrap();
+        assert_eq!(dir.as_deref(), Some("/tmp/ublk"));
+        assert_eq!(rest, &argv("dump 1")[..]);
+
+        let a = argv("--run-dir=/tmp/ublk list");
+        let (dir, rest) = parse_run_dir(&a).unwrap();
+        assert_eq!(dir.as_deref(), Some("/tmp/ublk"));
+        assert_eq!(rest, &argv("list")[..]);
+
+        let a = argv("list --json");
+        assert_eq!(parse_run_dir(&a).unwrap(), (None, &a[..]));
+        assert!(parse_run_dir(&argv("--run-dir")).is_err());
+    }
+
+    #[test]
+    fn test_run_usage() {
+        for cmd in [
+            "",
+            "foo",
+            "dump",
+            "del 1 2",
+            "wait 1",
+            "wait 1 --state up",
+            "params",
+        ] {
+            let err = run(&argv(cmd)).unwrap_err();
+            assert!(err.is::<Usage>(), "{}: {}", cmd, err);
+        }
+    }
+}